@@ -0,0 +1,30 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+  pub message: String,
+  pub line: u32,
+  pub column: u32,
+}
+
+impl AsmError {
+  pub fn new(message: &str, line: u32, column: u32) -> Self {
+    AsmError {
+      message: message.to_string(),
+      line,
+      column,
+    }
+  }
+}
+
+impl fmt::Display for AsmError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} at line {}, column {}",
+      self.message, self.line, self.column
+    )
+  }
+}
+
+pub type AsmResult<T> = Result<T, AsmError>;
@@ -0,0 +1,510 @@
+use super::{
+  asm_error::{AsmError, AsmResult},
+  instruction::{Instruction, Operand},
+  opcode::{operand_kinds, OperandKind, OPCODES},
+  program::{FunctionInfo, Program},
+};
+use std::collections::HashMap;
+
+// Assembly syntax, one statement per line:
+//
+//   ; comment
+//   .int 1, -2            append to INT_TABLE
+//   .float 0.5            append to FLOAT_TABLE
+//   .str "text"           append to STR_TABLE
+//   .func name/arity      record a function starting at the next instruction
+//   label:                define @label at the next instruction
+//   0012: ADD r0, r1, r2  leading offsets (as printed by the disassembler) are ignored
+//
+// Operands follow `operand_kinds`: registers are `rN`, immediates are
+// integers (decimal or 0x hex) or `@label`, SETF takes a float and SETS a
+// string literal or STR_TABLE index.
+pub fn assemble(source: &str) -> Result<Program, Vec<AsmError>> {
+  let mut assembler = Assembler::default();
+  for (i, line) in source.lines().enumerate() {
+    if let Err(error) = assembler.line(line, i as u32 + 1) {
+      assembler.errors.push(error);
+    }
+  }
+  assembler.finish()
+}
+
+#[derive(Default)]
+struct Assembler {
+  program: Program,
+  labels: HashMap<String, usize>,
+  references: Vec<(String, u32, u32)>, // label, line, column
+  errors: Vec<AsmError>,
+}
+
+impl Assembler {
+  fn line(&mut self, text: &str, line: u32) -> AsmResult<()> {
+    let mut cursor = Cursor::new(strip_comment(text), line);
+    cursor.skip_whitespace();
+
+    // `0012:` offset prefix
+    let save = cursor.pos;
+    if cursor.take_while(|c| c.is_ascii_digit()).is_some() && cursor.eat(':') {
+      cursor.skip_whitespace();
+    } else {
+      cursor.pos = save;
+    }
+
+    while let Some(label) = cursor.label_definition() {
+      if self.labels.contains_key(&label) {
+        return Err(cursor.error(&format!("Duplicate label '{}'", label)));
+      }
+      let address = self.program.code.len();
+      self.labels.insert(label.clone(), address);
+      self.program.labels.push((label, address));
+      cursor.skip_whitespace();
+    }
+
+    if cursor.is_at_end() {
+      return Ok(());
+    }
+
+    if cursor.eat('.') {
+      return self.directive(&mut cursor);
+    }
+
+    let column = cursor.column();
+    let mnemonic = cursor
+      .take_while(|c| c.is_ascii_alphanumeric() || c == '_')
+      .ok_or_else(|| cursor.error("Expected an instruction"))?;
+    let opcode = OPCODES
+      .iter()
+      .find(|op| format!("{:?}", op).eq_ignore_ascii_case(&mnemonic))
+      .copied()
+      .ok_or_else(|| AsmError::new(&format!("Unknown instruction '{}'", mnemonic), line, column))?;
+
+    let kinds = operand_kinds(opcode);
+    let mut operands = Vec::new();
+    for (i, kind) in kinds.iter().enumerate() {
+      cursor.skip_whitespace();
+      if i > 0 && !cursor.eat(',') {
+        return Err(cursor.error(&format!(
+          "{:?} expects {} operands, found {}",
+          opcode,
+          kinds.len(),
+          i
+        )));
+      }
+      cursor.skip_whitespace();
+      operands.push(self.operand(&mut cursor, *kind)?);
+    }
+    cursor.skip_whitespace();
+    if !cursor.is_at_end() {
+      return Err(cursor.error(&format!(
+        "Unexpected '{}' after {:?} operands",
+        cursor.rest(),
+        opcode
+      )));
+    }
+
+    self.program.code.push(Instruction::new(opcode, operands));
+    Ok(())
+  }
+
+  fn directive(&mut self, cursor: &mut Cursor) -> AsmResult<()> {
+    let name = cursor
+      .take_while(|c| c.is_ascii_alphabetic())
+      .unwrap_or_default();
+    cursor.skip_whitespace();
+    match name.as_str() {
+      "int" | "float" | "str" => loop {
+        match name.as_str() {
+          "int" => {
+            let n = cursor.integer()?;
+            self.program.int_table.push(n);
+          }
+          "float" => {
+            let n = cursor.float()?;
+            self.program.float_table.push(n);
+          }
+          _ => {
+            let s = cursor.string()?;
+            self.program.str_table.push(s);
+          }
+        }
+        cursor.skip_whitespace();
+        if cursor.is_at_end() {
+          return Ok(());
+        }
+        if !cursor.eat(',') {
+          return Err(cursor.error("Expected ',' between values"));
+        }
+        cursor.skip_whitespace();
+      },
+      "func" => {
+        let column = cursor.column();
+        let spec = cursor
+          .take_while(|c| !c.is_whitespace())
+          .ok_or_else(|| cursor.error("Expected name/arity"))?;
+        let (function, arity) = spec
+          .rsplit_once('/')
+          .and_then(|(name, arity)| Some((name, arity.parse::<usize>().ok()?)))
+          .ok_or_else(|| AsmError::new("Expected name/arity", cursor.line, column))?;
+        self.program.functions.push(FunctionInfo {
+          name: function.to_string(),
+          address: self.program.code.len(),
+          arity,
+        });
+        Ok(())
+      }
+      _ => Err(cursor.error(&format!("Unknown directive '.{}'", name))),
+    }
+  }
+
+  fn operand(&mut self, cursor: &mut Cursor, kind: OperandKind) -> AsmResult<Operand> {
+    let column = cursor.column();
+    match (kind, cursor.peek()) {
+      (OperandKind::Reg | OperandKind::Target, Some('r')) => cursor.register(),
+      (OperandKind::Reg, _) => Err(cursor.error("Expected a register")),
+      (OperandKind::Imm | OperandKind::Target, Some('@')) => {
+        cursor.eat('@');
+        let label = cursor
+          .identifier()
+          .ok_or_else(|| cursor.error("Expected label name after '@'"))?;
+        self.references.push((label.clone(), cursor.line, column));
+        Ok(Operand::Label(label))
+      }
+      (OperandKind::Imm | OperandKind::Target, _) => Ok(Operand::Imm(cursor.integer()?)),
+      (OperandKind::Float, _) => Ok(Operand::Imm(cursor.float()?.to_bits() as i32)),
+      (OperandKind::Str, Some('"')) => {
+        let s = cursor.string()?;
+        let idx = match self.program.str_table.iter().position(|x| *x == s) {
+          Some(idx) => idx,
+          None => {
+            self.program.str_table.push(s);
+            self.program.str_table.len() - 1
+          }
+        };
+        Ok(Operand::Imm(idx as i32))
+      }
+      (OperandKind::Str, _) => Ok(Operand::Imm(cursor.integer()?)),
+    }
+  }
+
+  fn finish(mut self) -> Result<Program, Vec<AsmError>> {
+    for (label, line, column) in &self.references {
+      if !self.labels.contains_key(label) {
+        self.errors.push(AsmError::new(
+          &format!("Undefined label @{}", label),
+          *line,
+          *column,
+        ));
+      }
+    }
+    if !self.errors.is_empty() {
+      return Err(self.errors);
+    }
+
+    for instruction in self.program.code.iter_mut() {
+      for operand in instruction.operands.iter_mut() {
+        if let Operand::Label(label) = operand {
+          *operand = Operand::Imm(self.labels[label.as_str()] as i32);
+        }
+      }
+    }
+    Ok(self.program)
+  }
+}
+
+fn strip_comment(line: &str) -> &str {
+  let mut in_string = false;
+  let mut escaped = false;
+  for (i, c) in line.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' if in_string => escaped = true,
+      '"' => in_string = !in_string,
+      ';' if !in_string => return &line[..i],
+      _ => {}
+    }
+  }
+  line
+}
+
+struct Cursor {
+  chars: Vec<char>,
+  pos: usize,
+  line: u32,
+}
+
+impl Cursor {
+  fn new(text: &str, line: u32) -> Self {
+    Cursor {
+      chars: text.chars().collect(),
+      pos: 0,
+      line,
+    }
+  }
+
+  fn column(&self) -> u32 {
+    self.pos as u32 + 1
+  }
+
+  fn error(&self, message: &str) -> AsmError {
+    AsmError::new(message, self.line, self.column())
+  }
+
+  fn is_at_end(&self) -> bool {
+    self.pos >= self.chars.len()
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).copied()
+  }
+
+  fn rest(&self) -> String {
+    self.chars[self.pos..].iter().collect()
+  }
+
+  fn eat(&mut self, expected: char) -> bool {
+    if self.peek() == Some(expected) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.peek().is_some_and(char::is_whitespace) {
+      self.pos += 1;
+    }
+  }
+
+  fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> Option<String> {
+    let start = self.pos;
+    while self.peek().is_some_and(&predicate) {
+      self.pos += 1;
+    }
+    if self.pos == start {
+      None
+    } else {
+      Some(self.chars[start..self.pos].iter().collect())
+    }
+  }
+
+  // Labels may contain any symbol characters, but can't start with a digit
+  // so they stay distinct from offsets.
+  fn identifier(&mut self) -> Option<String> {
+    if self.peek().is_none_or(|c| c.is_ascii_digit() || c == '.') {
+      return None;
+    }
+    self.take_while(|c| !c.is_whitespace() && !",:;@\"".contains(c))
+  }
+
+  fn label_definition(&mut self) -> Option<String> {
+    let save = self.pos;
+    if let Some(label) = self.identifier() {
+      if self.eat(':') {
+        return Some(label);
+      }
+    }
+    self.pos = save;
+    None
+  }
+
+  fn register(&mut self) -> AsmResult<Operand> {
+    let column = self.column();
+    self.eat('r');
+    let digits = self
+      .take_while(|c| c.is_ascii_digit())
+      .ok_or_else(|| self.error("Expected register number after 'r'"))?;
+    digits.parse::<u8>().map(Operand::Reg).map_err(|_| {
+      AsmError::new(
+        &format!("Register r{} out of range (r0-r255)", digits),
+        self.line,
+        column,
+      )
+    })
+  }
+
+  fn integer(&mut self) -> AsmResult<i32> {
+    let column = self.column();
+    let text = self
+      .take_while(|c| c.is_ascii_alphanumeric() || c == '-' || c == '+')
+      .ok_or_else(|| self.error("Expected an integer"))?;
+    let (negative, digits) = match text.strip_prefix('-') {
+      Some(digits) => (true, digits),
+      None => (false, text.strip_prefix('+').unwrap_or(&text)),
+    };
+    let value = match digits
+      .strip_prefix("0x")
+      .or_else(|| digits.strip_prefix("0X"))
+    {
+      Some(hex) => i64::from_str_radix(hex, 16),
+      None => digits.parse::<i64>(),
+    };
+    let value = value.map(|n| if negative { -n } else { n });
+    match value {
+      // Hex literals may spell out the full 32-bit pattern.
+      Ok(n) if n >= i32::MIN as i64 && n <= u32::MAX as i64 => Ok(n as i32),
+      _ => Err(AsmError::new(
+        &format!("Invalid 32-bit integer '{}'", text),
+        self.line,
+        column,
+      )),
+    }
+  }
+
+  fn float(&mut self) -> AsmResult<f32> {
+    let column = self.column();
+    let text = self
+      .take_while(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+      .ok_or_else(|| self.error("Expected a float"))?;
+    text
+      .parse::<f32>()
+      .map_err(|_| AsmError::new(&format!("Invalid float '{}'", text), self.line, column))
+  }
+
+  fn string(&mut self) -> AsmResult<String> {
+    let column = self.column();
+    if !self.eat('"') {
+      return Err(self.error("Expected a string literal"));
+    }
+    let mut value = String::new();
+    loop {
+      match self.peek() {
+        None => return Err(AsmError::new("Unterminated string", self.line, column)),
+        Some('"') => {
+          self.pos += 1;
+          return Ok(value);
+        }
+        Some('\\') => {
+          self.pos += 1;
+          let escaped = match self.peek() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(c) => return Err(self.error(&format!("Unknown escape sequence \\{}", c))),
+            None => return Err(AsmError::new("Unterminated string", self.line, column)),
+          };
+          value.push(escaped);
+          self.pos += 1;
+        }
+        Some(c) => {
+          value.push(c);
+          self.pos += 1;
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler::{codegen::compile, disassembler::disassemble, opcode::Opcode};
+  use crate::parser::parser::Parser;
+  use crate::scanner::scanner::read_str_scan;
+  use crate::vm::{value::Value, vm::Vm};
+  use std::io;
+
+  fn run(program: Program) -> (Value, String) {
+    let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+    let value = vm.run().unwrap();
+    (value, String::from_utf8(vm.output().clone()).unwrap())
+  }
+
+  #[test]
+  fn test_assemble_and_run() {
+    let source = r#"
+      ; sum 1..=10 and print a greeting
+      .int 10
+              SETS r5, "done; sum=" ; ';' inside strings is kept
+              SETI r0, 0
+              SETI r6, 0
+              LOAD r1, r6, 0        ; r1 = INT_TABLE[0]
+              SETI r2, 1
+      loop:   ADD r0, r0, r1
+              SUB r1, r1, r2
+              JMP_IF r1, @check
+      check:  GT r3, r1, r6
+              JMP_IF r3, @loop
+              SETI r4, 0
+              VMCALL r5, r4, 0
+              HLT
+    "#;
+
+    let program = assemble(source).unwrap();
+    assert_eq!(
+      program.labels,
+      vec![("loop".to_string(), 5), ("check".to_string(), 8)]
+    );
+    assert_eq!(
+      program.code[7],
+      Instruction::new(Opcode::JMP_IF, vec![Operand::Reg(1), Operand::Imm(8)])
+    );
+    assert_eq!(run(program), (Value::Int(55), "done; sum=".to_string()));
+  }
+
+  #[test]
+  fn test_functions_and_floats() {
+    let source = "
+              SETF r1, 1.5
+              SET_ARG r1, 0
+              CALL @double
+              POP r0
+              HLT
+      .func double/1
+      double: GET_ARG r0, 0
+              ADD r0, r0, r0
+              RETURN r0
+    ";
+
+    let program = assemble(source).unwrap();
+    assert_eq!(program.functions[0].name, "double");
+    assert_eq!(program.functions[0].address, 5);
+    assert_eq!(run(program).0, Value::Float(3.0));
+  }
+
+  #[test]
+  fn test_errors() {
+    let source = "
+      FOO r1
+      ADD r1, r2
+      SETI r300, 1
+      JMP @nowhere
+      SETI r1, 99999999999
+    ";
+
+    let errors = assemble(source).unwrap_err();
+    let messages: Vec<_> = errors
+      .iter()
+      .map(|e| (e.line, e.message.as_str()))
+      .collect();
+    assert_eq!(
+      messages,
+      vec![
+        (2, "Unknown instruction 'FOO'"),
+        (3, "ADD expects 3 operands, found 2"),
+        (4, "Register r300 out of range (r0-r255)"),
+        (6, "Invalid 32-bit integer '99999999999'"),
+        (5, "Undefined label @nowhere"),
+      ]
+    );
+    assert_eq!(errors[0].column, 7);
+  }
+
+  #[test]
+  fn test_disassembly_round_trips() {
+    let code = r#"
+            (def fact (fn (n) (if (<= n 1) 1 (* n (fact (- n 1))))))
+            (println "fact:" (fact 5) 2.5)
+        "#;
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    let program = compile(&Parser::new(tokens).parse().unwrap()).unwrap();
+
+    let listing = disassemble(&program);
+    let reassembled = assemble(&listing).unwrap();
+    assert_eq!(reassembled, program);
+    assert_eq!(run(reassembled).1, "fact: 120 2.5\n");
+  }
+}
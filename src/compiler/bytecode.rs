@@ -0,0 +1,602 @@
+use super::{
+  format_error::{FormatError, FormatResult},
+  instruction::{Instruction, Operand},
+  opcode::{opcode_from_bytes, opcode_to_bytes, operand_kinds, Opcode, OperandKind},
+  program::{FunctionInfo, Program},
+};
+use crate::vm::vm::{INT_TABLE, MAX_ARGS, STR_TABLE};
+use std::{collections::HashMap, fs, ops::Range, path::Path};
+
+// .tispc layout, all integers big-endian:
+//
+// magic    "TISP"
+// version  u16
+// sections tag: u8, length: u32, payload; in the order below, each exactly once
+//   1 INT_TABLE   count: u32, i32*
+//   2 FLOAT_TABLE count: u32, f32 bits*
+//   3 STR_TABLE   count: u32, (len: u32, utf-8 bytes)*
+//   4 FUNCTIONS   count: u32, (name, address: u32, arity: u32)*
+//   5 LABELS      count: u32, (name, address: u32)*
+//   6 CODE        count: u32, (opcode: u32, operand count: u8, operand*)*
+//                 operand = 0 reg: u8 | 1 imm: i32 | 2 label: u32 index into LABELS
+// checksum u32 FNV-1a over every preceding byte
+pub const MAGIC: [u8; 4] = *b"TISP";
+pub const FORMAT_VERSION: u16 = 1;
+pub const FILE_EXTENSION: &str = "tispc";
+
+const SECTIONS: [(u8, &str); 6] = [
+  (1, "INT_TABLE"),
+  (2, "FLOAT_TABLE"),
+  (3, "STR_TABLE"),
+  (4, "FUNCTIONS"),
+  (5, "LABELS"),
+  (6, "CODE"),
+];
+
+const OPERAND_REG: u8 = 0;
+const OPERAND_IMM: u8 = 1;
+const OPERAND_LABEL: u8 = 2;
+const MAX_OPERANDS: u8 = 3;
+
+// Address recorded for a label operand that the program never defines; the
+// reader rejects it.
+const UNRESOLVED: u32 = u32::MAX;
+
+pub fn write_program(program: &Program) -> Vec<u8> {
+  let mut labels = program.labels.clone();
+  let mut label_ids: HashMap<String, u32> = HashMap::new();
+  for (i, (name, _)) in labels.iter().enumerate() {
+    label_ids.entry(name.clone()).or_insert(i as u32);
+  }
+
+  let mut code = Vec::new();
+  put_u32(&mut code, program.code.len() as u32);
+  for instruction in &program.code {
+    code.extend_from_slice(&opcode_to_bytes(instruction.opcode));
+    code.push(instruction.operands.len() as u8);
+    for operand in &instruction.operands {
+      match operand {
+        Operand::Reg(r) => code.extend_from_slice(&[OPERAND_REG, *r]),
+        Operand::Imm(n) => {
+          code.push(OPERAND_IMM);
+          code.extend_from_slice(&n.to_be_bytes());
+        }
+        Operand::Label(name) => {
+          let id = *label_ids.entry(name.clone()).or_insert_with(|| {
+            labels.push((name.clone(), UNRESOLVED as usize));
+            labels.len() as u32 - 1
+          });
+          code.push(OPERAND_LABEL);
+          put_u32(&mut code, id);
+        }
+      }
+    }
+  }
+
+  let mut ints = Vec::new();
+  put_u32(&mut ints, program.int_table.len() as u32);
+  for n in &program.int_table {
+    ints.extend_from_slice(&n.to_be_bytes());
+  }
+
+  let mut floats = Vec::new();
+  put_u32(&mut floats, program.float_table.len() as u32);
+  for n in &program.float_table {
+    put_u32(&mut floats, n.to_bits());
+  }
+
+  let mut strings = Vec::new();
+  put_u32(&mut strings, program.str_table.len() as u32);
+  for s in &program.str_table {
+    put_str(&mut strings, s);
+  }
+
+  let mut functions = Vec::new();
+  put_u32(&mut functions, program.functions.len() as u32);
+  for function in &program.functions {
+    put_str(&mut functions, &function.name);
+    put_u32(&mut functions, function.address as u32);
+    put_u32(&mut functions, function.arity as u32);
+  }
+
+  let mut label_section = Vec::new();
+  put_u32(&mut label_section, labels.len() as u32);
+  for (name, address) in &labels {
+    put_str(&mut label_section, name);
+    put_u32(&mut label_section, *address as u32);
+  }
+
+  let mut bytes = Vec::new();
+  bytes.extend_from_slice(&MAGIC);
+  bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+  let payloads = [ints, floats, strings, functions, label_section, code];
+  for ((tag, _), payload) in SECTIONS.iter().zip(payloads) {
+    bytes.push(*tag);
+    put_u32(&mut bytes, payload.len() as u32);
+    bytes.extend_from_slice(&payload);
+  }
+  let checksum = fnv1a(&bytes);
+  put_u32(&mut bytes, checksum);
+  bytes
+}
+
+pub fn read_program(bytes: &[u8]) -> FormatResult<Program> {
+  let mut reader = Reader::new(bytes, 0);
+
+  let magic = reader.take(4, "magic number")?;
+  if magic != MAGIC {
+    return Err(FormatError::new("Not a tispc file: bad magic number", 0));
+  }
+  let version = reader.u16("format version")?;
+  if version != FORMAT_VERSION {
+    return Err(FormatError::new(
+      &format!(
+        "Unsupported format version {} (expected {})",
+        version, FORMAT_VERSION
+      ),
+      4,
+    ));
+  }
+
+  let mut program = Program::default();
+  let mut label_refs = Vec::new();
+  for (tag, name) in SECTIONS {
+    let offset = reader.pos;
+    let found = reader.u8(&format!("{} section tag", name))?;
+    if found != tag {
+      return Err(FormatError::new(
+        &format!(
+          "Expected {} section (tag {}), found tag {}",
+          name, tag, found
+        ),
+        offset,
+      ));
+    }
+    let len = reader.u32(&format!("{} section length", name))? as usize;
+    let start = reader.pos;
+    let payload = reader.take(len, &format!("{} section", name))?;
+    let mut section = Reader::new(payload, start);
+
+    match tag {
+      1 => program.int_table = section.list(|r| r.i32("int constant"))?,
+      2 => program.float_table = section.list(|r| r.u32("float constant").map(f32::from_bits))?,
+      3 => program.str_table = section.list(|r| r.string("string constant"))?,
+      4 => {
+        program.functions = section.list(|r| {
+          Ok(FunctionInfo {
+            name: r.string("function name")?,
+            address: r.u32("function address")? as usize,
+            arity: r.u32("function arity")? as usize,
+          })
+        })?
+      }
+      5 => {
+        let labels = section.list(|r| {
+          let offset = r.pos;
+          Ok((r.string("label name")?, r.u32("label address")?, offset))
+        })?;
+        for (name, address, offset) in labels {
+          label_refs.push((address, offset));
+          program.labels.push((name, address as usize));
+        }
+      }
+      _ => program.code = section.list(|r| r.instruction())?,
+    }
+
+    if !section.is_at_end() {
+      return Err(FormatError::new(
+        &format!(
+          "{} section has {} unexpected trailing bytes",
+          name,
+          payload.len() - (section.pos - start)
+        ),
+        section.pos,
+      ));
+    }
+  }
+
+  let checksum_offset = reader.pos;
+  let checksum = reader.u32("checksum")?;
+  if !reader.is_at_end() {
+    return Err(FormatError::new(
+      &format!(
+        "Unexpected {} bytes after checksum",
+        bytes.len() - reader.pos
+      ),
+      reader.pos,
+    ));
+  }
+  let expected = fnv1a(&bytes[..checksum_offset]);
+  if checksum != expected {
+    return Err(FormatError::new(
+      &format!(
+        "Checksum mismatch: file says {:#010x}, content hashes to {:#010x}",
+        checksum, expected
+      ),
+      checksum_offset,
+    ));
+  }
+
+  validate(&mut program, &label_refs)?;
+  Ok(program)
+}
+
+pub fn write_file<P: AsRef<Path>>(path: P, program: &Program) -> std::io::Result<()> {
+  fs::write(path, write_program(program))
+}
+
+pub fn read_file<P: AsRef<Path>>(path: P) -> FormatResult<Program> {
+  let bytes = fs::read(path).map_err(|e| FormatError::new(&e.to_string(), 0))?;
+  read_program(&bytes)
+}
+
+// Resolves label operands (decoded as `Label("#<index>")`) to addresses,
+// checks every recorded address against the code length and every
+// instruction's operands against its opcode. `label_refs` holds the raw
+// address and file offset of each LABELS entry.
+fn validate(program: &mut Program, label_refs: &[(u32, usize)]) -> FormatResult<()> {
+  let code_len = program.code.len();
+  for (i, (name, address)) in program.labels.iter().enumerate() {
+    let (raw, offset) = label_refs[i];
+    if raw != UNRESOLVED && *address > code_len {
+      return Err(FormatError::new(
+        &format!(
+          "Label '{}' address {} out of range (code length {})",
+          name, address, code_len
+        ),
+        offset,
+      ));
+    }
+  }
+  for function in &program.functions {
+    if function.address >= code_len {
+      return Err(FormatError::new(
+        &format!(
+          "Function '{}' address {} out of range (code length {})",
+          function.name, function.address, code_len
+        ),
+        0,
+      ));
+    }
+  }
+
+  for (pc, instruction) in program.code.iter_mut().enumerate() {
+    for operand in instruction.operands.iter_mut() {
+      if let Operand::Label(id) = operand {
+        let id: usize = id.trim_start_matches('#').parse().unwrap();
+        let (name, address) = match program.labels.get(id) {
+          Some(label) => label,
+          None => {
+            return Err(FormatError::new(
+              &format!("Instruction {} references unknown label #{}", pc, id),
+              0,
+            ))
+          }
+        };
+        if label_refs[id].0 == UNRESOLVED {
+          return Err(FormatError::new(
+            &format!("Instruction {} references undefined label @{}", pc, name),
+            label_refs[id].1,
+          ));
+        }
+        *operand = Operand::Imm(*address as i32);
+      }
+    }
+    check_operands(instruction, program.str_table.len())
+      .map_err(|message| FormatError::new(&format!("Instruction {}: {}", pc, message), 0))?;
+  }
+  program
+    .labels
+    .retain(|(_, address)| *address != UNRESOLVED as usize);
+  Ok(())
+}
+
+fn check_operands(instruction: &Instruction, str_count: usize) -> Result<(), String> {
+  let opcode = instruction.opcode;
+  let kinds = operand_kinds(opcode);
+  if instruction.operands.len() != kinds.len() {
+    return Err(format!(
+      "{:?} expects {} operands, found {}",
+      opcode,
+      kinds.len(),
+      instruction.operands.len()
+    ));
+  }
+  for (i, (kind, operand)) in kinds.iter().zip(&instruction.operands).enumerate() {
+    let imm = match (kind, operand) {
+      (OperandKind::Reg | OperandKind::Target, Operand::Reg(_)) => continue,
+      (OperandKind::Reg, _) => {
+        return Err(format!("{:?} expects a register as operand {}", opcode, i))
+      }
+      (_, Operand::Imm(imm)) => *imm,
+      _ => {
+        return Err(format!(
+          "{:?} expects an immediate as operand {}",
+          opcode, i
+        ))
+      }
+    };
+    let range = match (kind, opcode) {
+      (OperandKind::Str, _) => Some(0..str_count as i32),
+      (_, Opcode::SET_ARG | Opcode::GET_ARG | Opcode::GET_ARGS) => Some(0..MAX_ARGS as i32),
+      (_, Opcode::STORE | Opcode::LOAD) => Some(INT_TABLE..STR_TABLE + 1),
+      (_, Opcode::BITSHL | Opcode::BITSHRL | Opcode::BITSHRA) => Some(0..32),
+      _ => None,
+    };
+    if let Some(Range { start, end }) = range.filter(|range| !range.contains(&imm)) {
+      return Err(format!(
+        "{:?} operand {} is {}, outside {}..{}",
+        opcode, i, imm, start, end
+      ));
+    }
+  }
+  Ok(())
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,  // absolute offset in the file
+  base: usize, // absolute offset of bytes[0]
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8], base: usize) -> Self {
+    Reader {
+      bytes,
+      pos: base,
+      base,
+    }
+  }
+
+  fn is_at_end(&self) -> bool {
+    self.pos - self.base == self.bytes.len()
+  }
+
+  fn take(&mut self, len: usize, what: &str) -> FormatResult<&'a [u8]> {
+    let start = self.pos - self.base;
+    if self.bytes.len() - start < len {
+      return Err(FormatError::new(
+        &format!(
+          "Unexpected end of data while reading {} ({} bytes needed, {} available)",
+          what,
+          len,
+          self.bytes.len() - start
+        ),
+        self.pos,
+      ));
+    }
+    self.pos += len;
+    Ok(&self.bytes[start..start + len])
+  }
+
+  fn u8(&mut self, what: &str) -> FormatResult<u8> {
+    Ok(self.take(1, what)?[0])
+  }
+
+  fn u16(&mut self, what: &str) -> FormatResult<u16> {
+    Ok(u16::from_be_bytes(self.take(2, what)?.try_into().unwrap()))
+  }
+
+  fn u32(&mut self, what: &str) -> FormatResult<u32> {
+    Ok(u32::from_be_bytes(self.take(4, what)?.try_into().unwrap()))
+  }
+
+  fn i32(&mut self, what: &str) -> FormatResult<i32> {
+    Ok(i32::from_be_bytes(self.take(4, what)?.try_into().unwrap()))
+  }
+
+  fn string(&mut self, what: &str) -> FormatResult<String> {
+    let offset = self.pos;
+    let len = self.u32(&format!("{} length", what))? as usize;
+    let bytes = self.take(len, what)?;
+    String::from_utf8(bytes.to_vec())
+      .map_err(|_| FormatError::new(&format!("Invalid UTF-8 in {}", what), offset))
+  }
+
+  fn list<T>(
+    &mut self,
+    mut item: impl FnMut(&mut Self) -> FormatResult<T>,
+  ) -> FormatResult<Vec<T>> {
+    let count = self.u32("entry count")? as usize;
+    // Every entry takes at least one byte, which bounds the allocation for
+    // corrupt counts.
+    let mut items = Vec::with_capacity(count.min(self.bytes.len()));
+    for _ in 0..count {
+      items.push(item(self)?);
+    }
+    Ok(items)
+  }
+
+  fn instruction(&mut self) -> FormatResult<Instruction> {
+    let offset = self.pos;
+    let raw: [u8; 4] = self.take(4, "opcode")?.try_into().unwrap();
+    let opcode = opcode_from_bytes(raw).ok_or_else(|| {
+      FormatError::new(
+        &format!("Unknown opcode {:#010x}", u32::from_be_bytes(raw)),
+        offset,
+      )
+    })?;
+
+    let count_offset = self.pos;
+    let count = self.u8("operand count")?;
+    if count > MAX_OPERANDS {
+      return Err(FormatError::new(
+        &format!(
+          "{:?} has {} operands, at most {} allowed",
+          opcode, count, MAX_OPERANDS
+        ),
+        count_offset,
+      ));
+    }
+
+    let mut operands = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+      let tag_offset = self.pos;
+      let operand = match self.u8("operand tag")? {
+        OPERAND_REG => Operand::Reg(self.u8("register operand")?),
+        OPERAND_IMM => Operand::Imm(self.i32("immediate operand")?),
+        OPERAND_LABEL => Operand::Label(format!("#{}", self.u32("label operand")?)),
+        tag => {
+          return Err(FormatError::new(
+            &format!("Unknown operand tag {}", tag),
+            tag_offset,
+          ))
+        }
+      };
+      operands.push(operand);
+    }
+    Ok(Instruction::new(opcode, operands))
+  }
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+  bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_str(bytes: &mut Vec<u8>, value: &str) {
+  put_u32(bytes, value.len() as u32);
+  bytes.extend_from_slice(value.as_bytes());
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+  bytes.iter().fold(0x811c9dc5, |hash, byte| {
+    (hash ^ *byte as u32).wrapping_mul(0x01000193)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler::codegen::compile;
+  use crate::parser::parser::Parser;
+  use crate::scanner::scanner::read_str_scan;
+
+  fn compile_lisp_code(code: &str) -> Program {
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    compile(&Parser::new(tokens).parse().unwrap()).unwrap()
+  }
+
+  fn sample_program() -> Program {
+    let mut program = compile_lisp_code(
+      r#"
+            (def square (fn (x) (* x x)))
+            (println "square:" (square 1.5))
+        "#,
+    );
+    program.int_table = vec![-1, 7];
+    program.float_table = vec![0.25];
+    program
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let program = sample_program();
+    let bytes = write_program(&program);
+
+    assert_eq!(&bytes[..4], b"TISP");
+    assert_eq!(read_program(&bytes), Ok(program));
+  }
+
+  #[test]
+  fn test_label_operands_are_resolved() {
+    let mut program = Program::new(vec![
+      Instruction::new(Opcode::JMP, vec![Operand::Label("end".to_string())]),
+      Instruction::new(Opcode::HLT, vec![]),
+    ]);
+    program.labels = vec![("end".to_string(), 1)];
+
+    let read = read_program(&write_program(&program)).unwrap();
+    assert_eq!(read.code[0].operands, vec![Operand::Imm(1)]);
+
+    program.labels.clear();
+    let error = read_program(&write_program(&program)).unwrap_err();
+    assert!(error.message.contains("undefined label @end"));
+  }
+
+  #[test]
+  fn test_rejects_bad_operands() {
+    let error = |instruction: Instruction| {
+      let program = Program::new(vec![instruction]);
+      read_program(&write_program(&program)).unwrap_err().message
+    };
+    assert_eq!(
+      error(Instruction::new(Opcode::ADD, vec![Operand::Reg(0)])),
+      "Instruction 0: ADD expects 3 operands, found 1"
+    );
+    assert_eq!(
+      error(Instruction::new(Opcode::POP, vec![Operand::Imm(1)])),
+      "Instruction 0: POP expects a register as operand 0"
+    );
+    assert_eq!(
+      error(Instruction::new(
+        Opcode::SET_ARG,
+        vec![Operand::Reg(4), Operand::Imm(-1)]
+      )),
+      "Instruction 0: SET_ARG operand 1 is -1, outside 0..256"
+    );
+    assert_eq!(
+      error(Instruction::new(
+        Opcode::LOAD,
+        vec![Operand::Reg(0), Operand::Reg(1), Operand::Imm(7)]
+      )),
+      "Instruction 0: LOAD operand 2 is 7, outside 0..3"
+    );
+    assert_eq!(
+      error(Instruction::new(
+        Opcode::SETS,
+        vec![Operand::Reg(0), Operand::Imm(0)]
+      )),
+      "Instruction 0: SETS operand 1 is 0, outside 0..0"
+    );
+  }
+
+  #[test]
+  fn test_rejects_bad_header() {
+    let mut bytes = write_program(&sample_program());
+    bytes[0] = b'X';
+    let error = read_program(&bytes).unwrap_err();
+    assert_eq!(error.message, "Not a tispc file: bad magic number");
+
+    let mut bytes = write_program(&sample_program());
+    bytes[5] = 9;
+    let error = read_program(&bytes).unwrap_err();
+    assert_eq!(error.message, "Unsupported format version 9 (expected 1)");
+    assert_eq!(error.offset, 4);
+  }
+
+  #[test]
+  fn test_rejects_truncated_files() {
+    let bytes = write_program(&sample_program());
+    for len in 0..bytes.len() {
+      let error = read_program(&bytes[..len]).unwrap_err();
+      assert!(
+        error.message.starts_with("Unexpected end of data")
+          || error.message.starts_with("Not a tispc file")
+          || error.message.contains("section has"),
+        "len {}: {}",
+        len,
+        error
+      );
+    }
+  }
+
+  #[test]
+  fn test_rejects_corruption() {
+    let bytes = write_program(&sample_program());
+    for i in 6..bytes.len() {
+      let mut corrupt = bytes.clone();
+      corrupt[i] ^= 0x40;
+      assert!(read_program(&corrupt).is_err(), "flip at byte {}", i);
+    }
+
+    // Unknown opcodes are reported where they occur, before the checksum.
+    let mut program = Program::new(vec![Instruction::new(Opcode::HLT, vec![])]);
+    program.str_table = vec!["x".to_string()];
+    let mut bytes = write_program(&program);
+    let code_start = bytes.len() - 4 - 5;
+    bytes[code_start] = 0xff;
+    let error = read_program(&bytes).unwrap_err();
+    assert_eq!(error.message, "Unknown opcode 0xff00000d");
+    assert_eq!(error.offset, code_start);
+  }
+}
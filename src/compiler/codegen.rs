@@ -0,0 +1,1234 @@
+use super::{
+  compile_error::{CompileError, CompileResult},
+  instruction::{Instruction, Operand},
+  opcode::Opcode,
+  program::{FunctionInfo, Program},
+};
+use crate::parser::ast::{ASTNode, NodeType, Params};
+use std::collections::HashMap;
+
+// Register layout of every frame:
+// r0: result of the last expression (read by HLT / RETURN)
+// r1: table holding the global `def` bindings, passed to functions as arg 0
+// r2..: parameters, then temporaries
+const RESULT_REG: u8 = 0;
+const GLOBALS_REG: u8 = 1;
+const FIRST_FREE_REG: u16 = 2;
+
+struct FunctionState {
+  info: Option<(String, usize)>, // (name, arity), None for the top level
+  code: Vec<Instruction>,
+  labels: Vec<(String, usize)>,
+  locals: HashMap<String, u8>,
+  next_reg: u16,
+}
+
+impl FunctionState {
+  fn new(label: Option<String>) -> Self {
+    FunctionState {
+      info: None,
+      code: Vec::new(),
+      labels: label.into_iter().map(|label| (label, 0)).collect(),
+      locals: HashMap::new(),
+      next_reg: FIRST_FREE_REG,
+    }
+  }
+}
+
+pub struct Compiler {
+  functions: Vec<FunctionState>, // functions[0] is the top-level code
+  finished: Vec<FunctionState>,
+  globals: HashMap<String, i32>,
+  known_functions: HashMap<String, (String, Params)>, // name -> (label, params)
+  str_table: Vec<String>,
+  label_count: usize,
+}
+
+pub fn compile(program: &ASTNode) -> CompileResult<Program> {
+  Compiler::new().compile_program(program)
+}
+
+impl Compiler {
+  pub fn new() -> Self {
+    Compiler {
+      functions: vec![FunctionState::new(None)],
+      finished: Vec::new(),
+      globals: HashMap::new(),
+      known_functions: HashMap::new(),
+      str_table: Vec::new(),
+      label_count: 0,
+    }
+  }
+
+  pub fn compile_program(mut self, program: &ASTNode) -> CompileResult<Program> {
+    let nodes = match &program.node_type {
+      NodeType::Program(nodes) => nodes,
+      _ => return Err(CompileError::new("Expected a program")),
+    };
+
+    self.collect_globals(nodes);
+    self.emit(Opcode::NEW_TABLE, vec![Operand::Reg(GLOBALS_REG)]);
+    if nodes.is_empty() {
+      self.emit(Opcode::SETNIL, vec![Operand::Reg(RESULT_REG)]);
+    }
+    for node in nodes {
+      self.compile_expr(node, RESULT_REG)?;
+    }
+    self.emit(Opcode::HLT, vec![]);
+
+    let main = self.functions.pop().unwrap();
+    let mut program = link(std::iter::once(main).chain(self.finished))?;
+    program.str_table = self.str_table;
+    Ok(program)
+  }
+
+  // Every `def` gets a slot in the globals table up front so that functions
+  // may refer to bindings defined after them. Top-level `(def f (fn ...))`
+  // bound exactly once is additionally called directly through its label.
+  fn collect_globals(&mut self, nodes: &[ASTNode]) {
+    let mut definitions = HashMap::new();
+    for node in nodes {
+      self.collect_definitions(node, &mut definitions);
+    }
+
+    for node in nodes {
+      if let NodeType::Variable(name, value, _) = &node.node_type {
+        if let NodeType::FuncDef(params, _) = &value.node_type {
+          match Params::from_nodes(params) {
+            Ok(params) if definitions.get(name) == Some(&1) => {
+              let label = format!("fn_{}", name);
+              self.known_functions.insert(name.clone(), (label, params));
+            }
+            _ => {}
+          }
+        }
+      }
+    }
+  }
+
+  fn collect_definitions(&mut self, node: &ASTNode, definitions: &mut HashMap<String, usize>) {
+    match &node.node_type {
+      NodeType::Variable(name, value, _) => {
+        let slot = self.globals.len() as i32;
+        self.globals.entry(name.clone()).or_insert(slot);
+        *definitions.entry(name.clone()).or_insert(0) += 1;
+        self.collect_definitions(value, definitions);
+      }
+      NodeType::List(items) | NodeType::Program(items) => {
+        for item in items {
+          self.collect_definitions(item, definitions);
+        }
+      }
+      NodeType::FuncDef(params, body) => {
+        for item in params.iter().chain(body) {
+          self.collect_definitions(item, definitions);
+        }
+      }
+      // A function that is reassigned can't be called through its label.
+      NodeType::Set(name, value) => {
+        *definitions.entry(name.clone()).or_insert(0) += 1;
+        self.collect_definitions(value, definitions);
+      }
+      _ => {
+        for item in node.special_form_items().unwrap_or_default() {
+          self.collect_definitions(&item, definitions);
+        }
+      }
+    }
+  }
+
+  fn compile_expr(&mut self, node: &ASTNode, dest: u8) -> CompileResult<()> {
+    match &node.node_type {
+      NodeType::Int32(n) => self.emit(Opcode::SETI, vec![Operand::Reg(dest), Operand::Imm(*n)]),
+      NodeType::Float32(n) => self.emit(
+        Opcode::SETF,
+        vec![Operand::Reg(dest), Operand::Imm(n.to_bits() as i32)],
+      ),
+      NodeType::Int64(n) => {
+        return Err(CompileError::new(&format!(
+          "Integer literal {} does not fit in 32 bits",
+          n
+        )))
+      }
+      NodeType::Float64(n) => {
+        return Err(CompileError::new(&format!(
+          "Float literal {:?} is outside the 32-bit float range",
+          n
+        )))
+      }
+      NodeType::Bool(b) => self.emit_bool(*b, dest),
+      NodeType::Nil => self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]),
+      NodeType::StringLiteral(s) => self.emit_string(s, dest),
+      NodeType::Keyword(s) => self.emit_string(&format!(":{}", s), dest),
+      NodeType::Character(c) => self.emit_string(&c.to_string(), dest),
+      NodeType::Symbol(name) => self.compile_symbol(name, dest)?,
+      NodeType::Quote(quoted) => self.compile_quoted(quoted, dest)?,
+      NodeType::Variable(name, value, _) => self.compile_def(name, value, dest)?,
+      NodeType::FuncDef(params, body) => {
+        let label = self.new_label("lambda");
+        self.compile_function(label.clone(), &label, params, body)?;
+        self.emit(
+          Opcode::SETI,
+          vec![Operand::Reg(dest), Operand::Label(label)],
+        );
+      }
+      NodeType::List(items) => self.compile_list(items, dest)?,
+      NodeType::MacroDef(_) => self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]),
+      NodeType::Program(_) => return Err(CompileError::new("Unexpected nested program")),
+      NodeType::MacroTemplate(_) | NodeType::MacroComma(_) | NodeType::MacroListExpand(_) => {
+        return Err(CompileError::new(
+          "Macro syntax must be expanded before compilation",
+        ))
+      }
+      NodeType::If(condition, then, otherwise) => {
+        self.compile_if(condition, then, otherwise.as_deref(), dest)?
+      }
+      NodeType::Let(bindings, body) => self.compile_let(bindings, body, dest)?,
+      NodeType::Do(body) => self.compile_body(body, dest)?,
+      NodeType::Set(name, value) => self.compile_set(name, value, dest)?,
+      NodeType::Cond(clauses) => self.compile_cond(clauses, dest)?,
+      NodeType::And(items) => self.compile_and(items, dest)?,
+      NodeType::Or(items) => self.compile_or(items, dest)?,
+      NodeType::Error => {
+        return Err(CompileError::new(
+          "Cannot compile a form that failed to parse",
+        ))
+      }
+    }
+    Ok(())
+  }
+
+  // Leaves the value of the last form in `dest`, or nil if there are none.
+  // Earlier forms go to a scratch register, so `dest` keeps its old value
+  // until the last form has read it.
+  fn compile_body(&mut self, body: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let Some((last, rest)) = body.split_last() else {
+      self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]);
+      return Ok(());
+    };
+    if !rest.is_empty() {
+      let scratch = self.alloc()?;
+      for node in rest {
+        self.compile_expr(node, scratch)?;
+      }
+      self.free(scratch);
+    }
+    self.compile_expr(last, dest)
+  }
+
+  fn compile_symbol(&mut self, name: &str, dest: u8) -> CompileResult<()> {
+    if let Some(&reg) = self.current().locals.get(name) {
+      self.emit_move(dest, reg);
+      return Ok(());
+    }
+
+    let depth = self.functions.len() - 1;
+    if self.functions[..depth]
+      .iter()
+      .any(|f| f.locals.contains_key(name))
+    {
+      return Err(CompileError::new(&format!(
+        "Cannot capture local variable '{}' in a nested fn: closures are not supported by the compiler",
+        name
+      )));
+    }
+
+    if let Some((label, _)) = self.known_functions.get(name) {
+      let label = label.clone();
+      self.emit(
+        Opcode::SETI,
+        vec![Operand::Reg(dest), Operand::Label(label)],
+      );
+    } else if let Some(&slot) = self.globals.get(name) {
+      let key = self.alloc()?;
+      self.emit(Opcode::SETI, vec![Operand::Reg(key), Operand::Imm(slot)]);
+      self.emit(
+        Opcode::GET_TABLE,
+        vec![
+          Operand::Reg(dest),
+          Operand::Reg(GLOBALS_REG),
+          Operand::Reg(key),
+        ],
+      );
+      self.free(key);
+    } else {
+      return Err(CompileError::new(&format!("Undefined symbol '{}'", name)));
+    }
+    Ok(())
+  }
+
+  fn compile_def(&mut self, name: &str, value: &ASTNode, dest: u8) -> CompileResult<()> {
+    match (&value.node_type, self.known_functions.get(name)) {
+      (NodeType::FuncDef(params, body), Some((label, _))) => {
+        let label = label.clone();
+        self.compile_function(label.clone(), name, params, body)?;
+        self.emit(
+          Opcode::SETI,
+          vec![Operand::Reg(dest), Operand::Label(label)],
+        );
+      }
+      _ => self.compile_expr(value, dest)?,
+    }
+
+    let Some(&slot) = self.globals.get(name) else {
+      return Err(CompileError::new(&format!(
+        "No global slot for '{}': def is only allowed at the top level",
+        name
+      )));
+    };
+    let key = self.alloc()?;
+    self.emit(Opcode::SETI, vec![Operand::Reg(key), Operand::Imm(slot)]);
+    self.emit(
+      Opcode::SET_TABLE,
+      vec![
+        Operand::Reg(GLOBALS_REG),
+        Operand::Reg(key),
+        Operand::Reg(dest),
+      ],
+    );
+    self.free(key);
+    Ok(())
+  }
+
+  fn compile_function(
+    &mut self,
+    label: String,
+    name: &str,
+    params: &[ASTNode],
+    body: &[ASTNode],
+  ) -> CompileResult<()> {
+    let params = Params::from_nodes(params).map_err(|(message, _)| CompileError::new(&message))?;
+    self.functions.push(FunctionState::new(Some(label)));
+    self.current_mut().info = Some((name.to_string(), params.required.len()));
+    self.emit(
+      Opcode::GET_ARG,
+      vec![Operand::Reg(GLOBALS_REG), Operand::Imm(0)],
+    );
+    self.compile_arity_check(&params)?;
+
+    for (i, name) in params.required.iter().enumerate() {
+      let reg = self.alloc()?;
+      self.bind_param(name, reg)?;
+      self.emit(
+        Opcode::GET_ARG,
+        vec![Operand::Reg(reg), Operand::Imm(i as i32 + 1)],
+      );
+    }
+    if params.optional.len() + params.keys.len() > 0 || params.rest.is_some() {
+      self.compile_variadic_params(&params)?;
+    }
+
+    self.compile_body(body, RESULT_REG)?;
+    self.emit(Opcode::RETURN, vec![Operand::Reg(RESULT_REG)]);
+
+    let function = self.functions.pop().unwrap();
+    self.finished.push(function);
+    Ok(())
+  }
+
+  // Calls through a register can't be checked when compiling, so every
+  // function checks its argument count on entry.
+  fn compile_arity_check(&mut self, params: &Params) -> CompileResult<()> {
+    let min = self.alloc()?;
+    let max = self.alloc()?;
+    self.emit(
+      Opcode::SETI,
+      vec![
+        Operand::Reg(min),
+        Operand::Imm(params.required.len() as i32),
+      ],
+    );
+    if params.is_variadic() {
+      self.emit(Opcode::SETNIL, vec![Operand::Reg(max)]);
+    } else {
+      let count = params.required.len() + params.optional.len();
+      self.emit(
+        Opcode::SETI,
+        vec![Operand::Reg(max), Operand::Imm(count as i32)],
+      );
+    }
+    self.emit(
+      Opcode::VMCALL,
+      vec![Operand::Reg(min), Operand::Reg(max), Operand::Imm(5)],
+    );
+    self.free(min);
+    Ok(())
+  }
+
+  fn bind_param(&mut self, name: &str, reg: u8) -> CompileResult<()> {
+    if self.current().locals.contains_key(name) {
+      return Err(CompileError::new(&format!(
+        "Duplicate parameter '{}'",
+        name
+      )));
+    }
+    self.current_mut().locals.insert(name.to_string(), reg);
+    Ok(())
+  }
+
+  // Arguments past the required ones are read from a list of all of them,
+  // whose length tells which &optional ones were passed. `:key value` pairs
+  // are checked by the VM and then matched in a loop.
+  fn compile_variadic_params(&mut self, params: &Params) -> CompileResult<()> {
+    let positional = params.required.len() + params.optional.len();
+    let args = self.alloc()?;
+    let count = self.alloc()?;
+    self.emit(Opcode::GET_ARGS, vec![Operand::Reg(args), Operand::Imm(1)]);
+    self.emit(
+      Opcode::GET_LEN,
+      vec![Operand::Reg(count), Operand::Reg(args)],
+    );
+
+    for (i, (name, default)) in params.optional.iter().enumerate() {
+      let index = (params.required.len() + i) as i32;
+      let reg = self.alloc()?;
+      let passed_label = self.new_label("passed");
+      let end_label = self.new_label("endparam");
+      let passed = self.alloc()?;
+      self.emit(
+        Opcode::SETI,
+        vec![Operand::Reg(passed), Operand::Imm(index)],
+      );
+      self.emit(
+        Opcode::LT,
+        vec![
+          Operand::Reg(passed),
+          Operand::Reg(passed),
+          Operand::Reg(count),
+        ],
+      );
+      self.emit(
+        Opcode::JMP_IF,
+        vec![Operand::Reg(passed), Operand::Label(passed_label.clone())],
+      );
+      self.free(passed);
+      self.compile_default(default.as_ref(), reg)?;
+      self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+      self.place_label(passed_label);
+      self.emit(
+        Opcode::GET_ARG,
+        vec![Operand::Reg(reg), Operand::Imm(index + 1)],
+      );
+      self.place_label(end_label);
+      self.bind_param(name, reg)?;
+    }
+
+    if let Some(name) = &params.rest {
+      let reg = self.alloc()?;
+      self.emit(
+        Opcode::GET_ARGS,
+        vec![Operand::Reg(reg), Operand::Imm(positional as i32 + 1)],
+      );
+      self.bind_param(name, reg)?;
+    }
+
+    if !params.keys.is_empty() {
+      self.compile_key_params(&params.keys, args, count, positional)?;
+    }
+    Ok(())
+  }
+
+  fn compile_key_params(
+    &mut self,
+    keys: &[(String, Option<ASTNode>)],
+    args: u8,
+    count: u8,
+    positional: usize,
+  ) -> CompileResult<()> {
+    let pairs = self.alloc()?;
+    let names = self.alloc()?;
+    let idx = self.alloc()?;
+    let name = self.alloc()?;
+    self.emit(
+      Opcode::GET_ARGS,
+      vec![Operand::Reg(pairs), Operand::Imm(positional as i32 + 1)],
+    );
+    self.emit(Opcode::NEW_LIST, vec![Operand::Reg(names)]);
+    for (i, (key, _)) in keys.iter().enumerate() {
+      self.emit_string(&format!(":{}", key), name);
+      self.emit(
+        Opcode::SETI,
+        vec![Operand::Reg(idx), Operand::Imm(i as i32)],
+      );
+      self.emit(
+        Opcode::SET_LIST,
+        vec![Operand::Reg(names), Operand::Reg(idx), Operand::Reg(name)],
+      );
+    }
+    self.emit(
+      Opcode::VMCALL,
+      vec![Operand::Reg(pairs), Operand::Reg(names), Operand::Imm(4)],
+    );
+    self.free(pairs);
+
+    // A register and a "was passed" flag per key.
+    let mut slots = Vec::new();
+    for _ in keys {
+      let reg = self.alloc()?;
+      let passed = self.alloc()?;
+      self.emit(Opcode::SETNIL, vec![Operand::Reg(reg)]);
+      self.emit_bool(false, passed);
+      slots.push((reg, passed));
+    }
+
+    let index = self.alloc()?;
+    let one = self.alloc()?;
+    let key = self.alloc()?;
+    let value = self.alloc()?;
+    let test = self.alloc()?;
+    let loop_label = self.new_label("keys");
+    let body_label = self.new_label("key");
+    let end_label = self.new_label("endkeys");
+    self.emit(
+      Opcode::SETI,
+      vec![Operand::Reg(index), Operand::Imm(positional as i32)],
+    );
+    self.emit(Opcode::SETI, vec![Operand::Reg(one), Operand::Imm(1)]);
+    self.place_label(loop_label.clone());
+    self.emit(
+      Opcode::LT,
+      vec![Operand::Reg(test), Operand::Reg(index), Operand::Reg(count)],
+    );
+    self.emit(
+      Opcode::JMP_IF,
+      vec![Operand::Reg(test), Operand::Label(body_label.clone())],
+    );
+    self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+    self.place_label(body_label);
+    for reg in [key, value] {
+      self.emit(
+        Opcode::GET_LIST,
+        vec![Operand::Reg(reg), Operand::Reg(args), Operand::Reg(index)],
+      );
+      self.emit(
+        Opcode::ADD,
+        vec![Operand::Reg(index), Operand::Reg(index), Operand::Reg(one)],
+      );
+    }
+    let mut found_labels = Vec::new();
+    for (name, _) in keys {
+      let found = self.new_label("found");
+      self.emit_string(&format!(":{}", name), test);
+      self.emit(
+        Opcode::EQ,
+        vec![Operand::Reg(test), Operand::Reg(test), Operand::Reg(key)],
+      );
+      self.emit(
+        Opcode::JMP_IF,
+        vec![Operand::Reg(test), Operand::Label(found.clone())],
+      );
+      found_labels.push(found);
+    }
+    self.emit(Opcode::JMP, vec![Operand::Label(loop_label.clone())]);
+    for (found, &(reg, passed)) in found_labels.into_iter().zip(&slots) {
+      self.place_label(found);
+      self.emit_move(reg, value);
+      self.emit_bool(true, passed);
+      self.emit(Opcode::JMP, vec![Operand::Label(loop_label.clone())]);
+    }
+    self.place_label(end_label);
+    self.free(index);
+
+    for ((name, default), (reg, passed)) in keys.iter().zip(slots) {
+      let passed_label = self.new_label("passed");
+      self.emit(
+        Opcode::JMP_IF,
+        vec![Operand::Reg(passed), Operand::Label(passed_label.clone())],
+      );
+      self.compile_default(default.as_ref(), reg)?;
+      self.place_label(passed_label);
+      self.bind_param(name, reg)?;
+    }
+    Ok(())
+  }
+
+  fn compile_default(&mut self, default: Option<&ASTNode>, dest: u8) -> CompileResult<()> {
+    match default {
+      Some(default) => self.compile_expr(default, dest),
+      None => {
+        self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]);
+        Ok(())
+      }
+    }
+  }
+
+  fn compile_list(&mut self, items: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let (head, args) = match items.split_first() {
+      Some(split) => split,
+      None => {
+        self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]);
+        return Ok(());
+      }
+    };
+
+    if let NodeType::Symbol(name) = &head.node_type {
+      if !self.is_bound(name) {
+        match name.as_str() {
+          "+" | "-" | "*" | "/" => return self.compile_arithmetic(name, args, dest),
+          "<" | "<=" | ">" | ">=" | "=" | "==" | "!=" => {
+            return self.compile_comparison(name, args, dest)
+          }
+          "not" => return self.compile_not(args, dest),
+          "print" | "println" => return self.compile_print(name == "println", args, dest),
+          "list" => return self.compile_list_literal(args, dest, false),
+          _ => {}
+        }
+      }
+    }
+
+    self.compile_call(head, args, dest)
+  }
+
+  fn compile_call(&mut self, head: &ASTNode, args: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let mark = self.current().next_reg;
+
+    let target = match &head.node_type {
+      NodeType::Symbol(name) if !self.current().locals.contains_key(name) => self
+        .known_functions
+        .get(name)
+        .cloned()
+        .map(|(label, params)| (label, params, name.clone())),
+      _ => None,
+    };
+    let callee = match &target {
+      Some((label, params, name)) => {
+        if !params.accepts(args.len()) {
+          return Err(CompileError::new(&format!(
+            "Function '{}' expects {} arguments, got {}",
+            name,
+            params.arity(),
+            args.len()
+          )));
+        }
+        Operand::Label(label.clone())
+      }
+      None => {
+        let reg = self.alloc()?;
+        self.compile_expr(head, reg)?;
+        Operand::Reg(reg)
+      }
+    };
+
+    // Arguments are evaluated before any SET_ARG, since nested calls would
+    // otherwise consume the staged arguments.
+    let mut arg_regs = Vec::new();
+    for arg in args {
+      let reg = self.alloc()?;
+      self.compile_expr(arg, reg)?;
+      arg_regs.push(reg);
+    }
+
+    self.emit(
+      Opcode::SET_ARG,
+      vec![Operand::Reg(GLOBALS_REG), Operand::Imm(0)],
+    );
+    for (i, reg) in arg_regs.into_iter().enumerate() {
+      self.emit(
+        Opcode::SET_ARG,
+        vec![Operand::Reg(reg), Operand::Imm(i as i32 + 1)],
+      );
+    }
+    self.emit(Opcode::CALL, vec![callee]);
+    self.emit(Opcode::POP, vec![Operand::Reg(dest)]);
+
+    self.current_mut().next_reg = mark;
+    Ok(())
+  }
+
+  fn compile_if(
+    &mut self,
+    condition: &ASTNode,
+    then: &ASTNode,
+    otherwise: Option<&ASTNode>,
+    dest: u8,
+  ) -> CompileResult<()> {
+    let then_label = self.new_label("then");
+    let end_label = self.new_label("endif");
+
+    let cond = self.alloc()?;
+    self.compile_expr(condition, cond)?;
+    self.emit(
+      Opcode::JMP_IF,
+      vec![Operand::Reg(cond), Operand::Label(then_label.clone())],
+    );
+    self.free(cond);
+
+    match otherwise {
+      Some(otherwise) => self.compile_expr(otherwise, dest)?,
+      None => self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]),
+    }
+    self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+    self.place_label(then_label);
+    self.compile_expr(then, dest)?;
+    self.place_label(end_label);
+    Ok(())
+  }
+
+  // Each binding gets a register of its own for the rest of the `let`.
+  fn compile_let(
+    &mut self,
+    bindings: &[(String, ASTNode)],
+    body: &[ASTNode],
+    dest: u8,
+  ) -> CompileResult<()> {
+    let mark = self.current().next_reg;
+    let mut shadowed = Vec::new();
+    let mut result = Ok(());
+    for (name, value) in bindings {
+      let reg = match self.alloc() {
+        Ok(reg) => reg,
+        Err(error) => {
+          result = Err(error);
+          break;
+        }
+      };
+      result = self.compile_expr(value, reg);
+      let previous = self.current_mut().locals.insert(name.clone(), reg);
+      shadowed.push((name, previous));
+      if result.is_err() {
+        break;
+      }
+    }
+    if result.is_ok() {
+      result = self.compile_body(body, dest);
+    }
+
+    for (name, previous) in shadowed.into_iter().rev() {
+      let locals = &mut self.current_mut().locals;
+      match previous {
+        Some(reg) => locals.insert(name.clone(), reg),
+        None => locals.remove(name),
+      };
+    }
+    self.current_mut().next_reg = mark;
+    result
+  }
+
+  fn compile_set(&mut self, name: &str, value: &ASTNode, dest: u8) -> CompileResult<()> {
+    // The value may still read the variable, so it's built in a temporary.
+    if let Some(&reg) = self.current().locals.get(name) {
+      let temp = self.alloc()?;
+      self.compile_expr(value, temp)?;
+      self.emit_move(reg, temp);
+      self.emit_move(dest, temp);
+      self.free(temp);
+      return Ok(());
+    }
+    let Some(&slot) = self.globals.get(name) else {
+      return Err(CompileError::new(&format!(
+        "Cannot set! undefined variable '{}'",
+        name
+      )));
+    };
+
+    self.compile_expr(value, dest)?;
+    let key = self.alloc()?;
+    self.emit(Opcode::SETI, vec![Operand::Reg(key), Operand::Imm(slot)]);
+    self.emit(
+      Opcode::SET_TABLE,
+      vec![
+        Operand::Reg(GLOBALS_REG),
+        Operand::Reg(key),
+        Operand::Reg(dest),
+      ],
+    );
+    self.free(key);
+    Ok(())
+  }
+
+  // Tests run in order into a temporary; the first truthy one jumps to its
+  // body, which results in the test's value if it's empty.
+  fn compile_cond(&mut self, clauses: &[(ASTNode, Vec<ASTNode>)], dest: u8) -> CompileResult<()> {
+    let end_label = self.new_label("endcond");
+    let mut body_labels = Vec::new();
+    let value = self.alloc()?;
+    for (test, _) in clauses {
+      let label = self.new_label("clause");
+      self.compile_expr(test, value)?;
+      self.emit(
+        Opcode::JMP_IF,
+        vec![Operand::Reg(value), Operand::Label(label.clone())],
+      );
+      body_labels.push(label);
+    }
+    self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]);
+    self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+
+    for ((_, body), label) in clauses.iter().zip(body_labels) {
+      self.place_label(label);
+      if body.is_empty() {
+        self.emit_move(dest, value);
+      } else {
+        self.compile_body(body, dest)?;
+      }
+      self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+    }
+    self.place_label(end_label);
+    self.free(value);
+    Ok(())
+  }
+
+  // Stops at the first falsy value, which is the result; #t if empty.
+  fn compile_and(&mut self, items: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let Some((last, rest)) = items.split_last() else {
+      self.emit_bool(true, dest);
+      return Ok(());
+    };
+    let end_label = self.new_label("endand");
+    let value = self.alloc()?;
+    for item in rest {
+      let next_label = self.new_label("and");
+      self.compile_expr(item, value)?;
+      self.emit(
+        Opcode::JMP_IF,
+        vec![Operand::Reg(value), Operand::Label(next_label.clone())],
+      );
+      self.emit_move(dest, value);
+      self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+      self.place_label(next_label);
+    }
+    self.compile_expr(last, dest)?;
+    self.place_label(end_label);
+    self.free(value);
+    Ok(())
+  }
+
+  // Stops at the first truthy value, which is the result; #f if empty.
+  fn compile_or(&mut self, items: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let Some((last, rest)) = items.split_last() else {
+      self.emit_bool(false, dest);
+      return Ok(());
+    };
+    let found_label = self.new_label("foundor");
+    let end_label = self.new_label("endor");
+    let value = self.alloc()?;
+    for item in rest {
+      self.compile_expr(item, value)?;
+      self.emit(
+        Opcode::JMP_IF,
+        vec![Operand::Reg(value), Operand::Label(found_label.clone())],
+      );
+    }
+    self.compile_expr(last, dest)?;
+    self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+    self.place_label(found_label);
+    self.emit_move(dest, value);
+    self.place_label(end_label);
+    self.free(value);
+    Ok(())
+  }
+
+  fn compile_arithmetic(&mut self, op: &str, args: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let opcode = match op {
+      "+" => Opcode::ADD,
+      "-" => Opcode::SUB,
+      "*" => Opcode::MUL,
+      _ => Opcode::DIV,
+    };
+
+    match (op, args.len()) {
+      ("+", 0) => self.emit(Opcode::SETI, vec![Operand::Reg(dest), Operand::Imm(0)]),
+      ("*", 0) => self.emit(Opcode::SETI, vec![Operand::Reg(dest), Operand::Imm(1)]),
+      (_, 0) => {
+        return Err(CompileError::new(&format!(
+          "{} expects at least 1 argument",
+          op
+        )))
+      }
+      ("-", 1) => {
+        self.compile_expr(&args[0], dest)?;
+        self.emit(Opcode::NEGATE, vec![Operand::Reg(dest), Operand::Reg(dest)]);
+      }
+      ("/", 1) => {
+        let operand = self.alloc()?;
+        self.compile_expr(&args[0], operand)?;
+        self.emit(Opcode::SETI, vec![Operand::Reg(dest), Operand::Imm(1)]);
+        self.emit(
+          Opcode::DIV,
+          vec![
+            Operand::Reg(dest),
+            Operand::Reg(dest),
+            Operand::Reg(operand),
+          ],
+        );
+        self.free(operand);
+      }
+      _ => {
+        let acc = self.alloc()?;
+        self.compile_expr(&args[0], acc)?;
+        let operand = self.alloc()?;
+        for arg in &args[1..] {
+          self.compile_expr(arg, operand)?;
+          self.emit(
+            opcode,
+            vec![Operand::Reg(acc), Operand::Reg(acc), Operand::Reg(operand)],
+          );
+        }
+        self.emit_move(dest, acc);
+        self.free(acc);
+      }
+    }
+    Ok(())
+  }
+
+  fn compile_comparison(&mut self, op: &str, args: &[ASTNode], dest: u8) -> CompileResult<()> {
+    if args.len() != 2 {
+      return Err(CompileError::new(&format!(
+        "{} expects 2 arguments, got {}",
+        op,
+        args.len()
+      )));
+    }
+    let opcode = match op {
+      "<" => Opcode::LT,
+      "<=" => Opcode::LTE,
+      ">" => Opcode::GT,
+      ">=" => Opcode::GTE,
+      "!=" => Opcode::NEQ,
+      _ => Opcode::EQ,
+    };
+
+    let lhs = self.alloc()?;
+    let rhs = self.alloc()?;
+    self.compile_expr(&args[0], lhs)?;
+    self.compile_expr(&args[1], rhs)?;
+    self.emit(
+      opcode,
+      vec![Operand::Reg(dest), Operand::Reg(lhs), Operand::Reg(rhs)],
+    );
+    self.free(lhs);
+    Ok(())
+  }
+
+  fn compile_not(&mut self, args: &[ASTNode], dest: u8) -> CompileResult<()> {
+    if args.len() != 1 {
+      return Err(CompileError::new(&format!(
+        "not expects 1 argument, got {}",
+        args.len()
+      )));
+    }
+    let truthy_label = self.new_label("truthy");
+    let end_label = self.new_label("endnot");
+
+    let operand = self.alloc()?;
+    self.compile_expr(&args[0], operand)?;
+    self.emit(
+      Opcode::JMP_IF,
+      vec![Operand::Reg(operand), Operand::Label(truthy_label.clone())],
+    );
+    self.free(operand);
+    self.emit_bool(true, dest);
+    self.emit(Opcode::JMP, vec![Operand::Label(end_label.clone())]);
+    self.place_label(truthy_label);
+    self.emit_bool(false, dest);
+    self.place_label(end_label);
+    Ok(())
+  }
+
+  fn compile_print(&mut self, newline: bool, args: &[ASTNode], dest: u8) -> CompileResult<()> {
+    let value = self.alloc()?;
+    let flag = self.alloc()?;
+    for (i, arg) in args.iter().enumerate() {
+      if i > 0 {
+        self.emit_string(" ", value);
+        self.emit(Opcode::SETI, vec![Operand::Reg(flag), Operand::Imm(0)]);
+        self.emit(
+          Opcode::VMCALL,
+          vec![Operand::Reg(value), Operand::Reg(flag), Operand::Imm(0)],
+        );
+      }
+      self.compile_expr(arg, value)?;
+      let last = newline && i + 1 == args.len();
+      self.emit(
+        Opcode::SETI,
+        vec![Operand::Reg(flag), Operand::Imm(last as i32)],
+      );
+      self.emit(
+        Opcode::VMCALL,
+        vec![Operand::Reg(value), Operand::Reg(flag), Operand::Imm(0)],
+      );
+    }
+    if newline && args.is_empty() {
+      self.emit_string("", value);
+      self.emit(Opcode::SETI, vec![Operand::Reg(flag), Operand::Imm(1)]);
+      self.emit(
+        Opcode::VMCALL,
+        vec![Operand::Reg(value), Operand::Reg(flag), Operand::Imm(0)],
+      );
+    }
+    self.free(value);
+    self.emit(Opcode::SETNIL, vec![Operand::Reg(dest)]);
+    Ok(())
+  }
+
+  fn compile_list_literal(
+    &mut self,
+    items: &[ASTNode],
+    dest: u8,
+    quoted: bool,
+  ) -> CompileResult<()> {
+    let list = self.alloc()?;
+    let idx = self.alloc()?;
+    let value = self.alloc()?;
+    self.emit(Opcode::NEW_LIST, vec![Operand::Reg(list)]);
+    for (i, item) in items.iter().enumerate() {
+      if quoted {
+        self.compile_quoted(item, value)?;
+      } else {
+        self.compile_expr(item, value)?;
+      }
+      self.emit(
+        Opcode::SETI,
+        vec![Operand::Reg(idx), Operand::Imm(i as i32)],
+      );
+      self.emit(
+        Opcode::SET_LIST,
+        vec![Operand::Reg(list), Operand::Reg(idx), Operand::Reg(value)],
+      );
+    }
+    self.emit_move(dest, list);
+    self.free(list);
+    Ok(())
+  }
+
+  fn compile_quoted(&mut self, node: &ASTNode, dest: u8) -> CompileResult<()> {
+    match &node.node_type {
+      NodeType::Symbol(name) => self.emit_string(name, dest),
+      NodeType::List(items) => self.compile_list_literal(items, dest, true)?,
+      NodeType::Quote(inner) => {
+        let items = [
+          NodeType::Symbol("quote".to_string()).into(),
+          inner.as_ref().clone(),
+        ];
+        self.compile_list_literal(&items, dest, true)?
+      }
+      NodeType::Int32(_)
+      | NodeType::Int64(_)
+      | NodeType::Float32(_)
+      | NodeType::Float64(_)
+      | NodeType::Bool(_)
+      | NodeType::Nil
+      | NodeType::StringLiteral(_)
+      | NodeType::Keyword(_)
+      | NodeType::Character(_) => self.compile_expr(node, dest)?,
+      _ => match node.special_form_items() {
+        Some(items) => self.compile_list_literal(&items, dest, true)?,
+        None => return Err(CompileError::new("Unsupported quoted expression")),
+      },
+    }
+    Ok(())
+  }
+
+  fn is_bound(&self, name: &str) -> bool {
+    self.current().locals.contains_key(name) || self.globals.contains_key(name)
+  }
+
+  fn current(&self) -> &FunctionState {
+    self.functions.last().unwrap()
+  }
+
+  fn current_mut(&mut self) -> &mut FunctionState {
+    self.functions.last_mut().unwrap()
+  }
+
+  fn alloc(&mut self) -> CompileResult<u8> {
+    let state = self.current_mut();
+    if state.next_reg > u8::MAX as u16 {
+      return Err(CompileError::new(
+        "Expression too complex: ran out of registers",
+      ));
+    }
+    state.next_reg += 1;
+    Ok((state.next_reg - 1) as u8)
+  }
+
+  // Releases `reg` and every register allocated after it.
+  fn free(&mut self, reg: u8) {
+    self.current_mut().next_reg = reg as u16;
+  }
+
+  fn new_label(&mut self, prefix: &str) -> String {
+    self.label_count += 1;
+    format!("{}_{}", prefix, self.label_count)
+  }
+
+  fn place_label(&mut self, label: String) {
+    let state = self.current_mut();
+    let offset = state.code.len();
+    state.labels.push((label, offset));
+  }
+
+  fn emit(&mut self, opcode: Opcode, operands: Vec<Operand>) {
+    self
+      .current_mut()
+      .code
+      .push(Instruction::new(opcode, operands));
+  }
+
+  // The ISA has no MOV, so registers are copied through the value stack.
+  fn emit_move(&mut self, dest: u8, src: u8) {
+    if dest != src {
+      self.emit(Opcode::PUSH, vec![Operand::Reg(src)]);
+      self.emit(Opcode::POP, vec![Operand::Reg(dest)]);
+    }
+  }
+
+  // Nor is there a boolean immediate: compare a register with itself.
+  fn emit_bool(&mut self, value: bool, dest: u8) {
+    let opcode = if value { Opcode::EQ } else { Opcode::NEQ };
+    self.emit(Opcode::SETI, vec![Operand::Reg(dest), Operand::Imm(0)]);
+    self.emit(
+      opcode,
+      vec![Operand::Reg(dest), Operand::Reg(dest), Operand::Reg(dest)],
+    );
+  }
+
+  fn emit_string(&mut self, value: &str, dest: u8) {
+    let idx = match self.str_table.iter().position(|s| s == value) {
+      Some(idx) => idx,
+      None => {
+        self.str_table.push(value.to_string());
+        self.str_table.len() - 1
+      }
+    };
+    self.emit(
+      Opcode::SETS,
+      vec![Operand::Reg(dest), Operand::Imm(idx as i32)],
+    );
+  }
+}
+
+impl Default for Compiler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// Concatenates function bodies and replaces every `@label` with its address.
+fn link(functions: impl Iterator<Item = FunctionState>) -> CompileResult<Program> {
+  let mut program = Program::default();
+  let mut addresses = HashMap::new();
+  for function in functions {
+    let base = program.code.len();
+    if let Some((name, arity)) = function.info {
+      program.functions.push(FunctionInfo {
+        name,
+        address: base,
+        arity,
+      });
+    }
+    for (label, offset) in function.labels {
+      addresses.insert(label.clone(), (base + offset) as i32);
+      program.labels.push((label, base + offset));
+    }
+    program.code.extend(function.code);
+  }
+
+  for instruction in program.code.iter_mut() {
+    for operand in instruction.operands.iter_mut() {
+      if let Operand::Label(label) = operand {
+        match addresses.get(label) {
+          Some(&address) => *operand = Operand::Imm(address),
+          None => return Err(CompileError::new(&format!("Undefined label @{}", label))),
+        }
+      }
+    }
+  }
+  Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::parser::Parser;
+  use crate::scanner::scanner::read_str_scan;
+  use crate::vm::{value::Value, vm::Vm};
+  use std::io;
+
+  fn compile_lisp_code(code: &str) -> CompileResult<Program> {
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    let ast = Parser::new(tokens).parse().unwrap();
+    compile(&ast)
+  }
+
+  fn run_lisp_code(code: &str) -> (Value, String) {
+    let program = compile_lisp_code(code).unwrap();
+    let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+    let value = vm.run().unwrap();
+    (value, String::from_utf8(vm.output().clone()).unwrap())
+  }
+
+  #[test]
+  fn test_function_call() {
+    let code = r#"
+            (def square
+              (fn (x)
+                (* x x)))
+            (square 5)
+        "#;
+
+    assert_eq!(run_lisp_code(code).0, Value::Int(25));
+  }
+
+  #[test]
+  fn test_recursion_and_if() {
+    let code = r#"
+            (def fact (fn (n)
+              (if (<= n 1)
+                1
+                (* n (fact (- n 1))))))
+            (fact 10)
+        "#;
+
+    assert_eq!(run_lisp_code(code).0, Value::Int(3628800));
+  }
+
+  #[test]
+  fn test_globals_and_first_class_functions() {
+    let code = r#"
+            (def base 10)
+            (def add-base (fn (x) (+ base x)))
+            (def apply (fn (f x) (f x)))
+            (apply add-base 5)
+        "#;
+
+    assert_eq!(run_lisp_code(code).0, Value::Int(15));
+  }
+
+  #[test]
+  fn test_literals_and_print() {
+    let code = r#"
+            (println "answer:" 42 2.5 #t nil)
+            (print '(1 2))
+            (not #f)
+        "#;
+
+    let (value, output) = run_lisp_code(code);
+    assert_eq!(value, Value::Bool(true));
+    assert_eq!(output, "answer: 42 2.5 #t nil\n(1 2)");
+  }
+
+  #[test]
+  fn test_compile_errors() {
+    let error = compile_lisp_code("(undefined 1)").unwrap_err();
+    assert_eq!(error.message, "Undefined symbol 'undefined'");
+
+    let error = compile_lisp_code("(def f (fn (x) x)) (f 1 2)").unwrap_err();
+    assert_eq!(error.message, "Function 'f' expects 1 arguments, got 2");
+
+    let error = compile_lisp_code("(defn f (a &rest b) a) (f)").unwrap_err();
+    assert_eq!(
+      error.message,
+      "Function 'f' expects at least 1 arguments, got 0"
+    );
+
+    let error = compile_lisp_code("(def f (fn () (set! missing 1)))").unwrap_err();
+    assert_eq!(error.message, "Cannot set! undefined variable 'missing'");
+
+    let error = compile_lisp_code("(+ 1 #x1_0000_0000)").unwrap_err();
+    assert_eq!(
+      error.message,
+      "Integer literal 4294967296 does not fit in 32 bits"
+    );
+  }
+}
@@ -0,0 +1,22 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+  pub message: String,
+}
+
+impl CompileError {
+  pub fn new(message: &str) -> Self {
+    CompileError {
+      message: message.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for CompileError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
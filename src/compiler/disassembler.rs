@@ -0,0 +1,116 @@
+use super::{
+  instruction::Operand,
+  opcode::{operand_kinds, Opcode, OperandKind},
+  program::Program,
+};
+use std::{collections::HashMap, fmt::Write};
+
+// Renders a program as assembler source: constant tables as directives,
+// then one instruction per line prefixed with its offset. Jump targets are
+// printed as `@label` where one exists, float and string operands are
+// annotated with their values. The output assembles back to the same program.
+pub fn disassemble(program: &Program) -> String {
+  let mut out = String::new();
+
+  for n in &program.int_table {
+    writeln!(out, ".int {}", n).unwrap();
+  }
+  for n in &program.float_table {
+    writeln!(out, ".float {:?}", n).unwrap();
+  }
+  for s in &program.str_table {
+    writeln!(out, ".str {}", quote(s)).unwrap();
+  }
+
+  let mut labels_at: HashMap<usize, Vec<&str>> = HashMap::new();
+  for (name, address) in &program.labels {
+    labels_at.entry(*address).or_default().push(name);
+  }
+  let label_for = |address: i32| -> Option<&str> {
+    labels_at
+      .get(&(address as usize))
+      .and_then(|names| names.first().copied())
+  };
+
+  for (pc, instruction) in program.code.iter().enumerate() {
+    for function in program.functions.iter().filter(|f| f.address == pc) {
+      writeln!(out, ".func {}/{}", function.name, function.arity).unwrap();
+    }
+    for name in labels_at.get(&pc).into_iter().flatten() {
+      writeln!(out, "{}:", name).unwrap();
+    }
+
+    let kinds = operand_kinds(instruction.opcode);
+    let mut operands = Vec::new();
+    let mut notes = Vec::new();
+    for (i, operand) in instruction.operands.iter().enumerate() {
+      let kind = kinds.get(i).copied().unwrap_or(OperandKind::Imm);
+      let text = match (kind, operand) {
+        (_, Operand::Reg(r)) => format!("r{}", r),
+        (_, Operand::Label(label)) => format!("@{}", label),
+        (OperandKind::Float, Operand::Imm(bits)) => format!("{:?}", f32::from_bits(*bits as u32)),
+        (OperandKind::Str, Operand::Imm(idx)) => {
+          let first = program
+            .str_table
+            .iter()
+            .position(|s| Some(s) == program.str_table.get(*idx as usize));
+          match first {
+            Some(first) if first == *idx as usize => quote(&program.str_table[first]),
+            _ => idx.to_string(),
+          }
+        }
+        (OperandKind::Imm | OperandKind::Target, Operand::Imm(n))
+          if refers_to_code(program, instruction.opcode, *n) =>
+        {
+          match label_for(*n) {
+            Some(label) => {
+              notes.push(format!("-> {:04}", n));
+              format!("@{}", label)
+            }
+            None => n.to_string(),
+          }
+        }
+        (_, Operand::Imm(n)) => n.to_string(),
+      };
+      operands.push(text);
+    }
+
+    let mut line = format!("{:04}:  {:?}", pc, instruction.opcode);
+    if !operands.is_empty() {
+      write!(line, " {}", operands.join(", ")).unwrap();
+    }
+    if !notes.is_empty() {
+      line = format!("{:<40}; {}", line, notes.join(", "));
+    }
+    writeln!(out, "{}", line).unwrap();
+  }
+
+  out
+}
+
+// Jump and call immediates are code addresses; a SETI immediate is only
+// taken as one when it's a function entry (a function value).
+fn refers_to_code(program: &Program, opcode: Opcode, value: i32) -> bool {
+  match opcode {
+    Opcode::JMP | Opcode::JMP_IF | Opcode::CALL => true,
+    Opcode::SETI => program.functions.iter().any(|f| f.address as i32 == value),
+    _ => false,
+  }
+}
+
+fn quote(s: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\t' => quoted.push_str("\\t"),
+      '\r' => quoted.push_str("\\r"),
+      '\0' => quoted.push_str("\\0"),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
@@ -0,0 +1,24 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatError {
+  pub message: String,
+  pub offset: usize,
+}
+
+impl FormatError {
+  pub fn new(message: &str, offset: usize) -> Self {
+    FormatError {
+      message: message.to_string(),
+      offset,
+    }
+  }
+}
+
+impl fmt::Display for FormatError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} at byte offset {}", self.message, self.offset)
+  }
+}
+
+pub type FormatResult<T> = Result<T, FormatError>;
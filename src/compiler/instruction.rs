@@ -0,0 +1,20 @@
+use super::opcode::Opcode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+  Reg(u8),
+  Imm(i32),
+  Label(String), // unresolved @label, replaced by Imm(address) before execution
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+  pub opcode: Opcode,
+  pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+  pub fn new(opcode: Opcode, operands: Vec<Operand>) -> Self {
+    Instruction { opcode, operands }
+  }
+}
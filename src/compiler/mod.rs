@@ -1 +1,13 @@
+// Only tests hand-write programs in assembly so far.
+#[allow(dead_code)]
+pub mod asm_error;
+#[allow(dead_code)]
+pub mod assembler;
+pub mod bytecode;
+pub mod codegen;
+pub mod compile_error;
+pub mod disassembler;
+pub mod format_error;
+pub mod instruction;
 pub mod opcode;
+pub mod program;
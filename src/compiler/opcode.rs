@@ -1,4 +1,5 @@
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[repr(u8)]
 pub enum Opcode {
   SETI,   // rd, 32bit imm ;set reg Value as 32bit imm
@@ -43,6 +44,8 @@ pub enum Opcode {
   // imm = 1 : get int32 to r1
   // imm = 2 : get float to r1
   // imm = 3 : get string from input, save ptr to r1
+  // imm = 4 : check that list r1 holds only ":key value" pairs with keys from list r2
+  // imm = 5 : check that args 1.. number at least r1 and at most r2 (nil: no limit)
   PUSH, // r1
   POP,  // rd
 
@@ -67,9 +70,124 @@ pub enum Opcode {
 
   IGL,
   NOP,
+
+  GET_ARGS, // rd, imm ; rd = list of the args from imm on
 }
 
+// Indexed by discriminant.
+pub const OPCODES: [Opcode; 49] = [
+  Opcode::SETI,
+  Opcode::SETF,
+  Opcode::SETS,
+  Opcode::SETNIL,
+  Opcode::STORE,
+  Opcode::LOAD,
+  Opcode::ADD,
+  Opcode::SUB,
+  Opcode::MUL,
+  Opcode::DIV,
+  Opcode::CVT_I_D,
+  Opcode::CVT_D_I,
+  Opcode::NEGATE,
+  Opcode::HLT,
+  Opcode::JMP,
+  Opcode::JMP_IF,
+  Opcode::EQ,
+  Opcode::NEQ,
+  Opcode::GT,
+  Opcode::GTE,
+  Opcode::LT,
+  Opcode::LTE,
+  Opcode::BITAND,
+  Opcode::BITOR,
+  Opcode::BITXOR,
+  Opcode::BITNOT,
+  Opcode::BITSHL,
+  Opcode::BITSHRL,
+  Opcode::BITSHRA,
+  Opcode::VMCALL,
+  Opcode::PUSH,
+  Opcode::POP,
+  Opcode::GET_LEN,
+  Opcode::SET_ARG,
+  Opcode::GET_ARG,
+  Opcode::CALL,
+  Opcode::RETURN,
+  Opcode::NEW_LIST,
+  Opcode::SET_LIST,
+  Opcode::GET_LIST,
+  Opcode::NEW_TABLE,
+  Opcode::SET_TABLE,
+  Opcode::GET_TABLE,
+  Opcode::NEW_ARRAY,
+  Opcode::SET_ARRAY,
+  Opcode::GET_ARRAY,
+  Opcode::IGL,
+  Opcode::NOP,
+  Opcode::GET_ARGS,
+];
+
 pub fn opcode_to_bytes(op: Opcode) -> [u8; 4] {
   let value = op as u32;
   value.to_be_bytes()
 }
+
+pub fn opcode_from_bytes(bytes: [u8; 4]) -> Option<Opcode> {
+  let value = u32::from_be_bytes(bytes);
+  OPCODES.get(value as usize).copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+  Reg,    // rN
+  Imm,    // 32bit int imm | @label
+  Float,  // 32bit float imm, stored as its bits
+  Str,    // index into STR_TABLE
+  Target, // rN | 32bit imm | @label
+}
+
+// Operand layout of each opcode, as documented on the enum.
+pub fn operand_kinds(op: Opcode) -> &'static [OperandKind] {
+  use OperandKind::*;
+  match op {
+    Opcode::SETI => &[Reg, Imm],
+    Opcode::SETF => &[Reg, Float],
+    Opcode::SETS => &[Reg, Str],
+    Opcode::SETNIL
+    | Opcode::PUSH
+    | Opcode::POP
+    | Opcode::RETURN
+    | Opcode::NEW_LIST
+    | Opcode::NEW_TABLE
+    | Opcode::NEW_ARRAY => &[Reg],
+    Opcode::STORE | Opcode::LOAD => &[Reg, Reg, Imm],
+    Opcode::BITSHL | Opcode::BITSHRL | Opcode::BITSHRA => &[Reg, Reg, Imm],
+    Opcode::VMCALL => &[Reg, Reg, Imm],
+    Opcode::CVT_I_D | Opcode::CVT_D_I | Opcode::NEGATE | Opcode::BITNOT | Opcode::GET_LEN => {
+      &[Reg, Reg]
+    }
+    Opcode::JMP => &[Imm],
+    Opcode::JMP_IF | Opcode::SET_ARG | Opcode::GET_ARG | Opcode::GET_ARGS => &[Reg, Imm],
+    Opcode::CALL => &[Target],
+    Opcode::HLT | Opcode::IGL | Opcode::NOP => &[],
+    Opcode::ADD
+    | Opcode::SUB
+    | Opcode::MUL
+    | Opcode::DIV
+    | Opcode::EQ
+    | Opcode::NEQ
+    | Opcode::GT
+    | Opcode::GTE
+    | Opcode::LT
+    | Opcode::LTE
+    | Opcode::BITAND
+    | Opcode::BITOR
+    | Opcode::BITXOR
+    | Opcode::SET_LIST
+    | Opcode::GET_LIST
+    | Opcode::SET_TABLE
+    | Opcode::GET_TABLE
+    | Opcode::SET_ARRAY
+    | Opcode::GET_ARRAY => &[Reg, Reg, Reg],
+  }
+}
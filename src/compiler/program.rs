@@ -0,0 +1,31 @@
+use super::instruction::Instruction;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+  pub name: String,
+  pub address: usize,
+  pub arity: usize,
+}
+
+// A runnable unit: the instruction stream plus the initial contents of the
+// INT/FLOAT/STR tables addressed by LOAD/STORE (table id 0/1/2). Functions
+// and labels are kept for tooling, execution only needs resolved addresses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+  pub code: Vec<Instruction>,
+  pub int_table: Vec<i32>,
+  pub float_table: Vec<f32>,
+  pub str_table: Vec<String>,
+  pub functions: Vec<FunctionInfo>,
+  pub labels: Vec<(String, usize)>,
+}
+
+impl Program {
+  #[allow(dead_code)]
+  pub fn new(code: Vec<Instruction>) -> Self {
+    Program {
+      code,
+      ..Default::default()
+    }
+  }
+}
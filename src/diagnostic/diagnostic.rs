@@ -0,0 +1,131 @@
+use crate::expander::{expander::Expander, expander_error::ExpandError};
+use crate::parser::{
+  ast::ASTNode,
+  parser::{Parser, TemplateTable},
+  parser_error::{Note, ParseError},
+};
+use crate::scanner::{
+  scanner::read_str_scan_with_file, scanner_error::ScanError, span::FileId, span::Span,
+};
+use std::fmt;
+
+// Every problem found while reading source, whichever stage found it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+  Scan(ScanError),
+  Parse(ParseError),
+  Expand(ExpandError),
+}
+
+impl Diagnostic {
+  pub fn span(&self) -> Span {
+    match self {
+      Diagnostic::Scan(error) => error.span(),
+      Diagnostic::Parse(error) => error.span,
+      Diagnostic::Expand(error) => error.span,
+    }
+  }
+
+  pub fn notes(&self) -> &[Note] {
+    match self {
+      Diagnostic::Scan(_) => &[],
+      Diagnostic::Parse(error) => &error.notes,
+      Diagnostic::Expand(error) => &error.notes,
+    }
+  }
+
+  pub fn message(&self) -> String {
+    match self {
+      Diagnostic::Scan(error) => error.to_string(),
+      Diagnostic::Parse(error) => error.message.clone(),
+      Diagnostic::Expand(error) => error.message.clone(),
+    }
+  }
+}
+
+impl From<ScanError> for Diagnostic {
+  fn from(error: ScanError) -> Self {
+    Diagnostic::Scan(error)
+  }
+}
+
+impl From<ParseError> for Diagnostic {
+  fn from(error: ParseError) -> Self {
+    Diagnostic::Parse(error)
+  }
+}
+
+impl From<ExpandError> for Diagnostic {
+  fn from(error: ExpandError) -> Self {
+    Diagnostic::Expand(error)
+  }
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let start = self.span().start;
+    write!(
+      f,
+      "{} at line {}, column {}",
+      self.message(),
+      start.line,
+      start.column
+    )
+  }
+}
+
+// Scans, parses and expands macros in `text`. Each stage only runs when the
+// one before it succeeded. Quasiquotes left in the program read their
+// bodies from the returned templates.
+#[allow(dead_code)]
+pub fn read_str_parse(
+  text: String,
+  file: FileId,
+) -> Result<(ASTNode, TemplateTable), Vec<Diagnostic>> {
+  let mut expander = Expander::default();
+  let ast = read_str_expand(text, file, &mut expander)?;
+  Ok((ast, expander.templates().clone()))
+}
+
+// Like `read_str_parse`, but expands with `expander`, which keeps the macros
+// and templates read from `text` afterwards.
+pub fn read_str_expand(
+  text: String,
+  file: FileId,
+  expander: &mut Expander,
+) -> Result<ASTNode, Vec<Diagnostic>> {
+  let tokens = read_str_scan_with_file(text, file)
+    .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())?;
+  let mut parser = Parser::with_macros(tokens, expander.macros().clone(), TemplateTable::new());
+  let ast = parser
+    .parse()
+    .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())?;
+  expander.extend(parser.macros(), parser.templates());
+  expander
+    .expand_program(&ast)
+    .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diagnostics_from_both_stages() {
+    let errors = read_str_parse("(print \"oops)".to_string(), FileId(1)).unwrap_err();
+    assert!(matches!(
+      &errors[..],
+      [Diagnostic::Scan(ScanError::UnterminatedString { .. })]
+    ));
+    assert_eq!(errors[0].span().file, FileId(1));
+    assert_eq!(
+      errors[0].to_string(),
+      "Unterminated string at line 1, column 8"
+    );
+
+    let errors = read_str_parse("\n(def 1 2)".to_string(), FileId(1)).unwrap_err();
+    assert!(matches!(&errors[0], Diagnostic::Parse(_)));
+    assert_eq!(errors[0].span().start.line, 2);
+    assert!(errors[0].message().starts_with("Expected symbol"));
+  }
+}
@@ -0,0 +1,256 @@
+use super::diagnostic::Diagnostic;
+use crate::scanner::span::Span;
+use std::fmt::Write;
+
+const RED: &str = "\x1b[1;31m";
+const CYAN: &str = "\x1b[1;36m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// Renders diagnostics against the source they were found in:
+//
+//   error: Expected ')' at the end of list
+//    --> main.tisp:2:10
+//     |
+//   2 |   (+ x 1)
+//     |          ^
+//   note: list opened here
+//   ...
+pub struct Renderer<'a> {
+  file_name: &'a str,
+  lines: Vec<&'a str>,
+  color: bool,
+}
+
+impl<'a> Renderer<'a> {
+  pub fn new(file_name: &'a str, source: &'a str) -> Self {
+    Renderer {
+      file_name,
+      lines: source.lines().collect(),
+      color: false,
+    }
+  }
+
+  pub fn with_color(mut self, color: bool) -> Self {
+    self.color = color;
+    self
+  }
+
+  pub fn render(&self, diagnostic: &Diagnostic) -> String {
+    let mut out = String::new();
+    self.snippet(
+      &mut out,
+      "error",
+      RED,
+      &diagnostic.message(),
+      diagnostic.span(),
+    );
+    for note in diagnostic.notes() {
+      self.snippet(&mut out, "note", CYAN, &note.message, note.span);
+    }
+    out
+  }
+
+  pub fn render_all(&self, diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter().map(|d| self.render(d)).collect()
+  }
+
+  fn snippet(&self, out: &mut String, level: &str, level_color: &str, message: &str, span: Span) {
+    let start = span.start;
+    let gutter = " ".repeat(start.line.to_string().len());
+    writeln!(
+      out,
+      "{}{}",
+      self.paint(level_color, &format!("{}:", level)),
+      self.paint(BOLD, &format!(" {}", message))
+    )
+    .unwrap();
+    writeln!(
+      out,
+      "{}{} {}:{}:{}",
+      gutter,
+      self.paint(BLUE, "-->"),
+      self.file_name,
+      start.line,
+      start.column
+    )
+    .unwrap();
+
+    let line = match start
+      .line
+      .checked_sub(1)
+      .and_then(|i| self.lines.get(i as usize))
+    {
+      Some(line) => *line,
+      None if start.line as usize == self.lines.len() + 1 => "",
+      None => return,
+    };
+    let bar = self.paint(BLUE, "|");
+    writeln!(out, "{} {}", gutter, bar).unwrap();
+    writeln!(
+      out,
+      "{} {} {}",
+      self.paint(BLUE, &start.line.to_string()),
+      bar,
+      line
+    )
+    .unwrap();
+
+    // Keep tabs so the carets line up with the text above them.
+    let column = start.column.max(1) as usize - 1;
+    let padding: String = line
+      .chars()
+      .chain(std::iter::repeat(' '))
+      .take(column)
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect();
+    let width = if span.end.line == start.line {
+      (span.end.column as usize).saturating_sub(start.column as usize)
+    } else {
+      line.chars().count().saturating_sub(column)
+    };
+    let carets = "^".repeat(width.max(1));
+    writeln!(
+      out,
+      "{} {} {}{}",
+      gutter,
+      bar,
+      padding,
+      self.paint(level_color, &carets)
+    )
+    .unwrap();
+  }
+
+  fn paint(&self, color: &str, text: &str) -> String {
+    if self.color {
+      format!("{}{}{}", color, text, RESET)
+    } else {
+      text.to_string()
+    }
+  }
+}
+
+// One JSON array for editor integrations. Positions are the same 1-based
+// lines/columns and byte offsets carried by spans.
+pub fn render_json(file_name: &str, diagnostics: &[Diagnostic]) -> String {
+  let entries: Vec<String> = diagnostics
+    .iter()
+    .map(|diagnostic| {
+      let kind = match diagnostic {
+        Diagnostic::Scan(_) => "scan",
+        Diagnostic::Parse(_) => "parse",
+        Diagnostic::Expand(_) => "expand",
+      };
+      let notes: Vec<String> = diagnostic
+        .notes()
+        .iter()
+        .map(|note| {
+          format!(
+            "{{\"message\":{},\"span\":{}}}",
+            json_string(&note.message),
+            json_span(note.span)
+          )
+        })
+        .collect();
+      format!(
+        "{{\"severity\":\"error\",\"kind\":\"{}\",\"message\":{},\"file\":{},\"span\":{},\"notes\":[{}]}}",
+        kind,
+        json_string(&diagnostic.message()),
+        json_string(file_name),
+        json_span(diagnostic.span()),
+        notes.join(",")
+      )
+    })
+    .collect();
+  format!("[{}]", entries.join(","))
+}
+
+fn json_span(span: Span) -> String {
+  let position = |p: crate::scanner::span::Position| {
+    format!(
+      "{{\"offset\":{},\"line\":{},\"column\":{}}}",
+      p.offset, p.line, p.column
+    )
+  };
+  format!(
+    "{{\"start\":{},\"end\":{}}}",
+    position(span.start),
+    position(span.end)
+  )
+}
+
+fn json_string(s: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::diagnostic::diagnostic::read_str_parse;
+  use crate::scanner::span::FileId;
+
+  fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    read_str_parse(source.to_string(), FileId::default()).unwrap_err()
+  }
+
+  #[test]
+  fn test_render_with_note() {
+    let source = "(def f (fn (x)\n  (+ x 1)";
+    let errors = diagnostics(source);
+    let rendered = Renderer::new("main.tisp", source).render(&errors[0]);
+    assert_eq!(
+      rendered,
+      "\
+error: Expected ')' to close function body
+ --> main.tisp:2:10
+  |
+2 |   (+ x 1)
+  |          ^
+note: function opened here
+ --> main.tisp:1:8
+  |
+1 | (def f (fn (x)
+  |        ^
+"
+    );
+  }
+
+  #[test]
+  fn test_render_underlines_span() {
+    let source = "(print\n\t\"a\\qb\")";
+    let errors = diagnostics(source);
+    let rendered = Renderer::new("x.tisp", source).render(&errors[0]);
+    assert!(rendered.contains("2 | \t\"a\\qb\")\n  | \t  ^^\n"));
+
+    let colored = Renderer::new("x.tisp", source)
+      .with_color(true)
+      .render(&errors[0]);
+    assert!(colored.starts_with("\x1b[1;31merror:\x1b[0m"));
+  }
+
+  #[test]
+  fn test_render_json() {
+    let errors = diagnostics("(\"tab\\q\")");
+    let json = render_json("a \"b\".tisp", &errors);
+    assert_eq!(
+      json,
+      "[{\"severity\":\"error\",\"kind\":\"scan\",\"message\":\"Unknown escape sequence \\\\q\",\
+       \"file\":\"a \\\"b\\\".tisp\",\"span\":{\"start\":{\"offset\":5,\"line\":1,\"column\":6},\
+       \"end\":{\"offset\":7,\"line\":1,\"column\":8}},\"notes\":[]}]"
+    );
+  }
+}
@@ -0,0 +1,507 @@
+use crate::compiler::{
+  bytecode::{self, FILE_EXTENSION},
+  codegen::compile,
+  disassembler::disassemble,
+  program::Program,
+};
+use crate::diagnostic::{
+  diagnostic::{read_str_expand, Diagnostic},
+  renderer::{render_json, Renderer},
+};
+use crate::expander::{expander::Expander, printer::to_source};
+use crate::interpreter::interpreter::Interpreter;
+use crate::parser::{ast::ASTNode, parser::TemplateTable};
+use crate::repl::cli::Repl;
+use crate::scanner::{scanner::Lexer, span::FileId};
+use crate::vm::vm::Vm;
+use std::{
+  env,
+  fs::{self, File},
+  io::{self, BufRead, BufReader, IsTerminal},
+  path::Path,
+};
+
+// Exit codes, following sysexits(3) where one fits.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_RUNTIME_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_DATA_ERROR: i32 = 65; // scan, parse, compile or bytecode errors
+pub const EXIT_NO_INPUT: i32 = 66;
+pub const EXIT_IO_ERROR: i32 = 74;
+
+// A file argument of `-` reads standard input.
+const STDIN_PATH: &str = "-";
+
+const USAGE: &str = "\
+Usage: tisp [options] [command] [args]
+
+Commands:
+  (none)                        start the REPL
+  run <file> [--interp]         run a .tisp source or .tispc bytecode file
+  compile <file> [-o <out>]     compile to bytecode (default: <file>.tispc)
+  check <file>                  read and expand only, reporting every error
+  tokens <file>                 print the token stream
+  ast <file>                    print the syntax tree
+  expand <file> [--trace]       print the program with every macro expanded
+  disasm <file>                 print the bytecode listing of a .tisp or .tispc file
+  help                          show this message
+
+A <file> of - reads the source from standard input.
+
+Options:
+  --json                        report errors as JSON on stdout
+  --color <auto|always|never>   colour error output (default: auto)";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+  Repl,
+  Run {
+    path: String,
+    interpret: bool,
+  },
+  Compile {
+    path: String,
+    output: Option<String>,
+  },
+  Check {
+    path: String,
+  },
+  Tokens {
+    path: String,
+  },
+  Ast {
+    path: String,
+  },
+  Expand {
+    path: String,
+    trace: bool,
+  },
+  Disasm {
+    path: String,
+  },
+  Help,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+  let (command, rest) = match args.split_first() {
+    Some((command, rest)) => (command.as_str(), rest),
+    None => return Ok(Command::Repl),
+  };
+
+  let mut path = None;
+  let mut output = None;
+  let mut interpret = false;
+  let mut trace = false;
+  let mut rest = rest.iter();
+  while let Some(arg) = rest.next() {
+    match arg.as_str() {
+      "-o" | "--output" if command == "compile" => match rest.next() {
+        Some(out) => output = Some(out.clone()),
+        None => return Err(format!("{} requires a file name", arg)),
+      },
+      "--interp" if command == "run" => interpret = true,
+      "--trace" if command == "expand" => trace = true,
+      flag if flag.starts_with('-') && flag.len() > 1 => {
+        return Err(format!("Unknown option '{}' for '{}'", flag, command))
+      }
+      _ if path.is_some() => return Err(format!("Unexpected argument '{}'", arg)),
+      _ => path = Some(arg.clone()),
+    }
+  }
+
+  let path = || {
+    path
+      .clone()
+      .ok_or_else(|| format!("'{}' requires a file", command))
+  };
+  match command {
+    "run" => Ok(Command::Run {
+      path: path()?,
+      interpret,
+    }),
+    "compile" => Ok(Command::Compile {
+      path: path()?,
+      output,
+    }),
+    "check" => Ok(Command::Check { path: path()? }),
+    "tokens" => Ok(Command::Tokens { path: path()? }),
+    "ast" => Ok(Command::Ast { path: path()? }),
+    "expand" => Ok(Command::Expand {
+      path: path()?,
+      trace,
+    }),
+    "disasm" => Ok(Command::Disasm { path: path()? }),
+    "help" | "-h" | "--help" => Ok(Command::Help),
+    _ => Err(format!("Unknown command '{}'", command)),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+  Auto,
+  Always,
+  Never,
+}
+
+// Options accepted by every command, wherever they appear on the line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+  pub json: bool,
+  pub color: ColorChoice,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      json: false,
+      color: ColorChoice::Auto,
+    }
+  }
+}
+
+pub fn take_options(args: &[String]) -> Result<(Options, Vec<String>), String> {
+  let mut options = Options::default();
+  let mut rest = Vec::new();
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    let color = match arg.as_str() {
+      "--json" => {
+        options.json = true;
+        continue;
+      }
+      "--color" => args.next().map(String::as_str).unwrap_or(""),
+      _ => match arg.strip_prefix("--color=") {
+        Some(when) => when,
+        None => {
+          rest.push(arg.clone());
+          continue;
+        }
+      },
+    };
+    options.color = match color {
+      "auto" => ColorChoice::Auto,
+      "always" => ColorChoice::Always,
+      "never" => ColorChoice::Never,
+      _ => return Err("--color expects auto, always or never".to_string()),
+    };
+  }
+  Ok((options, rest))
+}
+
+pub fn run(args: &[String]) -> i32 {
+  let command = take_options(args).and_then(|(options, rest)| Ok((options, parse_args(&rest)?)));
+  let (options, command) = match command {
+    Ok(command) => command,
+    Err(message) => {
+      eprintln!("error: {}\n\n{}", message, USAGE);
+      return EXIT_USAGE;
+    }
+  };
+
+  match (Driver { options }).execute(command) {
+    Ok(()) => EXIT_OK,
+    Err(code) => code,
+  }
+}
+
+struct Driver {
+  options: Options,
+}
+
+impl Driver {
+  fn execute(&self, command: Command) -> Result<(), i32> {
+    match command {
+      Command::Repl => Repl::new()
+        .run()
+        .map_err(|e| report(EXIT_IO_ERROR, &e.to_string())),
+      Command::Help => {
+        println!("{}", USAGE);
+        Ok(())
+      }
+      Command::Run { path, interpret } => self.run_file(&path, interpret),
+      Command::Compile { path, output } => {
+        let output = output.unwrap_or_else(|| {
+          Path::new(&path)
+            .with_extension(FILE_EXTENSION)
+            .to_string_lossy()
+            .into_owned()
+        });
+        self.compile_file(&path).and_then(|program| {
+          bytecode::write_file(&output, &program)
+            .map_err(|e| report(EXIT_IO_ERROR, &format!("{}: {}", output, e)))
+        })
+      }
+      Command::Check { path } => self.parse_file(&path).map(|_| ()),
+      Command::Tokens { path } => self.print_tokens(&path),
+      Command::Ast { path } => self
+        .parse_file(&path)
+        .map(|(ast, _)| println!("{:#?}", ast)),
+      Command::Expand { path, trace } => self.print_expansion(&path, trace),
+      Command::Disasm { path } => self
+        .load_program(&path)
+        .map(|program| print!("{}", disassemble(&program))),
+    }
+  }
+
+  // Tokens are printed as they're scanned, so a large file or a pipe never
+  // has to be held in memory.
+  fn print_tokens(&self, path: &str) -> Result<(), i32> {
+    let reader: Box<dyn BufRead> = if path == STDIN_PATH {
+      Box::new(io::stdin().lock())
+    } else {
+      let file =
+        File::open(path).map_err(|e| report(EXIT_NO_INPUT, &format!("{}: {}", path, e)))?;
+      Box::new(BufReader::new(file))
+    };
+
+    let mut diagnostics = Vec::new();
+    for item in Lexer::new(reader, FileId::default()) {
+      match item {
+        Ok(token) => {
+          let start = token.span.start;
+          println!("{}:{}\t{:?}", start.line, start.column, token.token_type);
+        }
+        Err(error) => diagnostics.push(Diagnostic::from(error)),
+      }
+    }
+    if diagnostics.is_empty() {
+      return Ok(());
+    }
+    // Standard input can't be read again to show the errors in context.
+    if path == STDIN_PATH && !self.options.json {
+      for diagnostic in &diagnostics {
+        eprintln!("error: {}", diagnostic);
+      }
+      return Err(EXIT_DATA_ERROR);
+    }
+    let source = fs::read_to_string(path).unwrap_or_default();
+    Err(self.report_diagnostics(path, &source, &diagnostics))
+  }
+
+  fn parse_file(&self, path: &str) -> Result<(ASTNode, TemplateTable), i32> {
+    let mut expander = expander_for(path);
+    let ast = self.expand_file(path, &mut expander)?;
+    Ok((ast, expander.templates().clone()))
+  }
+
+  fn expand_file(&self, path: &str, expander: &mut Expander) -> Result<ASTNode, i32> {
+    let source = read_source(path)?;
+    read_str_expand(source.clone(), FileId::default(), expander)
+      .map_err(|diagnostics| self.report_diagnostics(path, &source, &diagnostics))
+  }
+
+  // Prints the expanded program as source. The trace lists each step as
+  // comments first: the call, where it was, and what it became.
+  fn print_expansion(&self, path: &str, trace: bool) -> Result<(), i32> {
+    let mut expander = expander_for(path);
+    if trace {
+      expander = expander.with_trace();
+    }
+    let ast = self.expand_file(path, &mut expander)?;
+    let templates = expander.templates();
+    for (i, step) in expander.steps().iter().enumerate() {
+      let start = step.call.span.start;
+      println!(
+        ";; {}. {} at {}:{}\n;;   {}\n;;   => {}",
+        i + 1,
+        step.name,
+        start.line,
+        start.column,
+        to_source(&step.call, templates),
+        to_source(&step.expansion, templates)
+      );
+    }
+    print!("{}", to_source(&ast, templates));
+    Ok(())
+  }
+
+  fn report_diagnostics(&self, path: &str, source: &str, diagnostics: &[Diagnostic]) -> i32 {
+    if self.options.json {
+      println!("{}", render_json(path, diagnostics));
+    } else {
+      let color = match self.options.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+      };
+      let renderer = Renderer::new(path, source).with_color(color);
+      eprint!("{}", renderer.render_all(diagnostics));
+    }
+    EXIT_DATA_ERROR
+  }
+
+  fn compile_file(&self, path: &str) -> Result<Program, i32> {
+    let (ast, _) = self.parse_file(path)?;
+    compile(&ast).map_err(|error| report(EXIT_DATA_ERROR, &format!("{}: {}", path, error)))
+  }
+
+  // Bytecode files are loaded as-is, anything else is compiled from source.
+  fn load_program(&self, path: &str) -> Result<Program, i32> {
+    if Path::new(path)
+      .extension()
+      .is_some_and(|ext| ext == FILE_EXTENSION)
+    {
+      if !Path::new(path).exists() {
+        return Err(report(EXIT_NO_INPUT, &format!("{}: file not found", path)));
+      }
+      bytecode::read_file(path)
+        .map_err(|error| report(EXIT_DATA_ERROR, &format!("{}: {}", path, error)))
+    } else {
+      self.compile_file(path)
+    }
+  }
+
+  fn run_file(&self, path: &str, interpret: bool) -> Result<(), i32> {
+    if interpret {
+      let (ast, templates) = self.parse_file(path)?;
+      return Interpreter::new()
+        .with_templates(templates)
+        .eval_program(&ast)
+        .map(|_| ())
+        .map_err(|error| report(EXIT_RUNTIME_ERROR, &error.to_string()));
+    }
+
+    let program = self.load_program(path)?;
+    Vm::new(program)
+      .run()
+      .map(|_| ())
+      .map_err(|error| report(EXIT_RUNTIME_ERROR, &error.to_string()))
+  }
+}
+
+fn report(code: i32, message: &str) -> i32 {
+  eprintln!("error: {}", message);
+  code
+}
+
+// Imports in standard input are relative to the working directory.
+fn expander_for(path: &str) -> Expander {
+  match Path::new(path).parent() {
+    Some(dir) if path != STDIN_PATH => Expander::default().with_base(dir),
+    _ => Expander::default(),
+  }
+}
+
+fn read_source(path: &str) -> Result<String, i32> {
+  if path == STDIN_PATH {
+    return io::read_to_string(io::stdin()).map_err(|e| report(EXIT_IO_ERROR, &e.to_string()));
+  }
+  fs::read_to_string(path).map_err(|e| report(EXIT_NO_INPUT, &format!("{}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn test_parse_args() {
+    assert_eq!(parse_args(&args(&[])), Ok(Command::Repl));
+    assert_eq!(
+      parse_args(&args(&["run", "main.tisp", "--interp"])),
+      Ok(Command::Run {
+        path: "main.tisp".to_string(),
+        interpret: true
+      })
+    );
+    assert_eq!(
+      parse_args(&args(&["compile", "-o", "out.tispc", "main.tisp"])),
+      Ok(Command::Compile {
+        path: "main.tisp".to_string(),
+        output: Some("out.tispc".to_string())
+      })
+    );
+    assert_eq!(
+      parse_args(&args(&["check", "a.tisp"])),
+      Ok(Command::Check {
+        path: "a.tisp".to_string()
+      })
+    );
+    assert_eq!(
+      parse_args(&args(&["expand", "--trace", "a.tisp"])),
+      Ok(Command::Expand {
+        path: "a.tisp".to_string(),
+        trace: true
+      })
+    );
+  }
+
+  #[test]
+  fn test_parse_args_errors() {
+    assert_eq!(
+      parse_args(&args(&["frobnicate"])),
+      Err("Unknown command 'frobnicate'".to_string())
+    );
+    assert_eq!(
+      parse_args(&args(&["run"])),
+      Err("'run' requires a file".to_string())
+    );
+    assert_eq!(
+      parse_args(&args(&["check", "a.tisp", "-o", "x"])),
+      Err("Unknown option '-o' for 'check'".to_string())
+    );
+    assert_eq!(
+      parse_args(&args(&["ast", "a.tisp", "b.tisp"])),
+      Err("Unexpected argument 'b.tisp'".to_string())
+    );
+  }
+
+  #[test]
+  fn test_exit_codes() {
+    let dir = std::env::temp_dir().join(format!("tisp-driver-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = |name: &str, source: &str| {
+      let path = dir.join(name);
+      fs::write(&path, source).unwrap();
+      path.to_string_lossy().into_owned()
+    };
+
+    let good = file("good.tisp", "(def x (fn (a) (* a 2))) (x 21)");
+    let bad_syntax = file("bad.tisp", "(def (fn");
+    let bad_runtime = file("boom.tisp", "(/ 1 0)");
+    let missing = dir.join("missing.tisp").to_string_lossy().into_owned();
+    let compiled = dir.join("good.tispc").to_string_lossy().into_owned();
+
+    assert_eq!(run(&args(&["check", &good])), EXIT_OK);
+    assert_eq!(run(&args(&["check", &bad_syntax])), EXIT_DATA_ERROR);
+    assert_eq!(run(&args(&["run", &bad_runtime])), EXIT_RUNTIME_ERROR);
+    assert_eq!(run(&args(&["run", &missing])), EXIT_NO_INPUT);
+    assert_eq!(run(&args(&["compile", &good])), EXIT_OK);
+    assert_eq!(run(&args(&["run", &compiled])), EXIT_OK);
+    assert_eq!(run(&args(&["run", "--bogus", &good])), EXIT_USAGE);
+
+    file("lib.tisp", "(macro twice (x) `(list ,x ,x)) (export twice)");
+    let importer = file(
+      "main.tisp",
+      "(import \"lib.tisp\" twice) (println (twice 1))",
+    );
+    let bad_import = file("other.tisp", "(import \"lib.tisp\" thrice)");
+    assert_eq!(run(&args(&["run", &importer])), EXIT_OK);
+    assert!(dir.join("lib.tispm").exists());
+    assert_eq!(run(&args(&["run", "--interp", &importer])), EXIT_OK);
+    assert_eq!(run(&args(&["check", &bad_import])), EXIT_DATA_ERROR);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_take_options() {
+    let (options, rest) =
+      take_options(&args(&["check", "--json", "a.tisp", "--color=never"])).unwrap();
+    assert_eq!(
+      options,
+      Options {
+        json: true,
+        color: ColorChoice::Never
+      }
+    );
+    assert_eq!(rest, args(&["check", "a.tisp"]));
+
+    let (options, _) = take_options(&args(&["--color", "always", "run", "a.tisp"])).unwrap();
+    assert_eq!(options.color, ColorChoice::Always);
+    assert!(take_options(&args(&["--color=sometimes"])).is_err());
+  }
+}
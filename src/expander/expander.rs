@@ -0,0 +1,1062 @@
+use super::{
+  expander_error::{ExpandError, ExpandResult},
+  module::{export_form, import_form, MacroNames, ModuleLoader},
+};
+use crate::interpreter::{environment::Environment, interpreter::Interpreter, value::Value};
+use crate::parser::{
+  ast::{ASTNode, NodeType},
+  parser::{MacroTable, Parser, TemplateTable, SPECIAL_FORMS},
+};
+use crate::scanner::{
+  scanner::identifier_token,
+  span::Span,
+  token::{Token, TokenType},
+};
+use std::{
+  collections::{HashMap, HashSet},
+  io,
+  path::{Path, PathBuf},
+  rc::Rc,
+};
+use uuid::Uuid;
+
+// How many times one form may be rewritten in place, and how deeply
+// expansions may nest inside each other's output, before expansion is
+// assumed not to terminate.
+pub const MAX_EXPANSION_STEPS: usize = 256;
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+// One macro call and the code it was rewritten into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpansionStep {
+  pub name: String,
+  pub call: ASTNode,
+  pub expansion: ASTNode,
+}
+
+// Rewrites macro calls into the code their macros produce. A macro body is
+// evaluated at compile time with its parameters bound to the call's
+// arguments as data, and the value it returns is read back as code, which is
+// expanded again until no macro calls are left.
+pub struct Expander {
+  macros: MacroTable,
+  interpreter: Interpreter<io::Sink>,
+  steps: Option<Vec<ExpansionStep>>,
+  loader: ModuleLoader,
+  // Imports are relative to the directory of the file being expanded.
+  base: PathBuf,
+  // The variables bound around the form being expanded, innermost last.
+  // Each is marked once a hygienic expansion in its scope needs the global
+  // it shadows.
+  locals: Vec<(String, bool)>,
+  // Placeholders for those globals, with the names they stand for.
+  globals: HashMap<String, String>,
+}
+
+impl Default for Expander {
+  fn default() -> Self {
+    Self::new(MacroTable::new(), TemplateTable::new())
+  }
+}
+
+impl Expander {
+  pub fn new(macros: MacroTable, templates: TemplateTable) -> Self {
+    Expander {
+      macros,
+      interpreter: Interpreter::with_output(io::sink()).with_templates(templates),
+      steps: None,
+      loader: ModuleLoader::default(),
+      base: PathBuf::new(),
+      locals: Vec::new(),
+      globals: HashMap::new(),
+    }
+  }
+
+  pub fn with_base(mut self, base: &Path) -> Self {
+    self.base = base.to_path_buf();
+    self
+  }
+
+  // Records every expansion step, in the order they happen.
+  pub fn with_trace(mut self) -> Self {
+    self.steps = Some(Vec::new());
+    self
+  }
+
+  pub fn steps(&self) -> &[ExpansionStep] {
+    self.steps.as_deref().unwrap_or_default()
+  }
+
+  // Adds the macros and templates a parser collected.
+  pub fn extend(&mut self, macros: &MacroTable, templates: &TemplateTable) {
+    self.macros.extend(macros.clone());
+    self.interpreter.templates_mut().extend(templates.clone());
+  }
+
+  pub fn macros(&self) -> &MacroTable {
+    &self.macros
+  }
+
+  // The parser's templates plus any read back from expansions.
+  pub fn templates(&self) -> &TemplateTable {
+    self.interpreter.templates()
+  }
+
+  // Expands each top-level form on its own, so one bad call doesn't hide
+  // the others.
+  pub fn expand_program(&mut self, program: &ASTNode) -> Result<ASTNode, Vec<ExpandError>> {
+    let NodeType::Program(forms) = &program.node_type else {
+      return self.expand(program, 0).map_err(|error| vec![error]);
+    };
+
+    // Imported macros may not take the name of a global the program defines.
+    let globals: HashSet<&str> = forms
+      .iter()
+      .filter_map(|form| match &form.node_type {
+        NodeType::Variable(name, ..) => Some(name.as_str()),
+        _ => None,
+      })
+      .collect();
+    let mut expanded = Vec::new();
+    let mut errors = Vec::new();
+    for form in forms {
+      let result = if let Some(import) = import_form(form) {
+        self.import(import, &globals, form.span)
+      } else if let Some(export) = export_form(form) {
+        export
+          .map(|_| ())
+          .map_err(|message| ExpandError::new(&message, form.span))
+      } else {
+        // A macro may expand to a definition only where one is allowed.
+        self
+          .expand(form, 0)
+          .and_then(|form| match form.misplaced_definition() {
+            Some(span) => Err(ExpandError::new(
+              "def is only allowed at the top level",
+              span,
+            )),
+            None => Ok(form),
+          })
+          .map(|form| expanded.push(form))
+      };
+      if let Err(error) = result {
+        errors.push(error);
+      }
+    }
+    if errors.is_empty() {
+      Ok(ASTNode::new(NodeType::Program(expanded), program.span))
+    } else {
+      Err(errors)
+    }
+  }
+
+  pub fn expand(&mut self, node: &ASTNode, depth: usize) -> ExpandResult<ASTNode> {
+    let (node, steps) = self.expand_head(node.clone(), depth, false)?;
+    if let Some(quoted) = self.expand_inspection(&node)? {
+      return Ok(quoted);
+    }
+
+    let depth = if steps > 0 { depth + 1 } else { depth };
+    let node_type = match node.node_type {
+      NodeType::List(items) => NodeType::List(self.expand_all(&items, depth)?),
+      NodeType::Variable(name, value, doc) => {
+        NodeType::Variable(name, Box::new(self.expand(&value, depth)?), doc)
+      }
+      NodeType::FuncDef(params, body) => {
+        let scope = self.locals.len();
+        let function = self.expand_function(params, &body, depth);
+        return self.leave_scope(scope, function, node.span);
+      }
+      NodeType::If(condition, then, otherwise) => NodeType::If(
+        Box::new(self.expand(&condition, depth)?),
+        Box::new(self.expand(&then, depth)?),
+        match otherwise {
+          Some(otherwise) => Some(Box::new(self.expand(&otherwise, depth)?)),
+          None => None,
+        },
+      ),
+      NodeType::Let(bindings, body) => {
+        let scope = self.locals.len();
+        let binding = self.expand_let(bindings, &body, depth);
+        return self.leave_scope(scope, binding, node.span);
+      }
+      NodeType::Do(body) => NodeType::Do(self.expand_all(&body, depth)?),
+      NodeType::Set(name, value) => NodeType::Set(name, Box::new(self.expand(&value, depth)?)),
+      NodeType::Cond(clauses) => NodeType::Cond(
+        clauses
+          .into_iter()
+          .map(|(test, body)| Ok((self.expand(&test, depth)?, self.expand_all(&body, depth)?)))
+          .collect::<ExpandResult<_>>()?,
+      ),
+      NodeType::And(items) => NodeType::And(self.expand_all(&items, depth)?),
+      NodeType::Or(items) => NodeType::Or(self.expand_all(&items, depth)?),
+      // Quoted data and quasiquote templates are not code yet.
+      other => other,
+    };
+    Ok(ASTNode::new(node_type, node.span))
+  }
+
+  // Each parameter is in scope from its own default on, as each binding of a
+  // `let` is from the next one's value on.
+  fn expand_function(
+    &mut self,
+    params: Vec<ASTNode>,
+    body: &[ASTNode],
+    depth: usize,
+  ) -> ExpandResult<NodeType> {
+    let mut expanded = Vec::new();
+    for param in params {
+      let param = self.expand_param(param, depth)?;
+      let name = match &param.node_type {
+        NodeType::List(pair) => &pair[0].node_type,
+        name => name,
+      };
+      match name {
+        NodeType::Symbol(name) if !name.starts_with('&') => self.locals.push((name.clone(), false)),
+        _ => {}
+      }
+      expanded.push(param);
+    }
+    Ok(NodeType::FuncDef(expanded, self.expand_all(body, depth)?))
+  }
+
+  // Only the default of a `(name default)` parameter is code.
+  fn expand_param(&mut self, param: ASTNode, depth: usize) -> ExpandResult<ASTNode> {
+    match param.node_type {
+      NodeType::List(mut pair) if pair.len() == 2 => {
+        pair[1] = self.expand(&pair[1], depth)?;
+        Ok(ASTNode::new(NodeType::List(pair), param.span))
+      }
+      _ => Ok(param),
+    }
+  }
+
+  fn expand_let(
+    &mut self,
+    bindings: Vec<(String, ASTNode)>,
+    body: &[ASTNode],
+    depth: usize,
+  ) -> ExpandResult<NodeType> {
+    let mut expanded = Vec::new();
+    for (name, value) in bindings {
+      let value = self.expand(&value, depth)?;
+      self.locals.push((name.clone(), false));
+      expanded.push((name, value));
+    }
+    Ok(NodeType::Let(expanded, self.expand_all(body, depth)?))
+  }
+
+  // Leaves the scope of the locals bound since `scope`. Those that shadow a
+  // global a hygienic expansion in their scope refers to are renamed, and
+  // the placeholders for globals no longer shadowed get their names back.
+  fn leave_scope(
+    &mut self,
+    scope: usize,
+    node_type: ExpandResult<NodeType>,
+    span: Span,
+  ) -> ExpandResult<ASTNode> {
+    let left = self.locals.split_off(scope);
+    let node_type = node_type?;
+    let mut binders = HashMap::new();
+    for (name, shadows) in left {
+      if shadows && !binders.contains_key(&name) {
+        let fresh = self.interpreter.gensym(&name);
+        binders.insert(name, fresh);
+      }
+    }
+    if binders.is_empty() {
+      return Ok(ASTNode::new(node_type, span));
+    }
+    let mut renames = HashMap::new();
+    let locals = &self.locals;
+    self.globals.retain(|placeholder, name| {
+      if binders.contains_key(name) && !locals.iter().any(|(local, _)| local == name) {
+        renames.insert(placeholder.clone(), name.clone());
+        false
+      } else {
+        true
+      }
+    });
+
+    // A binder is renamed from where its scope starts, so earlier values
+    // and defaults still see the variable it shadows.
+    let bind = |name: &String, renames: &mut HashMap<String, String>| {
+      if let Some(fresh) = binders.get(name) {
+        renames.insert(name.clone(), fresh.clone());
+      }
+      renames.get(name).unwrap_or(name).clone()
+    };
+    let node_type = match node_type {
+      NodeType::Let(bindings, body) => {
+        let mut renamed = Vec::new();
+        for (name, value) in bindings {
+          let value = self.rename_locals(&value, &renames, 0);
+          renamed.push((bind(&name, &mut renames), value));
+        }
+        NodeType::Let(renamed, self.rename_all(&body, &renames, 0))
+      }
+      NodeType::FuncDef(params, body) => {
+        let mut renamed = Vec::new();
+        for param in params {
+          let param = match param.node_type {
+            NodeType::List(mut pair) if pair.len() == 2 => {
+              pair[1] = self.rename_locals(&pair[1], &renames, 0);
+              if let NodeType::Symbol(name) = &pair[0].node_type {
+                pair[0].node_type = NodeType::Symbol(bind(name, &mut renames));
+              }
+              ASTNode::new(NodeType::List(pair), param.span)
+            }
+            NodeType::Symbol(name) => {
+              ASTNode::new(NodeType::Symbol(bind(&name, &mut renames)), param.span)
+            }
+            _ => param,
+          };
+          renamed.push(param);
+        }
+        NodeType::FuncDef(renamed, self.rename_all(&body, &renames, 0))
+      }
+      node_type => node_type,
+    };
+    Ok(ASTNode::new(node_type, span))
+  }
+
+  // A free symbol of a hygienic expansion names a global or special form.
+  // Where a local shadows the global, a placeholder stands in for it until
+  // the local is renamed out of the way.
+  fn global_reference(&mut self, name: &str) -> String {
+    if SPECIAL_FORMS.contains(&name) || name == "else" || self.macros.contains_key(name) {
+      return name.to_string();
+    }
+    let mut shadowed = false;
+    for (local, shadows) in self.locals.iter_mut() {
+      if local == name {
+        *shadows = true;
+        shadowed = true;
+      }
+    }
+    if !shadowed {
+      return name.to_string();
+    }
+    let placeholder = self.interpreter.gensym(name);
+    self.globals.insert(placeholder.clone(), name.to_string());
+    placeholder
+  }
+
+  // Renames variables in code at `level` quasiquotes deep. Quoted data is
+  // left alone; a template with renamed unquotes is copied.
+  fn rename_locals(
+    &mut self,
+    node: &ASTNode,
+    renames: &HashMap<String, String>,
+    level: usize,
+  ) -> ASTNode {
+    let name = |name: &String| match renames.get(name) {
+      Some(fresh) if level == 0 => fresh.clone(),
+      _ => name.clone(),
+    };
+    let node_type = match &node.node_type {
+      NodeType::Symbol(symbol) => NodeType::Symbol(name(symbol)),
+      NodeType::Quote(_) if level == 0 => return node.clone(),
+      NodeType::Quote(inner) => NodeType::Quote(self.rename_one(inner, renames, level)),
+      NodeType::List(items) => NodeType::List(self.rename_all(items, renames, level)),
+      NodeType::Variable(variable, value, doc) => NodeType::Variable(
+        variable.clone(),
+        self.rename_one(value, renames, level),
+        doc.clone(),
+      ),
+      NodeType::FuncDef(params, body) => NodeType::FuncDef(
+        self.rename_all(params, renames, level),
+        self.rename_all(body, renames, level),
+      ),
+      NodeType::MacroTemplate(uuid) => {
+        let Some(template) = self.interpreter.templates().get(uuid).cloned() else {
+          return node.clone();
+        };
+        let renamed = self.rename_locals(&template, renames, level + 1);
+        if renamed == *template {
+          return node.clone();
+        }
+        let uuid = Uuid::new_v4();
+        self
+          .interpreter
+          .templates_mut()
+          .insert(uuid, Box::new(renamed));
+        NodeType::MacroTemplate(uuid)
+      }
+      NodeType::MacroComma(inner) => {
+        NodeType::MacroComma(self.rename_one(inner, renames, level.saturating_sub(1)))
+      }
+      NodeType::MacroListExpand(inner) => {
+        NodeType::MacroListExpand(self.rename_one(inner, renames, level.saturating_sub(1)))
+      }
+      NodeType::If(condition, then, otherwise) => NodeType::If(
+        self.rename_one(condition, renames, level),
+        self.rename_one(then, renames, level),
+        otherwise
+          .as_ref()
+          .map(|otherwise| self.rename_one(otherwise, renames, level)),
+      ),
+      NodeType::Let(bindings, body) => NodeType::Let(
+        bindings
+          .iter()
+          .map(|(binding, value)| (name(binding), self.rename_locals(value, renames, level)))
+          .collect(),
+        self.rename_all(body, renames, level),
+      ),
+      NodeType::Do(body) => NodeType::Do(self.rename_all(body, renames, level)),
+      NodeType::Set(variable, value) => {
+        NodeType::Set(name(variable), self.rename_one(value, renames, level))
+      }
+      NodeType::Cond(clauses) => NodeType::Cond(
+        clauses
+          .iter()
+          .map(|(test, body)| {
+            (
+              self.rename_locals(test, renames, level),
+              self.rename_all(body, renames, level),
+            )
+          })
+          .collect(),
+      ),
+      NodeType::And(items) => NodeType::And(self.rename_all(items, renames, level)),
+      NodeType::Or(items) => NodeType::Or(self.rename_all(items, renames, level)),
+      other => other.clone(),
+    };
+    ASTNode::new(node_type, node.span)
+  }
+
+  fn rename_one(
+    &mut self,
+    node: &ASTNode,
+    renames: &HashMap<String, String>,
+    level: usize,
+  ) -> Box<ASTNode> {
+    Box::new(self.rename_locals(node, renames, level))
+  }
+
+  fn rename_all(
+    &mut self,
+    nodes: &[ASTNode],
+    renames: &HashMap<String, String>,
+    level: usize,
+  ) -> Vec<ASTNode> {
+    nodes
+      .iter()
+      .map(|node| self.rename_locals(node, renames, level))
+      .collect()
+  }
+
+  // Imports and exports are handled here, before any code runs, so they
+  // leave nothing in the expanded program.
+  fn import(
+    &mut self,
+    import: Result<(String, MacroNames), String>,
+    globals: &HashSet<&str>,
+    span: Span,
+  ) -> ExpandResult<()> {
+    let (file, names) = import.map_err(|message| ExpandError::new(&message, span))?;
+    let module = self
+      .loader
+      .import(&self.base.join(&file))
+      .map_err(|message| {
+        ExpandError::new(&format!("Cannot import '{}': {}", file, message), span)
+      })?;
+    let macros = module.select(&names).map_err(|(name, node)| {
+      ExpandError::new(
+        &format!("'{}' does not export macro '{}'", file, name),
+        node.span,
+      )
+    })?;
+    // Importing the same macro again is harmless.
+    for (name, definition) in &macros {
+      let redefined = self
+        .macros
+        .get(name)
+        .is_some_and(|defined| defined != definition);
+      if redefined || globals.contains(name.as_str()) {
+        return Err(ExpandError::new(
+          &format!(
+            "Macro '{}' imported from '{}' is already defined",
+            name, file
+          ),
+          span,
+        ));
+      }
+    }
+    self.extend(&module.helpers, &module.templates);
+    self.extend(&macros, &module.templates);
+    Ok(())
+  }
+
+  // Expands `node` for as long as it's a macro call, or only once. Returns
+  // the result and the number of steps taken.
+  fn expand_head(
+    &mut self,
+    mut node: ASTNode,
+    depth: usize,
+    once: bool,
+  ) -> ExpandResult<(ASTNode, usize)> {
+    let mut steps = 0;
+    while let Some(name) = self.macro_call(&node) {
+      if steps == MAX_EXPANSION_STEPS || depth == MAX_EXPANSION_DEPTH {
+        return Err(ExpandError::new(
+          &format!("Expansion of macro '{}' does not terminate", name),
+          node.span,
+        ));
+      }
+      node = self.expand_call(&name, &node)?;
+      steps += 1;
+      if once {
+        break;
+      }
+    }
+    Ok((node, steps))
+  }
+
+  // `(macroexpand 'form)` becomes the quoted expansion of `form`, taken until
+  // its head is no longer a macro; `(macroexpand-1 'form)` takes one step.
+  // Subforms are left alone in both.
+  fn expand_inspection(&mut self, node: &ASTNode) -> ExpandResult<Option<ASTNode>> {
+    let NodeType::List(items) = &node.node_type else {
+      return Ok(None);
+    };
+    let name = match items.first().map(|head| &head.node_type) {
+      Some(NodeType::Symbol(name)) if name == "macroexpand" || name == "macroexpand-1" => name,
+      _ => return Ok(None),
+    };
+    let [ASTNode {
+      node_type: NodeType::Quote(form),
+      ..
+    }] = &items[1..]
+    else {
+      return Err(ExpandError::new(
+        &format!("{} expects one quoted form", name),
+        node.span,
+      ));
+    };
+
+    // The form is data here, not code in the scope of the call's locals.
+    let locals = std::mem::take(&mut self.locals);
+    let form = self.expand_head((**form).clone(), 0, name == "macroexpand-1");
+    self.locals = locals;
+    let (form, _) = form?;
+    Ok(Some(ASTNode::new(
+      NodeType::Quote(Box::new(form)),
+      node.span,
+    )))
+  }
+
+  fn expand_all(&mut self, nodes: &[ASTNode], depth: usize) -> ExpandResult<Vec<ASTNode>> {
+    nodes.iter().map(|node| self.expand(node, depth)).collect()
+  }
+
+  fn macro_call(&self, node: &ASTNode) -> Option<String> {
+    match &node.node_type {
+      NodeType::List(items) => match items.first().map(|head| &head.node_type) {
+        Some(NodeType::Symbol(name)) if self.macros.contains_key(name) => Some(name.clone()),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+
+  fn expand_call(&mut self, name: &str, call: &ASTNode) -> ExpandResult<ASTNode> {
+    let NodeType::List(items) = &call.node_type else {
+      unreachable!("macro calls are lists");
+    };
+    let definition = self.macros[name].clone();
+    let env = Environment::extend(self.interpreter.globals());
+    self
+      .bind_params(name, &definition.params, &items[1..], &env)
+      .map_err(|message| ExpandError::new(&message, call.span))?;
+
+    if definition.hygienic {
+      self.interpreter.rename_template_symbols();
+    }
+    let result = definition
+      .body
+      .iter()
+      .try_fold(Value::Nil, |_, node| self.interpreter.eval(node, &env));
+    let renames = self.interpreter.take_renames();
+    let value = result.map_err(|error| {
+      ExpandError::new(
+        &format!("Error expanding macro '{}': {}", name, error),
+        call.span,
+      )
+    })?;
+    let value = if definition.hygienic {
+      self.restore_free_symbols(&value, &renames)
+    } else {
+      value
+    };
+    let expansion = self.read_back(name, &value, call.span)?;
+    if let Some(steps) = &mut self.steps {
+      steps.push(ExpansionStep {
+        name: name.to_string(),
+        call: call.clone(),
+        expansion: expansion.clone(),
+      });
+    }
+    Ok(expansion)
+  }
+
+  // Binds each parameter to its argument as data. A `&rest` parameter takes
+  // the remaining arguments as a list.
+  fn bind_params(
+    &mut self,
+    name: &str,
+    params: &[ASTNode],
+    args: &[ASTNode],
+    env: &Rc<Environment>,
+  ) -> Result<(), String> {
+    let names: Vec<&str> = params
+      .iter()
+      .filter_map(|param| match &param.node_type {
+        NodeType::Symbol(name) => Some(name.as_str()),
+        _ => None,
+      })
+      .collect();
+    let (required, rest) = match names.iter().position(|&param| param == "&rest") {
+      Some(i) if i + 2 == names.len() => (&names[..i], Some(names[i + 1])),
+      Some(_) => {
+        return Err(format!(
+          "Macro '{}' must have exactly one parameter after '&rest'",
+          name
+        ))
+      }
+      None => (&names[..], None),
+    };
+
+    if args.len() < required.len() || (rest.is_none() && args.len() > required.len()) {
+      return Err(format!(
+        "Macro '{}' expects {}{} argument{}, got {}",
+        name,
+        if rest.is_some() { "at least " } else { "" },
+        required.len(),
+        if required.len() == 1 { "" } else { "s" },
+        args.len()
+      ));
+    }
+
+    let quote_error = |error| format!("Error expanding macro '{}': {}", name, error);
+    for (param, arg) in required.iter().zip(args) {
+      let value = self.interpreter.quote(arg, env, 0).map_err(quote_error)?;
+      env.define(param, value);
+    }
+    if let Some(rest) = rest {
+      let values = args[required.len()..]
+        .iter()
+        .map(|arg| self.interpreter.quote(arg, env, 0))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(quote_error)?;
+      env.define(rest, Value::List(Rc::new(values)));
+    }
+    Ok(())
+  }
+
+  // A hygienic macro's template symbols were all renamed while its body ran.
+  // Those a `fn` or `let` of the expansion binds keep their fresh names in
+  // that binder's scope, so they can't capture, or be captured by, code
+  // passed in from the call site. The rest get their names back.
+  fn restore_free_symbols(&mut self, value: &Value, renames: &HashMap<String, String>) -> Value {
+    let originals: HashMap<&str, &str> = renames
+      .iter()
+      .map(|(name, fresh)| (fresh.as_str(), name.as_str()))
+      .collect();
+    self.restore(value, &originals, &mut Vec::new(), true)
+  }
+
+  // `bound` holds the fresh names in scope; `code` is false in quoted data.
+  fn restore(
+    &mut self,
+    value: &Value,
+    originals: &HashMap<&str, &str>,
+    bound: &mut Vec<String>,
+    code: bool,
+  ) -> Value {
+    let items = match value {
+      Value::Symbol(name) => {
+        return match originals.get(name.as_str()) {
+          Some(_) if bound.contains(name) => value.clone(),
+          Some(original) if code => Value::Symbol(self.global_reference(original)),
+          Some(original) => Value::Symbol(original.to_string()),
+          None => value.clone(),
+        }
+      }
+      Value::List(items) => items,
+      _ => return value.clone(),
+    };
+    let head = match items.first() {
+      Some(Value::Symbol(head)) => originals.get(head.as_str()).copied().unwrap_or(head),
+      _ => "",
+    };
+    let scope = bound.len();
+    let mut restored = Vec::new();
+    match (head, items.as_slice()) {
+      ("quote" | "quasiquote", [_, _]) => {
+        for item in items.iter() {
+          restored.push(self.restore(item, originals, bound, false));
+        }
+      }
+      // `(fn (a (b default)) ...)` and `(let ((name value) ...) ...)`: each
+      // binder is in scope from the next default or value on.
+      ("fn" | "let", [keyword, Value::List(binders), body @ ..]) if code => {
+        restored.push(self.restore(keyword, originals, bound, code));
+        let mut params = Vec::new();
+        for binder in binders.iter() {
+          let name = match binder {
+            Value::List(pair) if pair.len() == 2 => {
+              let value = self.restore(&pair[1], originals, bound, code);
+              bind(&pair[0], originals, bound);
+              let name = self.restore(&pair[0], originals, bound, code);
+              params.push(Value::List(Rc::new(vec![name, value])));
+              continue;
+            }
+            name => name,
+          };
+          bind(name, originals, bound);
+          params.push(self.restore(name, originals, bound, code));
+        }
+        restored.push(Value::List(Rc::new(params)));
+        for item in body {
+          restored.push(self.restore(item, originals, bound, code));
+        }
+      }
+      _ => {
+        for item in items.iter() {
+          restored.push(self.restore(item, originals, bound, code));
+        }
+      }
+    }
+    bound.truncate(scope);
+    Value::List(Rc::new(restored))
+  }
+
+  // Reads a macro's result back as code, as if it had been written at the
+  // call site.
+  fn read_back(&mut self, name: &str, value: &Value, span: Span) -> ExpandResult<ASTNode> {
+    let mut tokens = Vec::new();
+    value_tokens(value, span, &mut tokens).map_err(|what| {
+      ExpandError::new(
+        &format!("Macro '{}' produced {}, which is not code", name, what),
+        span,
+      )
+    })?;
+
+    let mut parser = Parser::with_macros(tokens, self.macros.clone(), TemplateTable::new());
+    let invalid = |message: &str| {
+      ExpandError::new(
+        &format!("Macro '{}' expanded to invalid code: {}", name, message),
+        span,
+      )
+    };
+    let program = parser
+      .parse()
+      .map_err(|errors| invalid(&errors[0].message))?;
+    self
+      .interpreter
+      .templates_mut()
+      .extend(parser.templates().clone());
+    match program.node_type {
+      NodeType::Program(mut forms) if forms.len() == 1 => Ok(forms.pop().unwrap()),
+      _ => Err(invalid("expected a single form")),
+    }
+  }
+}
+
+// `&rest` and friends mark parameters rather than name them.
+fn bind(binder: &Value, originals: &HashMap<&str, &str>, bound: &mut Vec<String>) {
+  if let Value::Symbol(name) = binder {
+    if originals
+      .get(name.as_str())
+      .is_some_and(|original| !original.starts_with('&'))
+    {
+      bound.push(name.clone());
+    }
+  }
+}
+
+// The tokens that read back as `value`, all placed at `span`.
+fn value_tokens(value: &Value, span: Span, tokens: &mut Vec<Token>) -> Result<(), &'static str> {
+  let token_type = match value {
+    Value::Nil => TokenType::Nil,
+    Value::Int(n) => TokenType::Int32(*n),
+    Value::Float(n) => TokenType::Float32(*n),
+    Value::Bool(b) => TokenType::Bool(*b),
+    Value::Str(s) => TokenType::String(s.clone()),
+    Value::Character(c) => TokenType::Character(*c),
+    Value::Keyword(k) => TokenType::Keyword(k.clone()),
+    Value::Symbol(name) => identifier_token(name.clone()),
+    Value::List(items) => {
+      let prefix = match items.as_slice() {
+        [Value::Symbol(tag), _] => match tag.as_str() {
+          "quote" => Some(TokenType::Quote),
+          "quasiquote" => Some(TokenType::ReaderMacro("`".to_string())),
+          "unquote" => Some(TokenType::ReaderMacro(",".to_string())),
+          "unquote-splicing" => Some(TokenType::ReaderMacro(",@".to_string())),
+          _ => None,
+        },
+        _ => None,
+      };
+      if let Some(prefix) = prefix {
+        tokens.push(Token {
+          token_type: prefix,
+          span,
+        });
+        return value_tokens(&items[1], span, tokens);
+      }
+
+      tokens.push(Token {
+        token_type: TokenType::LeftParen,
+        span,
+      });
+      for item in items.iter() {
+        value_tokens(item, span, tokens)?;
+      }
+      TokenType::RightParen
+    }
+    Value::Function(_) | Value::Builtin(_) => return Err("a function"),
+  };
+  tokens.push(Token { token_type, span });
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::diagnostic::diagnostic::{read_str_parse, Diagnostic};
+  use crate::expander::printer::to_source;
+  use crate::scanner::scanner::read_str_scan;
+  use crate::scanner::span::FileId;
+
+  fn expand(code: &str) -> Result<(ASTNode, TemplateTable), Vec<Diagnostic>> {
+    read_str_parse(code.to_string(), FileId::default())
+  }
+
+  fn run(program: &ASTNode, templates: TemplateTable) -> String {
+    Interpreter::with_output(Vec::new())
+      .with_templates(templates)
+      .eval_program(program)
+      .unwrap()
+      .to_string()
+  }
+
+  fn forms(program: &ASTNode) -> &[ASTNode] {
+    match &program.node_type {
+      NodeType::Program(forms) => forms,
+      other => panic!("expected a program, got {:?}", other),
+    }
+  }
+
+  fn symbol(name: &str) -> ASTNode {
+    NodeType::Symbol(name.to_string()).into()
+  }
+
+  #[test]
+  fn test_expand_with_rest_params() {
+    let (program, templates) = expand(
+      "(macro unless (condition &rest body)\n  `(if ,condition nil (list ,@body)))\n(unless (> 1 2) 1 'two)",
+    )
+    .unwrap();
+    let call = &forms(&program)[1];
+    assert_eq!(
+      *call,
+      NodeType::If(
+        Box::new(
+          NodeType::List(vec![
+            symbol(">"),
+            NodeType::Int32(1).into(),
+            NodeType::Int32(2).into()
+          ])
+          .into()
+        ),
+        Box::new(NodeType::Nil.into()),
+        Some(Box::new(
+          NodeType::List(vec![
+            symbol("list"),
+            NodeType::Int32(1).into(),
+            NodeType::Quote(Box::new(symbol("two"))).into(),
+          ])
+          .into()
+        )),
+      )
+      .into()
+    );
+    // Expanded code takes the place of the call it came from.
+    assert_eq!(call.span.start.line, 3);
+
+    assert_eq!(run(&program, templates), "(1 two)");
+  }
+
+  #[test]
+  fn test_expand_param_defaults() {
+    let (program, templates) = expand(
+      "(macro two () 2)
+       (defn f (a &optional (b (two)) &key (c (list (two) a))) (list a b c))
+       (f 1)",
+    )
+    .unwrap();
+    assert_eq!(run(&program, templates), "(1 2 (2 1))");
+  }
+
+  #[test]
+  fn test_expand_to_fixpoint() {
+    let (program, templates) = expand(
+      "(macro twice (x) `(list ,x ,x))
+       (macro quad (x) `(twice (twice ,x)))
+       (macro pick (flag a b) (if flag a b))
+       (def n 3)
+       (pick #f (oops) (quad n))",
+    )
+    .unwrap();
+    assert_eq!(run(&program, templates), "((3 3) (3 3))");
+
+    // Nested quasiquotes are rebuilt, with `,,x` filled in by the outer one.
+    let (program, templates) = expand("(macro make (x) ``(a ,,x)) (make (+ 1 2))").unwrap();
+    assert!(matches!(
+      forms(&program)[1].node_type,
+      NodeType::MacroTemplate(_)
+    ));
+    assert_eq!(run(&program, templates), "(a 3)");
+  }
+
+  #[test]
+  fn test_hygiene() {
+    let code = "(macro adder (n) `(def captured (fn (x) (+ x ,n))))
+                (macro clean-adder :hygienic (n) `(def kept (fn (x) (+ x ,n))))
+                (def x 10)
+                (adder x)
+                (clean-adder x)
+                (list (captured 1) (kept 1))";
+    let (program, templates) = expand(code).unwrap();
+    assert_eq!(run(&program, templates), "(2 11)");
+
+    // Only the binding is renamed; `kept`, `fn` and `+` keep their meaning.
+    let NodeType::Variable(name, function, _) = &forms(&program)[4].node_type else {
+      panic!("expected a definition");
+    };
+    assert_eq!(name, "kept");
+    let NodeType::FuncDef(params, body) = &function.node_type else {
+      panic!("expected a function");
+    };
+    let NodeType::Symbol(param) = &params[0].node_type else {
+      panic!("expected a parameter");
+    };
+    assert!(param.starts_with("x#"));
+    assert_eq!(
+      body[0],
+      NodeType::List(vec![symbol("+"), symbol(param), symbol("x")]).into()
+    );
+
+    // Each binder gets its own scope: `x` outside the `fn` is the global.
+    let code = "(def x 5)
+                (macro m :hygienic () `(list x ((fn (x) x) 1)))
+                (m)";
+    let (program, templates) = expand(code).unwrap();
+    assert_eq!(run(&program, templates), "(5 1)");
+
+    // Free symbols mean the globals where the macro was defined, even under
+    // a call-site local of the same name, which is renamed instead.
+    let code = "(macro m :hygienic (v) `(list ,v))
+                (let ((list (fn (a) 99))) (m 1))";
+    let (program, templates) = expand(code).unwrap();
+    assert_eq!(run(&program, templates), "(1)");
+    let code = "(macro m :hygienic (v) `(list ,v))
+                (let ((list (fn (a) (list a 'list)))) (list (m 1)))";
+    let (program, templates) = expand(code).unwrap();
+    assert_eq!(run(&program, templates), "((1) list)");
+
+    // Macro bodies can also make fresh names themselves.
+    let (program, templates) =
+      expand("(macro fresh () `',(gensym 'tmp)) (list (fresh) (fresh))").unwrap();
+    let value = run(&program, templates);
+    assert!(value.starts_with("(tmp#"));
+    let names: Vec<&str> = value[1..value.len() - 1].split(' ').collect();
+    assert_ne!(names[0], names[1]);
+  }
+
+  #[test]
+  fn test_macroexpand_and_trace() {
+    let code = "(macro twice (x) `(list ,x ,x))
+                (macro quad (x) `(twice (twice ,x)))
+                (list (macroexpand-1 '(quad 1)) (macroexpand '(quad 1)) (macroexpand '(+ 1 2)))";
+    let (program, templates) = expand(code).unwrap();
+    assert_eq!(
+      run(&program, templates),
+      "((twice (twice 1)) (list (twice 1) (twice 1)) (+ 1 2))"
+    );
+    let written_out = "(macro twice (x) `(list ,x ,x))
+                       (list (macroexpand-1 (quote (twice 1))) (macroexpand (quote (+ 1 2))))";
+    let (program, templates) = expand(written_out).unwrap();
+    assert_eq!(run(&program, templates), "((list 1 1) (+ 1 2))");
+
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    let mut parser = Parser::new(tokens);
+    let program = parser.parse().unwrap();
+    let expander = Expander::new(parser.macros().clone(), parser.templates().clone());
+    let mut expander = expander.with_trace();
+    expander
+      .expand(&NodeType::List(vec![symbol("quad"), symbol("y")]).into(), 0)
+      .unwrap();
+    let steps: Vec<_> = expander
+      .steps()
+      .iter()
+      .map(|step| {
+        (
+          step.name.as_str(),
+          to_source(&step.expansion, expander.templates()),
+        )
+      })
+      .collect();
+    assert_eq!(
+      steps,
+      [
+        ("quad", "(twice (twice y))".to_string()),
+        ("twice", "(list (twice y) (twice y))".to_string()),
+        ("twice", "(list y y)".to_string()),
+        ("twice", "(list y y)".to_string()),
+      ]
+    );
+    assert!(expander.expand_program(&program).is_ok());
+
+    let errors = expand("(macroexpand (quad 1))").unwrap_err();
+    assert_eq!(errors[0].message(), "macroexpand expects one quoted form");
+  }
+
+  #[test]
+  fn test_expansion_errors() {
+    let code = "(macro one (x) x)\n(macro loop () `(loop))\n(macro grow () `(list (grow)))\n\
+                (macro bad (x) (+ x 1))\n(macro sneaky () +)\n(macro define (n) `(def ,n 1))\n\
+                (list (one) (one 1 2))\n(loop)\n(grow)\n(bad y)\n(sneaky)\n(define x)\n(list (define y))";
+    let errors = expand(code).unwrap_err();
+    let found: Vec<_> = errors
+      .iter()
+      .map(|error| (error.message(), error.span().start.line))
+      .collect();
+    assert_eq!(
+      found,
+      [
+        ("Macro 'one' expects 1 argument, got 0".to_string(), 7),
+        (
+          "Expansion of macro 'loop' does not terminate".to_string(),
+          8
+        ),
+        (
+          "Expansion of macro 'grow' does not terminate".to_string(),
+          9
+        ),
+        (
+          "Error expanding macro 'bad': Type error: + cannot operate on symbol and int".to_string(),
+          10
+        ),
+        (
+          "Macro 'sneaky' produced a function, which is not code".to_string(),
+          11
+        ),
+        ("def is only allowed at the top level".to_string(), 13),
+      ]
+    );
+    assert_eq!(errors[0].span().start.column, 7);
+    assert!(matches!(errors[0], Diagnostic::Expand(_)));
+  }
+}
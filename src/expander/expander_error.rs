@@ -0,0 +1,29 @@
+use crate::parser::parser_error::Note;
+use crate::scanner::span::Span;
+use std::fmt;
+
+// A macro call that couldn't be expanded, reported at the call site.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandError {
+  pub message: String,
+  pub span: Span,
+  pub notes: Vec<Note>,
+}
+
+impl ExpandError {
+  pub fn new(message: &str, span: Span) -> Self {
+    ExpandError {
+      message: message.to_string(),
+      span,
+      notes: Vec::new(),
+    }
+  }
+}
+
+impl fmt::Display for ExpandError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+pub type ExpandResult<T> = Result<T, ExpandError>;
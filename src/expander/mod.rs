@@ -0,0 +1,5 @@
+#[allow(clippy::module_inception)]
+pub mod expander;
+pub mod expander_error;
+pub mod module;
+pub mod printer;
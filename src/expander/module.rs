@@ -0,0 +1,462 @@
+use super::printer::macro_source;
+use crate::diagnostic::diagnostic::Diagnostic;
+use crate::parser::{
+  ast::{ASTNode, NodeType},
+  parser::{MacroDefinition, MacroTable, Parser, TemplateTable},
+};
+use crate::scanner::{scanner::read_str_scan_with_file, span::FileId};
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  fs,
+  path::{Path, PathBuf},
+  rc::Rc,
+  time::UNIX_EPOCH,
+};
+
+// Exported macros are cached next to their source as `lib.tispm`.
+pub const CACHE_EXTENSION: &str = "tispm";
+const CACHE_HEADER: &str = ";; tisp macro cache 2";
+
+// The macros a source file exports, and the templates their bodies use.
+#[derive(Debug, Clone, Default)]
+pub struct Module {
+  pub macros: MacroTable,
+  // The file's other macros, which exported ones may expand into. They come
+  // along with every import but can't be imported by name.
+  pub helpers: MacroTable,
+  pub templates: TemplateTable,
+  // Every file the exports were read from, for checking the cache.
+  sources: Vec<Stamp>,
+}
+
+impl Module {
+  // The named macros, or all of them when `names` is empty.
+  // Fails with the first name that isn't exported.
+  pub fn select<'a>(
+    &self,
+    names: &'a [(String, ASTNode)],
+  ) -> Result<MacroTable, &'a (String, ASTNode)> {
+    if names.is_empty() {
+      return Ok(self.macros.clone());
+    }
+    names
+      .iter()
+      .map(|named| match self.macros.get(&named.0) {
+        Some(definition) => Ok((named.0.clone(), definition.clone())),
+        None => Err(named),
+      })
+      .collect()
+  }
+
+  // The module with each helper renamed to `qualifier#name`, which no
+  // program can spell, and every use of it in the module's macros and
+  // templates renamed to match. Importers then can't call the helpers, and
+  // the helpers can't be replaced by the importer's own definitions.
+  fn with_private_helpers(&self, qualifier: &str) -> Module {
+    let renames: HashMap<String, String> = self
+      .helpers
+      .keys()
+      .map(|name| (name.clone(), format!("{}#{}", qualifier, name)))
+      .collect();
+    let rename = |definition: &MacroDefinition| MacroDefinition {
+      params: definition
+        .params
+        .iter()
+        .map(|param| param.rename_symbols(&renames))
+        .collect(),
+      body: definition
+        .body
+        .iter()
+        .map(|node| node.rename_symbols(&renames))
+        .collect(),
+      hygienic: definition.hygienic,
+    };
+    Module {
+      macros: self
+        .macros
+        .iter()
+        .map(|(name, definition)| (name.clone(), rename(definition)))
+        .collect(),
+      helpers: self
+        .helpers
+        .iter()
+        .map(|(name, definition)| (renames[name].clone(), rename(definition)))
+        .collect(),
+      templates: self
+        .templates
+        .iter()
+        .map(|(uuid, template)| (*uuid, Box::new(template.rename_symbols(&renames))))
+        .collect(),
+      sources: self.sources.clone(),
+    }
+  }
+}
+
+// A source file's size and modification time when its module was read.
+#[derive(Debug, Clone, PartialEq)]
+struct Stamp {
+  path: PathBuf,
+  len: u64,
+  modified: u128,
+}
+
+impl Stamp {
+  fn of(path: &Path) -> Result<Self, String> {
+    let metadata = fs::metadata(path).map_err(|e| e.to_string())?;
+    let modified = metadata
+      .modified()
+      .ok()
+      .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+      .map_or(0, |time| time.as_nanos());
+    Ok(Stamp {
+      path: path.to_path_buf(),
+      len: metadata.len(),
+      modified,
+    })
+  }
+}
+
+// Macro names written in `import` and `export`, with where they were written.
+pub type MacroNames = Vec<(String, ASTNode)>;
+
+// A top-level `(import "path" name...)`: the path and the named macros.
+pub fn import_form(node: &ASTNode) -> Option<Result<(String, MacroNames), String>> {
+  let items = top_level_form(node, "import")?;
+  let [path, names @ ..] = items else {
+    return Some(Err("import expects a file name".to_string()));
+  };
+  let NodeType::StringLiteral(path) = &path.node_type else {
+    return Some(Err("import expects a file name".to_string()));
+  };
+  Some(symbols(names).map(|names| (path.clone(), names)))
+}
+
+// A top-level `(export name...)`: the macros a file offers to importers.
+pub fn export_form(node: &ASTNode) -> Option<Result<MacroNames, String>> {
+  top_level_form(node, "export").map(symbols)
+}
+
+fn top_level_form<'a>(node: &'a ASTNode, keyword: &str) -> Option<&'a [ASTNode]> {
+  match &node.node_type {
+    NodeType::List(items) => match items.split_first() {
+      Some((head, rest)) if head.node_type == NodeType::Symbol(keyword.to_string()) => Some(rest),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+fn symbols(nodes: &[ASTNode]) -> Result<MacroNames, String> {
+  nodes
+    .iter()
+    .map(|node| match &node.node_type {
+      NodeType::Symbol(name) => Ok((name.clone(), node.clone())),
+      _ => Err("Expected a macro name".to_string()),
+    })
+    .collect()
+}
+
+// Loads modules for `import`, each at most once. A module is read from its
+// cache while the cache is newer than every source it came from; otherwise
+// the source is parsed and the cache rewritten.
+#[derive(Debug, Default)]
+pub struct ModuleLoader {
+  loaded: HashMap<PathBuf, Rc<Module>>,
+  loading: Vec<PathBuf>,
+}
+
+impl ModuleLoader {
+  pub fn load(&mut self, path: &Path) -> Result<Rc<Module>, String> {
+    let path = fs::canonicalize(path).map_err(|e| e.to_string())?;
+    if let Some(module) = self.loaded.get(&path) {
+      return Ok(Rc::clone(module));
+    }
+    if self.loading.contains(&path) {
+      return Err("the file imports itself".to_string());
+    }
+
+    let cache = path.with_extension(CACHE_EXTENSION);
+    let module = match read_cache(&cache) {
+      Some(module) => module,
+      None => {
+        self.loading.push(path.clone());
+        let module = self.read_source(&path);
+        self.loading.pop();
+        let module = module?;
+        // A cache that can't be written only costs a re-parse next time.
+        let _ = fs::write(&cache, write_cache(&module));
+        module
+      }
+    };
+    let module = Rc::new(module);
+    self.loaded.insert(path, Rc::clone(&module));
+    Ok(module)
+  }
+
+  // A module for a program to import. Its helpers are renamed after the
+  // module's file, so modules with helpers of the same name don't clash.
+  pub fn import(&mut self, path: &Path) -> Result<Module, String> {
+    let module = self.load(path)?;
+    let path = fs::canonicalize(path).map_err(|e| e.to_string())?;
+    Ok(module.with_private_helpers(&path.display().to_string()))
+  }
+
+  fn read_source(&mut self, path: &Path) -> Result<Module, String> {
+    let stamp = Stamp::of(path)?;
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let first_error = |diagnostic: Diagnostic| format!("{}: {}", path.display(), diagnostic);
+    let tokens = read_str_scan_with_file(source, FileId::default())
+      .map_err(|errors| first_error(errors[0].clone().into()))?;
+    let mut parser = Parser::new(tokens);
+    let program = parser
+      .parse()
+      .map_err(|errors| first_error(errors[0].clone().into()))?;
+
+    let mut macros = parser.macros().clone();
+    let mut helpers = MacroTable::new();
+    let mut templates = parser.templates().clone();
+    let mut sources = vec![stamp];
+    let mut exports = Vec::new();
+    let NodeType::Program(forms) = &program.node_type else {
+      unreachable!("the parser returns a program");
+    };
+    let in_file = |message: String| format!("{}: {}", path.display(), message);
+    for form in forms {
+      if let Some(import) = import_form(form) {
+        let (file, names) = import.map_err(in_file)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let module = self
+          .load(&base.join(&file))
+          .map_err(|message| in_file(format!("cannot import '{}': {}", file, message)))?;
+        let selected = module
+          .select(&names)
+          .map_err(|(name, _)| in_file(format!("'{}' does not export macro '{}'", file, name)))?;
+        helpers.extend(module.helpers.clone());
+        helpers.extend(module.macros.clone());
+        macros.extend(selected);
+        templates.extend(module.templates.clone());
+        sources.extend(module.sources.iter().cloned());
+      } else if let Some(export) = export_form(form) {
+        exports.extend(export.map_err(in_file)?);
+      }
+    }
+
+    let mut exported = MacroTable::new();
+    for (name, _) in exports {
+      match macros.remove(&name) {
+        Some(definition) => exported.insert(name, definition),
+        None if exported.contains_key(&name) => None,
+        None => {
+          return Err(in_file(format!(
+            "cannot export '{}', no macro has that name",
+            name
+          )))
+        }
+      };
+    }
+    helpers.extend(macros);
+    helpers.retain(|name, _| !exported.contains_key(name));
+    Ok(Module {
+      macros: exported,
+      helpers,
+      templates,
+      sources,
+    })
+  }
+}
+
+// Modules are cached as their `macro` forms and an `export` of the exported
+// ones, after a header naming each source file with the size and time it had.
+fn write_cache(module: &Module) -> String {
+  let mut out = format!("{}\n", CACHE_HEADER);
+  for stamp in &module.sources {
+    writeln!(
+      out,
+      ";; source {} {} {}",
+      stamp.len,
+      stamp.modified,
+      stamp.path.display()
+    )
+    .unwrap();
+  }
+  let mut helpers: Vec<_> = module.helpers.iter().collect();
+  let mut exports: Vec<_> = module.macros.iter().collect();
+  helpers.sort_by_key(|(name, _)| *name);
+  exports.sort_by_key(|(name, _)| *name);
+  for (name, definition) in helpers.into_iter().chain(exports.iter().copied()) {
+    writeln!(out, "{}", macro_source(name, definition, &module.templates)).unwrap();
+  }
+  out.push_str("(export");
+  for (name, _) in exports {
+    write!(out, " {}", name).unwrap();
+  }
+  out.push_str(")\n");
+  out
+}
+
+// The cached module, if there is a cache and no source changed since.
+fn read_cache(cache: &Path) -> Option<Module> {
+  let text = fs::read_to_string(cache).ok()?;
+  let mut lines = text.lines();
+  if lines.next()? != CACHE_HEADER {
+    return None;
+  }
+
+  let mut sources = Vec::new();
+  for line in lines.clone() {
+    let Some(stamp) = line.strip_prefix(";; source ") else {
+      break;
+    };
+    let mut fields = stamp.splitn(3, ' ');
+    let stamp = Stamp {
+      len: fields.next()?.parse().ok()?,
+      modified: fields.next()?.parse().ok()?,
+      path: PathBuf::from(fields.next()?),
+    };
+    if Stamp::of(&stamp.path).ok()? != stamp {
+      return None;
+    }
+    sources.push(stamp);
+  }
+
+  let mut parser = Parser::new(read_str_scan_with_file(text, FileId::default()).ok()?);
+  let program = parser.parse().ok()?;
+  let NodeType::Program(forms) = &program.node_type else {
+    return None;
+  };
+  let mut helpers = parser.macros().clone();
+  let mut macros = MacroTable::new();
+  for form in forms {
+    if let Some(export) = export_form(form) {
+      for (name, _) in export.ok()? {
+        macros.insert(name.clone(), helpers.remove(&name)?);
+      }
+    }
+  }
+  Some(Module {
+    macros,
+    helpers,
+    templates: parser.templates().clone(),
+    sources,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::diagnostic::diagnostic::read_str_expand;
+  use crate::expander::{expander::Expander, printer::to_source};
+  use std::env;
+
+  #[test]
+  fn test_cache_round_trip() {
+    let dir = env::temp_dir().join(format!("tisp-module-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let base = dir.join("base.tisp");
+    let lib = dir.join("lib.tisp");
+    fs::write(&base, "(macro twice (x) `(list ,x ,x)) (export twice)").unwrap();
+    fs::write(
+      &lib,
+      "(import \"base.tisp\")\n(macro quad (x) `(twice (twice ,x)))\n(export quad twice)",
+    )
+    .unwrap();
+
+    let module = ModuleLoader::default().load(&lib).unwrap();
+    let mut names: Vec<_> = module.macros.keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, ["quad", "twice"]);
+    assert_eq!(module.sources.len(), 2);
+
+    // The cache is used as long as neither source changes...
+    let cache = lib.with_extension(CACHE_EXTENSION);
+    let cached = read_cache(&cache).unwrap();
+    let sources = |module: &Module| {
+      let mut sources: Vec<_> = module
+        .macros
+        .iter()
+        .map(|(name, definition)| macro_source(name, definition, &module.templates))
+        .collect();
+      sources.sort();
+      sources
+    };
+    assert_eq!(sources(&cached), sources(&module));
+    assert!(fs::read_to_string(&cache)
+      .unwrap()
+      .contains("(macro quad (x) `(twice (twice ,x)))"));
+
+    // ...and dropped once one does, even one only imported.
+    fs::write(&base, "(macro twice (x) `(list ,x ,x ,x)) (export twice)").unwrap();
+    assert!(read_cache(&cache).is_none());
+    let module = ModuleLoader::default().load(&lib).unwrap();
+    assert!(macro_source("twice", &module.macros["twice"], &module.templates).contains(",x ,x ,x"));
+
+    fs::write(&base, "(import \"lib.tisp\") (export quad)").unwrap();
+    fs::write(&lib, "(import \"base.tisp\")").unwrap();
+    let error = ModuleLoader::default().load(&lib).unwrap_err();
+    assert!(error.ends_with("the file imports itself"), "{}", error);
+
+    fs::write(&lib, "(export missing)").unwrap();
+    let error = ModuleLoader::default().load(&lib).unwrap_err();
+    assert!(error.ends_with("cannot export 'missing', no macro has that name"));
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_helper_macros() {
+    let dir = env::temp_dir().join(format!("tisp-helpers-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join("lib.tisp");
+    fs::write(
+      &lib,
+      "(macro helper (x) `(list ,x ,x))\n(macro twice (x) `(helper ,x))\n(export twice)",
+    )
+    .unwrap();
+    let expand = |code: &str| {
+      let mut expander = Expander::default().with_base(&dir);
+      read_str_expand(code.to_string(), FileId::default(), &mut expander)
+        .map(|program| to_source(&program, expander.templates()))
+        .map_err(|errors| errors[0].message())
+    };
+
+    // Read from the source, then from the cache.
+    for _ in 0..2 {
+      assert_eq!(
+        expand("(import \"lib.tisp\" twice) (twice 3)").unwrap(),
+        "(list 3 3)\n"
+      );
+      assert_eq!(
+        expand("(import \"lib.tisp\" helper)").unwrap_err(),
+        "'lib.tisp' does not export macro 'helper'"
+      );
+    }
+
+    // The helper is only seen by `twice`; the importer's own `helper` stays.
+    assert_eq!(
+      expand("(macro helper (x) `(+ ,x 100)) (import \"lib.tisp\" twice) (helper (twice 1))")
+        .unwrap(),
+      "(+ (list 1 1) 100)\n"
+    );
+    assert!(
+      expand("(import \"lib.tisp\" twice) (defn helper (x) (* x 10)) (helper 1)")
+        .unwrap()
+        .ends_with("(helper 1)\n")
+    );
+    assert_eq!(
+      expand("(macro twice (x) x) (import \"lib.tisp\" twice)").unwrap_err(),
+      "Macro 'twice' imported from 'lib.tisp' is already defined"
+    );
+    assert_eq!(
+      expand("(import \"lib.tisp\") (def twice 2)").unwrap_err(),
+      "Macro 'twice' imported from 'lib.tisp' is already defined"
+    );
+    assert!(expand("(import \"lib.tisp\") (import \"lib.tisp\" twice) (twice 1)").is_ok());
+
+    let cached = read_cache(&lib.with_extension(CACHE_EXTENSION)).unwrap();
+    assert_eq!(cached.macros.keys().collect::<Vec<_>>(), ["twice"]);
+    assert_eq!(cached.helpers.keys().collect::<Vec<_>>(), ["helper"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,168 @@
+use crate::parser::{
+  ast::{ASTNode, NodeType},
+  parser::{MacroDefinition, TemplateTable},
+};
+use std::fmt::Write;
+
+// Writes a syntax tree back out as source that reads as the same tree.
+// Quasiquote bodies are looked up in `templates`.
+pub fn to_source(node: &ASTNode, templates: &TemplateTable) -> String {
+  let mut out = String::new();
+  write_node(&mut out, node, templates);
+  out
+}
+
+// The `macro` form that defines `definition` under `name`.
+pub fn macro_source(name: &str, definition: &MacroDefinition, templates: &TemplateTable) -> String {
+  let mut out = format!("(macro {} ", name);
+  if definition.hygienic {
+    out.push_str(":hygienic ");
+  }
+  write_list(&mut out, &definition.params, templates);
+  for form in &definition.body {
+    out.push(' ');
+    write_node(&mut out, form, templates);
+  }
+  out.push(')');
+  out
+}
+
+fn write_node(out: &mut String, node: &ASTNode, templates: &TemplateTable) {
+  match &node.node_type {
+    NodeType::Program(forms) => {
+      for form in forms {
+        // Definitions are used up by expansion and leave no code behind.
+        if !matches!(form.node_type, NodeType::MacroDef(_)) {
+          write_node(out, form, templates);
+          out.push('\n');
+        }
+      }
+    }
+    NodeType::Int32(n) => write!(out, "{}", n).unwrap(),
+    NodeType::Int64(n) => write!(out, "{}", n).unwrap(),
+    NodeType::Float32(n) => write!(out, "{:?}", n).unwrap(),
+    NodeType::Float64(n) => write!(out, "{:?}", n).unwrap(),
+    NodeType::Bool(b) => out.push_str(if *b { "#t" } else { "#f" }),
+    NodeType::Nil => out.push_str("nil"),
+    NodeType::Symbol(name) => out.push_str(name),
+    NodeType::Keyword(k) => write!(out, ":{}", k).unwrap(),
+    NodeType::StringLiteral(s) => write_string(out, s),
+    NodeType::Character(c) => write_char(out, *c),
+    NodeType::List(items) => write_list(out, items, templates),
+    NodeType::Quote(inner) => {
+      out.push('\'');
+      write_node(out, inner, templates);
+    }
+    NodeType::Variable(name, value, doc) => {
+      write!(out, "(def {} ", name).unwrap();
+      if let Some(doc) = doc {
+        write_string(out, doc);
+        out.push(' ');
+      }
+      write_node(out, value, templates);
+      out.push(')');
+    }
+    NodeType::FuncDef(params, body) => {
+      out.push_str("(fn ");
+      write_list(out, params, templates);
+      for form in body {
+        out.push(' ');
+        write_node(out, form, templates);
+      }
+      out.push(')');
+    }
+    NodeType::MacroDef(name) => write!(out, "; macro {}", name).unwrap(),
+    NodeType::MacroTemplate(uuid) => {
+      out.push('`');
+      match templates.get(uuid) {
+        Some(template) => write_node(out, template, templates),
+        None => out.push_str("<unknown template>"),
+      }
+    }
+    NodeType::MacroComma(inner) => {
+      out.push(',');
+      write_node(out, inner, templates);
+    }
+    NodeType::MacroListExpand(inner) => {
+      out.push_str(",@");
+      write_node(out, inner, templates);
+    }
+    NodeType::If(..)
+    | NodeType::Let(..)
+    | NodeType::Do(_)
+    | NodeType::Set(..)
+    | NodeType::Cond(_)
+    | NodeType::And(_)
+    | NodeType::Or(_) => write_list(out, &node.special_form_items().unwrap(), templates),
+    NodeType::Error => out.push_str("<error>"),
+  }
+}
+
+fn write_list(out: &mut String, items: &[ASTNode], templates: &TemplateTable) {
+  out.push('(');
+  for (i, item) in items.iter().enumerate() {
+    if i > 0 {
+      out.push(' ');
+    }
+    write_node(out, item, templates);
+  }
+  out.push(')');
+}
+
+fn write_string(out: &mut String, s: &str) {
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\t' => out.push_str("\\t"),
+      '\r' => out.push_str("\\r"),
+      '\0' => out.push_str("\\0"),
+      c if c.is_control() => write!(out, "\\u{{{:X}}}", c as u32).unwrap(),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+}
+
+fn write_char(out: &mut String, c: char) {
+  out.push_str("#\\");
+  match c {
+    ' ' => out.push_str("space"),
+    '\n' => out.push_str("newline"),
+    '\t' => out.push_str("tab"),
+    '\r' => out.push_str("return"),
+    '\0' => out.push_str("nul"),
+    c if c.is_control() => write!(out, "x{:X}", c as u32).unwrap(),
+    c => out.push(c),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::parser::Parser;
+  use crate::scanner::scanner::read_str_scan;
+
+  fn print(code: &str) -> String {
+    let mut parser = Parser::new(read_str_scan(code.to_string()).unwrap());
+    let program = parser.parse().unwrap();
+    to_source(&program, parser.templates())
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let code = "(def f \"Prints.\" (fn (a b) (print \"tab\\there \\\"q\\\"\" #\\space #\\a)))\n\
+                (f 1 -2.5 :key '(x nil #t))\n\
+                `(a `(b ,,c) ,@(d e))\n";
+    assert_eq!(print(code), code);
+    assert_eq!(print("(macro m (x) x) ( m\n 1 )"), "(m 1)\n");
+
+    let code = "(macro m :hygienic (a &rest b) `(f ,a ,@b) 'done)";
+    let mut parser = Parser::new(read_str_scan(code.to_string()).unwrap());
+    parser.parse().unwrap();
+    let source = macro_source("m", &parser.macros()["m"], parser.templates());
+    assert_eq!(source, code);
+  }
+}
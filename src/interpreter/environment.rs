@@ -0,0 +1,44 @@
+use super::value::Value;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+#[derive(Debug, Default)]
+pub struct Environment {
+  values: RefCell<HashMap<String, Value>>,
+  parent: Option<Rc<Environment>>,
+}
+
+impl Environment {
+  pub fn new() -> Rc<Self> {
+    Rc::new(Environment::default())
+  }
+
+  pub fn extend(parent: &Rc<Environment>) -> Rc<Self> {
+    Rc::new(Environment {
+      values: RefCell::new(HashMap::new()),
+      parent: Some(Rc::clone(parent)),
+    })
+  }
+
+  pub fn define(&self, name: &str, value: Value) {
+    self.values.borrow_mut().insert(name.to_string(), value);
+  }
+
+  // Rebinds `name` where it was defined. Returns false if it never was.
+  pub fn set(&self, name: &str, value: Value) -> bool {
+    if let Some(slot) = self.values.borrow_mut().get_mut(name) {
+      *slot = value;
+      return true;
+    }
+    match &self.parent {
+      Some(parent) => parent.set(name, value),
+      None => false,
+    }
+  }
+
+  pub fn get(&self, name: &str) -> Option<Value> {
+    match self.values.borrow().get(name) {
+      Some(value) => Some(value.clone()),
+      None => self.parent.as_ref().and_then(|parent| parent.get(name)),
+    }
+  }
+}
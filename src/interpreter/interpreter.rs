@@ -0,0 +1,983 @@
+use super::{
+  environment::Environment,
+  runtime_error::{RuntimeError, RuntimeResult},
+  value::{Closure, Value},
+};
+use crate::parser::{
+  ast::{ASTNode, NodeType, Params},
+  parser::TemplateTable,
+};
+use std::{
+  cmp::Ordering,
+  collections::HashMap,
+  io::{self, Stdout, Write},
+  rc::Rc,
+};
+use uuid::Uuid;
+
+pub const MAX_CALL_DEPTH: usize = 512;
+
+const BUILTINS: [&str; 16] = [
+  "+", "-", "*", "/", "<", "<=", ">", ">=", "=", "==", "!=", "not", "print", "println", "list",
+  "gensym",
+];
+
+// Reference evaluator over the AST. It implements the same language subset
+// and printing rules as `compiler::codegen` + `vm`, so the two can be checked
+// against each other.
+pub struct Interpreter<W: Write> {
+  globals: Rc<Environment>,
+  output: W,
+  depth: usize,
+  templates: TemplateTable,
+  gensyms: usize,
+  // While set, symbols written in quasiquote templates are replaced by
+  // fresh ones, the same fresh symbol for every use of a name.
+  renames: Option<HashMap<String, String>>,
+  // Docstrings of the globals defined with one.
+  docs: HashMap<String, String>,
+}
+
+impl Interpreter<Stdout> {
+  pub fn new() -> Self {
+    Interpreter::with_output(io::stdout())
+  }
+}
+
+impl Default for Interpreter<Stdout> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<W: Write> Interpreter<W> {
+  pub fn with_output(output: W) -> Self {
+    let globals = Environment::new();
+    for name in BUILTINS {
+      globals.define(name, Value::Builtin(name));
+    }
+    Interpreter {
+      globals,
+      output,
+      depth: 0,
+      templates: TemplateTable::new(),
+      gensyms: 0,
+      renames: None,
+      docs: HashMap::new(),
+    }
+  }
+
+  // Quasiquote bodies live in the parser's template table.
+  pub fn with_templates(mut self, templates: TemplateTable) -> Self {
+    self.templates = templates;
+    self
+  }
+
+  pub fn templates(&self) -> &TemplateTable {
+    &self.templates
+  }
+
+  pub fn templates_mut(&mut self) -> &mut TemplateTable {
+    &mut self.templates
+  }
+
+  // A symbol no program can spell: the scanner never puts '#' inside a name.
+  pub fn gensym(&mut self, prefix: &str) -> String {
+    self.gensyms += 1;
+    format!("{}#{}", prefix, self.gensyms)
+  }
+
+  pub fn rename_template_symbols(&mut self) {
+    self.renames = Some(HashMap::new());
+  }
+
+  // Stops renaming and returns each renamed name with its fresh symbol.
+  pub fn take_renames(&mut self) -> HashMap<String, String> {
+    self.renames.take().unwrap_or_default()
+  }
+
+  fn template_symbol(&mut self, name: &str) -> String {
+    match &self.renames {
+      Some(renames) if renames.contains_key(name) => renames[name].clone(),
+      Some(_) => {
+        let fresh = self.gensym(name);
+        if let Some(renames) = &mut self.renames {
+          renames.insert(name.to_string(), fresh.clone());
+        }
+        fresh
+      }
+      None => name.to_string(),
+    }
+  }
+
+  #[allow(dead_code)]
+  pub fn output(&self) -> &W {
+    &self.output
+  }
+
+  pub fn output_mut(&mut self) -> &mut W {
+    &mut self.output
+  }
+
+  pub fn globals(&self) -> &Rc<Environment> {
+    &self.globals
+  }
+
+  pub fn doc(&self, name: &str) -> Option<&str> {
+    self.docs.get(name).map(String::as_str)
+  }
+
+  pub fn eval_program(&mut self, program: &ASTNode) -> RuntimeResult<Value> {
+    match &program.node_type {
+      NodeType::Program(nodes) => {
+        let env = Rc::clone(&self.globals);
+        self.eval_body(nodes, &env)
+      }
+      _ => Err(RuntimeError::new("Expected a program")),
+    }
+  }
+
+  pub fn eval(&mut self, node: &ASTNode, env: &Rc<Environment>) -> RuntimeResult<Value> {
+    match &node.node_type {
+      NodeType::Int32(_) | NodeType::Int64(_) | NodeType::Float32(_) | NodeType::Float64(_) => {
+        number(node)
+      }
+      NodeType::Bool(b) => Ok(Value::Bool(*b)),
+      NodeType::Nil => Ok(Value::Nil),
+      NodeType::StringLiteral(s) => Ok(Value::Str(s.clone())),
+      NodeType::Keyword(k) => Ok(Value::Keyword(k.clone())),
+      NodeType::Character(c) => Ok(Value::Character(*c)),
+      NodeType::Symbol(name) => env
+        .get(name)
+        .ok_or_else(|| RuntimeError::new(&format!("Undefined symbol '{}'", name))),
+      NodeType::Quote(quoted) => self.quote(quoted, env, 0),
+      NodeType::Variable(name, value, doc) => {
+        let value = self.eval(value, env)?;
+        self.globals.define(name, value.clone());
+        // A redefinition without a docstring drops the old one.
+        match doc {
+          Some(doc) => self.docs.insert(name.clone(), doc.clone()),
+          None => self.docs.remove(name),
+        };
+        Ok(value)
+      }
+      NodeType::FuncDef(params, body) => self.eval_fn(params, body, env),
+      NodeType::List(items) => self.eval_list(items, env),
+      NodeType::MacroDef(_) => Ok(Value::Nil),
+      NodeType::Program(_) => Err(RuntimeError::new("Unexpected nested program")),
+      NodeType::MacroTemplate(uuid) => {
+        let template = self.template(uuid)?;
+        self.quote(&template, env, 1)
+      }
+      NodeType::MacroComma(_) | NodeType::MacroListExpand(_) => {
+        Err(RuntimeError::new("Unquote outside of quasiquote"))
+      }
+      NodeType::If(..)
+      | NodeType::Let(..)
+      | NodeType::Do(_)
+      | NodeType::Set(..)
+      | NodeType::Cond(_)
+      | NodeType::And(_)
+      | NodeType::Or(_) => self.eval_special_form(node, env),
+      NodeType::Error => Err(RuntimeError::new(
+        "Cannot evaluate a form that failed to parse",
+      )),
+    }
+  }
+
+  fn eval_fn(
+    &mut self,
+    params: &[ASTNode],
+    body: &[ASTNode],
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    let params = Params::from_nodes(params).map_err(|(message, _)| RuntimeError::new(&message))?;
+    Ok(Value::Function(Rc::new(Closure {
+      params,
+      body: body.to_vec(),
+      env: Rc::clone(env),
+    })))
+  }
+
+  // Kept out of `eval`, whose frame is paid for at every level of recursion.
+  fn eval_special_form(&mut self, node: &ASTNode, env: &Rc<Environment>) -> RuntimeResult<Value> {
+    match &node.node_type {
+      NodeType::If(condition, then, otherwise) => self.eval_if(condition, then, otherwise, env),
+      NodeType::Let(bindings, body) => self.eval_let(bindings, body, env),
+      NodeType::Do(body) => self.eval_body(body, env),
+      NodeType::Set(name, value) => self.eval_set(name, value, env),
+      NodeType::Cond(clauses) => self.eval_cond(clauses, env),
+      NodeType::And(items) => self.eval_and_or(items, true, env),
+      NodeType::Or(items) => self.eval_and_or(items, false, env),
+      _ => unreachable!("not a special form"),
+    }
+  }
+
+  fn eval_if(
+    &mut self,
+    condition: &ASTNode,
+    then: &ASTNode,
+    otherwise: &Option<Box<ASTNode>>,
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    if self.eval(condition, env)?.is_truthy() {
+      self.eval(then, env)
+    } else {
+      match otherwise {
+        Some(otherwise) => self.eval(otherwise, env),
+        None => Ok(Value::Nil),
+      }
+    }
+  }
+
+  // Each binding sees the ones before it.
+  fn eval_let(
+    &mut self,
+    bindings: &[(String, ASTNode)],
+    body: &[ASTNode],
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    let env = Environment::extend(env);
+    for (name, value) in bindings {
+      let value = self.eval(value, &env)?;
+      env.define(name, value);
+    }
+    self.eval_body(body, &env)
+  }
+
+  fn eval_set(
+    &mut self,
+    name: &str,
+    value: &ASTNode,
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    let value = self.eval(value, env)?;
+    if env.set(name, value.clone()) {
+      Ok(value)
+    } else {
+      Err(RuntimeError::new(&format!(
+        "Cannot set! undefined variable '{}'",
+        name
+      )))
+    }
+  }
+
+  // A clause without a body yields its test's value.
+  fn eval_cond(
+    &mut self,
+    clauses: &[(ASTNode, Vec<ASTNode>)],
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    for (test, body) in clauses {
+      let value = self.eval(test, env)?;
+      if value.is_truthy() {
+        return if body.is_empty() {
+          Ok(value)
+        } else {
+          self.eval_body(body, env)
+        };
+      }
+    }
+    Ok(Value::Nil)
+  }
+
+  // `and` stops at the first falsy value and `or` at the first truthy one.
+  fn eval_and_or(
+    &mut self,
+    items: &[ASTNode],
+    is_and: bool,
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    let mut value = Value::Bool(is_and);
+    for item in items {
+      value = self.eval(item, env)?;
+      if value.is_truthy() != is_and {
+        break;
+      }
+    }
+    Ok(value)
+  }
+
+  fn template(&self, uuid: &Uuid) -> RuntimeResult<ASTNode> {
+    self
+      .templates
+      .get(uuid)
+      .map(|template| (**template).clone())
+      .ok_or_else(|| RuntimeError::new("Unknown quasiquote template"))
+  }
+
+  // Turns code into data. `level` counts the quasiquotes around `node`; an
+  // unquote at level 1 is evaluated, deeper ones stay as `(unquote ...)`.
+  pub fn quote(
+    &mut self,
+    node: &ASTNode,
+    env: &Rc<Environment>,
+    level: usize,
+  ) -> RuntimeResult<Value> {
+    let tagged =
+      |tag: &str, value: Value| Value::List(Rc::new(vec![Value::Symbol(tag.to_string()), value]));
+    match &node.node_type {
+      NodeType::Int32(_) | NodeType::Int64(_) | NodeType::Float32(_) | NodeType::Float64(_) => {
+        number(node)
+      }
+      NodeType::Bool(b) => Ok(Value::Bool(*b)),
+      NodeType::Nil => Ok(Value::Nil),
+      NodeType::StringLiteral(s) => Ok(Value::Str(s.clone())),
+      NodeType::Keyword(k) => Ok(Value::Keyword(k.clone())),
+      NodeType::Character(c) => Ok(Value::Character(*c)),
+      NodeType::Symbol(name) if level > 0 => Ok(Value::Symbol(self.template_symbol(name))),
+      NodeType::Symbol(name) => Ok(Value::Symbol(name.clone())),
+      NodeType::List(items) => Ok(Value::List(Rc::new(self.quote_items(items, env, level)?))),
+      NodeType::Quote(inner) => Ok(tagged("quote", self.quote(inner, env, level)?)),
+      NodeType::Variable(name, value, doc) => {
+        let mut items = vec![
+          Value::Symbol("def".to_string()),
+          Value::Symbol(name.clone()),
+        ];
+        items.extend(doc.iter().map(|doc| Value::Str(doc.clone())));
+        items.push(self.quote(value, env, level)?);
+        Ok(Value::List(Rc::new(items)))
+      }
+      NodeType::FuncDef(params, body) => {
+        let mut items = vec![
+          Value::Symbol("fn".to_string()),
+          Value::List(Rc::new(self.quote_items(params, env, level)?)),
+        ];
+        items.extend(self.quote_items(body, env, level)?);
+        Ok(Value::List(Rc::new(items)))
+      }
+      NodeType::MacroTemplate(uuid) => {
+        let template = self.template(uuid)?;
+        Ok(tagged("quasiquote", self.quote(&template, env, level + 1)?))
+      }
+      NodeType::MacroComma(inner) if level == 1 => self.eval(inner, env),
+      NodeType::MacroComma(inner) => Ok(tagged(
+        "unquote",
+        self.quote(inner, env, level.saturating_sub(1))?,
+      )),
+      NodeType::MacroListExpand(_) if level == 1 => Err(RuntimeError::new(
+        "Unquote-splicing ',@' must be inside a list",
+      )),
+      NodeType::MacroListExpand(inner) => Ok(tagged(
+        "unquote-splicing",
+        self.quote(inner, env, level.saturating_sub(1))?,
+      )),
+      _ => match node.special_form_items() {
+        Some(items) => Ok(Value::List(Rc::new(self.quote_items(&items, env, level)?))),
+        None => Err(RuntimeError::new("Unsupported quoted expression")),
+      },
+    }
+  }
+
+  fn quote_items(
+    &mut self,
+    items: &[ASTNode],
+    env: &Rc<Environment>,
+    level: usize,
+  ) -> RuntimeResult<Vec<Value>> {
+    let mut values = Vec::new();
+    for item in items {
+      match &item.node_type {
+        NodeType::MacroListExpand(inner) if level == 1 => match self.eval(inner, env)? {
+          Value::List(spliced) => values.extend(spliced.iter().cloned()),
+          Value::Nil => {}
+          other => {
+            return Err(RuntimeError::new(&format!(
+              "Unquote-splicing ',@' expects a list, got {}",
+              other.type_name()
+            )))
+          }
+        },
+        _ => values.push(self.quote(item, env, level)?),
+      }
+    }
+    Ok(values)
+  }
+
+  fn eval_body(&mut self, body: &[ASTNode], env: &Rc<Environment>) -> RuntimeResult<Value> {
+    let mut result = Value::Nil;
+    for node in body {
+      result = self.eval(node, env)?;
+    }
+    Ok(result)
+  }
+
+  fn eval_list(&mut self, items: &[ASTNode], env: &Rc<Environment>) -> RuntimeResult<Value> {
+    let (head, args) = match items.split_first() {
+      Some(split) => split,
+      None => return Ok(Value::Nil),
+    };
+
+    let function = self.eval(head, env)?;
+    let args = args
+      .iter()
+      .map(|arg| self.eval(arg, env))
+      .collect::<RuntimeResult<Vec<_>>>()?;
+    self.apply(&function, args)
+  }
+
+  pub fn apply(&mut self, function: &Value, args: Vec<Value>) -> RuntimeResult<Value> {
+    match function {
+      Value::Builtin(name) => self.apply_builtin(name, args),
+      Value::Function(closure) => {
+        if !closure.params.accepts(args.len()) {
+          return Err(RuntimeError::new(&format!(
+            "Function expects {} arguments, got {}",
+            closure.params.arity(),
+            args.len()
+          )));
+        }
+        if self.depth >= MAX_CALL_DEPTH {
+          return Err(RuntimeError::new("Call stack overflow"));
+        }
+
+        let env = Environment::extend(&closure.env);
+        self.depth += 1;
+        let result = self
+          .bind_params(&closure.params, args, &env)
+          .and_then(|()| self.eval_body(&closure.body, &env));
+        self.depth -= 1;
+        result
+      }
+      other => Err(RuntimeError::new(&format!(
+        "Cannot call a value of type {}",
+        other.type_name()
+      ))),
+    }
+  }
+
+  // Missing &optional and &key arguments take their defaults, evaluated
+  // after the parameters before them are bound. Whatever follows the
+  // &optional arguments is both the &rest list and the `:key value` pairs.
+  fn bind_params(
+    &mut self,
+    params: &Params,
+    args: Vec<Value>,
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<()> {
+    let mut args = args.into_iter();
+    for name in &params.required {
+      env.define(name, args.next().unwrap_or(Value::Nil));
+    }
+    for (name, default) in &params.optional {
+      let value = match args.next() {
+        Some(value) => value,
+        None => self.default_value(default, env)?,
+      };
+      env.define(name, value);
+    }
+
+    let rest: Vec<Value> = args.collect();
+    if let Some(name) = &params.rest {
+      env.define(name, Value::List(Rc::new(rest.clone())));
+    }
+    if params.keys.is_empty() {
+      return Ok(());
+    }
+
+    let mut supplied = HashMap::new();
+    for pair in rest.chunks(2) {
+      match pair {
+        [Value::Keyword(key), value] if params.keys.iter().any(|(name, _)| name == key) => {
+          supplied.insert(key.as_str(), value.clone());
+        }
+        [Value::Keyword(key), _] => {
+          return Err(RuntimeError::new(&format!(
+            "Unknown keyword argument :{}",
+            key
+          )))
+        }
+        [Value::Keyword(key)] => {
+          return Err(RuntimeError::new(&format!(
+            "Keyword argument :{} has no value",
+            key
+          )))
+        }
+        [other, ..] => {
+          return Err(RuntimeError::new(&format!(
+            "Expected a keyword argument, got {}",
+            other.type_name()
+          )))
+        }
+        [] => {}
+      }
+    }
+    for (name, default) in &params.keys {
+      let value = match supplied.remove(name.as_str()) {
+        Some(value) => value,
+        None => self.default_value(default, env)?,
+      };
+      env.define(name, value);
+    }
+    Ok(())
+  }
+
+  fn default_value(
+    &mut self,
+    default: &Option<ASTNode>,
+    env: &Rc<Environment>,
+  ) -> RuntimeResult<Value> {
+    match default {
+      Some(default) => self.eval(default, env),
+      None => Ok(Value::Nil),
+    }
+  }
+
+  fn apply_builtin(&mut self, name: &str, args: Vec<Value>) -> RuntimeResult<Value> {
+    match name {
+      "+" | "-" | "*" | "/" => arithmetic(name, args),
+      "<" | "<=" | ">" | ">=" | "=" | "==" | "!=" => {
+        if args.len() != 2 {
+          return Err(RuntimeError::new(&format!(
+            "{} expects 2 arguments, got {}",
+            name,
+            args.len()
+          )));
+        }
+        compare(name, &args[0], &args[1])
+      }
+      "not" => match args.as_slice() {
+        [value] => Ok(Value::Bool(!value.is_truthy())),
+        _ => Err(RuntimeError::new(&format!(
+          "not expects 1 argument, got {}",
+          args.len()
+        ))),
+      },
+      "print" | "println" => {
+        let text = args
+          .iter()
+          .map(|arg| arg.to_string())
+          .collect::<Vec<_>>()
+          .join(" ");
+        let result = if name == "println" {
+          writeln!(self.output, "{}", text)
+        } else {
+          write!(self.output, "{}", text).and_then(|_| self.output.flush())
+        };
+        result.map_err(|e| RuntimeError::new(&e.to_string()))?;
+        Ok(Value::Nil)
+      }
+      "list" => Ok(Value::List(Rc::new(args))),
+      "gensym" => match args.as_slice() {
+        [] => Ok(Value::Symbol(self.gensym("g"))),
+        [Value::Symbol(prefix)] | [Value::Str(prefix)] => Ok(Value::Symbol(self.gensym(prefix))),
+        [other] => Err(RuntimeError::new(&format!(
+          "gensym expects a symbol or string prefix, got {}",
+          other.type_name()
+        ))),
+        _ => Err(RuntimeError::new(&format!(
+          "gensym expects at most 1 argument, got {}",
+          args.len()
+        ))),
+      },
+      _ => Err(RuntimeError::new(&format!("Unknown builtin '{}'", name))),
+    }
+  }
+}
+
+// Values are 32-bit, so wider literals are rejected when evaluated.
+fn number(node: &ASTNode) -> RuntimeResult<Value> {
+  match &node.node_type {
+    NodeType::Int32(n) => Ok(Value::Int(*n)),
+    NodeType::Float32(n) => Ok(Value::Float(*n)),
+    NodeType::Int64(n) => Err(RuntimeError::new(&format!(
+      "Integer literal {} does not fit in 32 bits",
+      n
+    ))),
+    NodeType::Float64(n) => Err(RuntimeError::new(&format!(
+      "Float literal {:?} is outside the 32-bit float range",
+      n
+    ))),
+    _ => Err(RuntimeError::new("Expected a number")),
+  }
+}
+
+fn arithmetic(op: &str, args: Vec<Value>) -> RuntimeResult<Value> {
+  match (op, args.len()) {
+    ("+", 0) => return Ok(Value::Int(0)),
+    ("*", 0) => return Ok(Value::Int(1)),
+    (_, 0) => {
+      return Err(RuntimeError::new(&format!(
+        "{} expects at least 1 argument",
+        op
+      )))
+    }
+    ("-", 1) => {
+      return match &args[0] {
+        Value::Int(n) => n
+          .checked_neg()
+          .map(Value::Int)
+          .ok_or_else(|| RuntimeError::new("Integer overflow")),
+        Value::Float(n) => Ok(Value::Float(-n)),
+        other => Err(type_error(op, &[other])),
+      }
+    }
+    ("/", 1) => return binary_arithmetic(op, Value::Int(1), args[0].clone()),
+    _ => {}
+  }
+
+  let mut args = args.into_iter();
+  let first = args.next().unwrap();
+  args.try_fold(first, |acc, arg| binary_arithmetic(op, acc, arg))
+}
+
+fn binary_arithmetic(op: &str, lhs: Value, rhs: Value) -> RuntimeResult<Value> {
+  match (&lhs, &rhs) {
+    (Value::Int(a), Value::Int(b)) => {
+      let result = match op {
+        "+" => a.checked_add(*b),
+        "-" => a.checked_sub(*b),
+        "*" => a.checked_mul(*b),
+        _ if *b == 0 => return Err(RuntimeError::new("Division by zero")),
+        _ => a.checked_div(*b),
+      };
+      result
+        .map(Value::Int)
+        .ok_or_else(|| RuntimeError::new("Integer overflow"))
+    }
+    (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+      let (a, b) = (as_float(&lhs), as_float(&rhs));
+      Ok(Value::Float(match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        _ => a / b,
+      }))
+    }
+    (Value::Str(a), Value::Str(b)) if op == "+" => Ok(Value::Str(format!("{}{}", a, b))),
+    _ => Err(type_error(op, &[&lhs, &rhs])),
+  }
+}
+
+fn compare(op: &str, lhs: &Value, rhs: &Value) -> RuntimeResult<Value> {
+  let ordering = match op {
+    "=" | "==" => return Ok(Value::Bool(lhs == rhs)),
+    "!=" => return Ok(Value::Bool(lhs != rhs)),
+    _ => match (lhs, rhs) {
+      (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+      (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+        as_float(lhs).partial_cmp(&as_float(rhs))
+      }
+      (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+      _ => return Err(type_error(op, &[lhs, rhs])),
+    },
+  };
+
+  Ok(Value::Bool(match (op, ordering) {
+    (_, None) => false,
+    ("<", Some(ordering)) => ordering == Ordering::Less,
+    ("<=", Some(ordering)) => ordering != Ordering::Greater,
+    (">", Some(ordering)) => ordering == Ordering::Greater,
+    (_, Some(ordering)) => ordering != Ordering::Less,
+  }))
+}
+
+fn as_float(value: &Value) -> f32 {
+  match value {
+    Value::Int(n) => *n as f32,
+    Value::Float(n) => *n,
+    _ => f32::NAN,
+  }
+}
+
+fn type_error(op: &str, operands: &[&Value]) -> RuntimeError {
+  let types: Vec<&str> = operands.iter().map(|v| v.type_name()).collect();
+  RuntimeError::new(&format!(
+    "Type error: {} cannot operate on {}",
+    op,
+    types.join(" and ")
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler::codegen::compile;
+  use crate::parser::parser::Parser;
+  use crate::scanner::scanner::read_str_scan;
+  use crate::vm::vm::Vm;
+
+  fn parse_lisp_code(code: &str) -> ASTNode {
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    Parser::new(tokens).parse().unwrap()
+  }
+
+  fn eval_lisp_code(code: &str) -> (RuntimeResult<Value>, String) {
+    let mut interpreter = Interpreter::with_output(Vec::new());
+    let result = interpreter.eval_program(&parse_lisp_code(code));
+    (
+      result,
+      String::from_utf8(interpreter.output().clone()).unwrap(),
+    )
+  }
+
+  // Runs `code` through both evaluators and expects identical output.
+  fn assert_same_as_vm(code: &str) {
+    let (result, output) = eval_lisp_code(code);
+    let result = result.unwrap();
+
+    let program = compile(&parse_lisp_code(code)).unwrap();
+    let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+    let vm_result = vm.run().unwrap();
+    let vm_output = String::from_utf8(vm.output().clone()).unwrap();
+
+    assert_eq!(output, vm_output);
+    assert_eq!(result.to_string(), vm.display(&vm_result).to_string());
+  }
+
+  // Runs `code` through both evaluators and expects the same runtime error.
+  fn assert_same_error_as_vm(code: &str, message: &str) {
+    assert_eq!(eval_lisp_code(code).0.unwrap_err().message, message);
+
+    let program = compile(&parse_lisp_code(code)).unwrap();
+    let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+    assert_eq!(vm.run().unwrap_err().message, message);
+  }
+
+  // Runs `code` where the evaluators are known to differ: the interpreter
+  // gives `interpreted`, the VM `compiled` or a compile error with that text.
+  fn assert_differs_from_vm(code: &str, interpreted: &str, compiled: &str) {
+    let mut parser = Parser::new(read_str_scan(code.to_string()).unwrap());
+    let program = parser.parse().unwrap();
+    let mut interpreter =
+      Interpreter::with_output(Vec::new()).with_templates(parser.templates().clone());
+    let result = interpreter.eval_program(&program).unwrap();
+    assert_eq!(result.to_string(), interpreted);
+
+    let vm_result = match compile(&program) {
+      Ok(program) => {
+        let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+        let value = vm.run().unwrap();
+        vm.display(&value).to_string()
+      }
+      Err(error) => error.message,
+    };
+    assert_eq!(vm_result, compiled);
+  }
+
+  #[test]
+  fn test_function_definition_and_call() {
+    let code = r#"
+            (def add
+              (fn (x y)
+                (+ x y)))
+            (add 10 20)
+        "#;
+
+    assert_eq!(eval_lisp_code(code).0, Ok(Value::Int(30)));
+  }
+
+  #[test]
+  fn test_functions_see_latest_global_bindings() {
+    let code = r#"
+            (def n 1)
+            (def add-n (fn (x) (+ x n)))
+            (def n 5)
+            (add-n 10)
+        "#;
+
+    assert_eq!(eval_lisp_code(code).0, Ok(Value::Int(15)));
+  }
+
+  #[test]
+  fn test_if_and_quote() {
+    let code = r#"
+            (if (> 1 2) 'yes '(no 1 "two"))
+        "#;
+
+    let (result, _) = eval_lisp_code(code);
+    assert_eq!(result.unwrap().to_string(), "(no 1 two)");
+    assert_eq!(eval_lisp_code("(if #f 1)").0, Ok(Value::Nil));
+  }
+
+  #[test]
+  fn test_special_forms() {
+    let code = r#"
+            (def n 0)
+            (def bump (fn () (set! n (+ n 1)) n))
+            (let ((x 1) (y (+ x 1)))
+              (bump)
+              (list x y (do (bump) n) (cond (#f 1) ((bump)) (else 3))))
+        "#;
+    let (result, _) = eval_lisp_code(code);
+    assert_eq!(result.unwrap().to_string(), "(1 2 2 3)");
+
+    let (result, _) = eval_lisp_code("(list (and) (or) (and 1 nil 2) (or #f 2 missing))");
+    assert_eq!(result.unwrap().to_string(), "(#t #f nil 2)");
+
+    let (result, _) = eval_lisp_code("(set! missing 1)");
+    assert_eq!(
+      result.unwrap_err().message,
+      "Cannot set! undefined variable 'missing'"
+    );
+  }
+
+  #[test]
+  fn test_params() {
+    let code = r#"
+            (defn f (a &optional (b (+ a 1)) &rest more &key (k b))
+              (list a b more k))
+            (list (f 1) (f 1 5) (f 1 5 :k 9) ((fn (&rest all) all)))
+        "#;
+    let (result, _) = eval_lisp_code(code);
+    assert_eq!(
+      result.unwrap().to_string(),
+      "((1 2 () 2) (1 5 () 5) (1 5 (:k 9) 9) ())"
+    );
+
+    let error = |code: &str| eval_lisp_code(code).0.unwrap_err().message;
+    assert_eq!(
+      error("((fn (a &optional b) a))"),
+      "Function expects 1 to 2 arguments, got 0"
+    );
+    assert_eq!(
+      error("((fn (&key k) k) :j 1)"),
+      "Unknown keyword argument :j"
+    );
+    assert_eq!(
+      error("((fn (&key k) k) :k)"),
+      "Keyword argument :k has no value"
+    );
+  }
+
+  #[test]
+  fn test_gensym() {
+    let (result, _) = eval_lisp_code("(list (gensym) (gensym 'tmp) (gensym \"x\") (gensym))");
+    assert_eq!(result.unwrap().to_string(), "(g#1 tmp#2 x#3 g#4)");
+
+    let (result, _) = eval_lisp_code("(gensym 1)");
+    assert_eq!(
+      result.unwrap_err().message,
+      "gensym expects a symbol or string prefix, got int"
+    );
+  }
+
+  #[test]
+  fn test_runtime_errors() {
+    let (result, _) = eval_lisp_code("(+ 1 missing)");
+    assert_eq!(result.unwrap_err().message, "Undefined symbol 'missing'");
+
+    let (result, _) = eval_lisp_code("(/ 1 0)");
+    assert_eq!(result.unwrap_err().message, "Division by zero");
+
+    let (result, _) = eval_lisp_code("(def f (fn () (f))) (f)");
+    assert_eq!(result.unwrap_err().message, "Call stack overflow");
+
+    let (result, _) = eval_lisp_code("(* 2 1e100)");
+    assert_eq!(
+      result.unwrap_err().message,
+      "Float literal 1e100 is outside the 32-bit float range"
+    );
+  }
+
+  #[test]
+  fn test_differential_against_vm() {
+    assert_same_as_vm(
+      r#"
+            (def fib (fn (n)
+              (if (< n 2)
+                n
+                (+ (fib (- n 1)) (fib (- n 2))))))
+            (println "fib" (fib 15))
+            (fib 10)
+        "#,
+    );
+    assert_same_as_vm(r#"(println 1 2.5 "s" #t #f nil (not 0) '(a (b c)))"#);
+    assert_same_as_vm("(list (quote (a b)) (quote x) (= (quote (a b)) '(a b)))");
+    assert_same_as_vm("(+ 1 2.5 (* 2 3) (/ 7 2) (- 4))");
+    assert_same_as_vm(
+      r#"
+            (defn twice (f x) (f (f x)))
+            (println (twice (fn (n) (* n 3)) 2) ((fn (x) (+ x 1)) 41))
+            (defn greet (name &optional (greeting "hello") punct) (list greeting name punct))
+            (println (greet "bob") (greet "al" "hi" "!"))
+            (defn collect (first &rest more) (list first more))
+            (println (collect 1) (collect 1 2 3))
+            (defn box (&key (width 10) (height (* width 2)) label) (list width height label))
+            (println (box) (box :height 3) (box :label "x" :width 4))
+            (defn mixed (a &optional (b 2) &rest r &key k) (list a b r k))
+            (list (mixed 1) (mixed 1 5 :k 9))
+        "#,
+    );
+    assert_same_as_vm(
+      r#"
+            (def base (* 6 7))
+            (defn add-base (x) "Adds the base." (+ x base))
+            (def result (if (> base 40) (add-base 1) 0))
+            (do (def a 1) (def b (+ a 1)))
+            (list result a b)
+        "#,
+    );
+    assert_same_as_vm("(list -4 #xff #b-11 1_000 2.5e3 -1.5e-3)");
+    assert_same_as_vm(r#"(print (+ "con" "cat") (== 1 1.0) (= 'a 'b) (<= 2 2))"#);
+    assert_same_as_vm(
+      r#"
+            (def total 0)
+            (def add! (fn (x) (set! total (+ total x)) total))
+            (def sign (fn (n)
+              (cond ((< n 0) -1)
+                    ((== n 0) 0)
+                    (else 1))))
+            (def count (fn (n)
+              (let ((i 0) (seen '()))
+                (do (set! i (+ i n))
+                    (let ((i (* i 10))) (println "inner" i))
+                    i))))
+            (println (count 3))
+            (println (sign -5) (sign 0) (sign 7))
+            (println (and 1 2) (and 1 #f (add! 100)) (or nil (add! 5)) (or 1 (add! 100)))
+            total
+        "#,
+    );
+    assert_same_as_vm(
+      r#"
+            (defn f (x) (let ((y x)) (set! y (and #t y)) y))
+            (defn g (x) (set! x (or #f x)) x)
+            (defn h (x) (set! x (do 1 (+ x 1))) x)
+            (defn k (x) (set! x (cond (#f 0) (x))) x)
+            (list (f 5) (g 7) (h 7) (k 9))
+        "#,
+    );
+
+    assert_same_error_as_vm("((fn (x) x) 1 2)", "Function expects 1 arguments, got 2");
+    assert_same_error_as_vm(
+      "(defn f (a &optional b) a) (def g f) (g)",
+      "Function expects 1 to 2 arguments, got 0",
+    );
+    assert_same_error_as_vm(
+      "(defn apply (f) (f)) (apply (fn (a &rest r) a))",
+      "Function expects at least 1 arguments, got 0",
+    );
+
+    let keys = "(defn box (&key (width 10) height) (list width height))";
+    assert_same_error_as_vm(
+      &format!("{} (box :depth 1)", keys),
+      "Unknown keyword argument :depth",
+    );
+    assert_same_error_as_vm(
+      &format!("{} (box :width 1 :height)", keys),
+      "Keyword argument :height has no value",
+    );
+    assert_same_error_as_vm(
+      &format!("{} (box 1 :width 2)", keys),
+      "Expected a keyword argument, got int",
+    );
+
+    // Known differences. The compiler only takes code whose macros are
+    // expanded, so quasiquote and gensym exist only in the interpreter; it
+    // has no closures; and the VM keeps symbols and keywords as strings, so
+    // they equal strings with the same text.
+    assert_differs_from_vm(
+      "(def x 3) `(a `(b ,(c ,x)))",
+      "(a (quasiquote (b (unquote (c 3)))))",
+      "Macro syntax must be expanded before compilation",
+    );
+    assert_differs_from_vm("(gensym 'tmp)", "tmp#1", "Undefined symbol 'gensym'");
+    assert_differs_from_vm(
+      "(defn adder (x) (fn (y) (+ x y))) ((adder 1) 2)",
+      "3",
+      "Cannot capture local variable 'x' in a nested fn: closures are not supported by the compiler",
+    );
+    assert_differs_from_vm(r#"(list (= :a ":a") (= 'a "a"))"#, "(#f #f)", "(#t #t)");
+  }
+}
@@ -0,0 +1,5 @@
+pub mod environment;
+#[allow(clippy::module_inception)]
+pub mod interpreter;
+pub mod runtime_error;
+pub mod value;
@@ -0,0 +1,22 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+  pub message: String,
+}
+
+impl RuntimeError {
+  pub fn new(message: &str) -> Self {
+    RuntimeError {
+      message: message.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for RuntimeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+pub type RuntimeResult<T> = Result<T, RuntimeError>;
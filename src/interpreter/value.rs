@@ -0,0 +1,95 @@
+use super::environment::Environment;
+use crate::parser::ast::{ASTNode, Params};
+use std::{fmt, rc::Rc};
+
+#[derive(Debug)]
+pub struct Closure {
+  pub params: Params,
+  pub body: Vec<ASTNode>,
+  pub env: Rc<Environment>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+  Nil,
+  Int(i32),
+  Float(f32),
+  Bool(bool),
+  Str(String),
+  Character(char),
+  Keyword(String),
+  Symbol(String), // only produced by quote
+  List(Rc<Vec<Value>>),
+  Function(Rc<Closure>),
+  Builtin(&'static str),
+}
+
+impl Value {
+  pub fn is_truthy(&self) -> bool {
+    !matches!(self, Value::Nil | Value::Bool(false))
+  }
+
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Value::Nil => "nil",
+      Value::Int(_) => "int",
+      Value::Float(_) => "float",
+      Value::Bool(_) => "bool",
+      Value::Str(_) => "string",
+      Value::Character(_) => "character",
+      Value::Keyword(_) => "keyword",
+      Value::Symbol(_) => "symbol",
+      Value::List(_) => "list",
+      Value::Function(_) | Value::Builtin(_) => "function",
+    }
+  }
+}
+
+impl PartialEq for Value {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Value::Nil, Value::Nil) => true,
+      (Value::Int(a), Value::Int(b)) => a == b,
+      (Value::Float(a), Value::Float(b)) => a == b,
+      (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => *a as f32 == *b,
+      (Value::Bool(a), Value::Bool(b)) => a == b,
+      (Value::Str(a), Value::Str(b)) => a == b,
+      (Value::Character(a), Value::Character(b)) => a == b,
+      (Value::Keyword(a), Value::Keyword(b)) => a == b,
+      (Value::Symbol(a), Value::Symbol(b)) => a == b,
+      (Value::List(a), Value::List(b)) => a == b,
+      (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+      (Value::Builtin(a), Value::Builtin(b)) => a == b,
+      _ => false,
+    }
+  }
+}
+
+// Matches the VM's `print` output so both evaluators can be compared.
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Value::Nil => write!(f, "nil"),
+      Value::Int(n) => write!(f, "{}", n),
+      Value::Float(n) => write!(f, "{:?}", n),
+      Value::Bool(true) => write!(f, "#t"),
+      Value::Bool(false) => write!(f, "#f"),
+      Value::Str(s) => write!(f, "{}", s),
+      Value::Character(c) => write!(f, "{}", c),
+      Value::Keyword(k) => write!(f, ":{}", k),
+      Value::Symbol(s) => write!(f, "{}", s),
+      Value::List(items) => {
+        write!(f, "(")?;
+        for (i, item) in items.iter().enumerate() {
+          if i > 0 {
+            write!(f, " ")?;
+          }
+          write!(f, "{}", item)?;
+        }
+        write!(f, ")")
+      }
+      Value::Function(_) => write!(f, "<fn>"),
+      Value::Builtin(name) => write!(f, "<builtin {}>", name),
+    }
+  }
+}
@@ -1,11 +1,16 @@
 mod compiler;
+mod diagnostic;
+mod driver;
+mod expander;
+mod interpreter;
 mod parser;
 mod repl;
 mod scanner;
+mod vm;
 
-use parser::parser::Parser;
-use scanner::scanner::read_str_scan;
+use std::{env, process};
 
 fn main() {
-
+  let args: Vec<String> = env::args().skip(1).collect();
+  process::exit(driver::driver::run(&args));
 }
@@ -1,22 +1,292 @@
+use crate::scanner::span::Span;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum ASTNode {
+pub enum NodeType {
   Program(Vec<ASTNode>),
   Int32(i32),
+  Int64(i64),
   Float32(f32),
-  Bool(bool),        // #t #f
-  Nil,               // nil
-  Symbol(String),    // symbol
-  Keyword(String),   // :keyword
+  Float64(f64),
+  Bool(bool),      // #t #f
+  Nil,             // nil
+  Symbol(String),  // symbol
+  Keyword(String), // :keyword
   StringLiteral(String),
   Character(char),
   List(Vec<ASTNode>),
   Quote(Box<ASTNode>),
-  Variable(String, Box<ASTNode>),
+  Variable(String, Box<ASTNode>, Option<String>), // def, with its docstring
   FuncDef(Vec<ASTNode>, Vec<ASTNode>),
   MacroDef(String),
   MacroTemplate(Uuid),
   MacroComma(Box<ASTNode>),
   MacroListExpand(Box<ASTNode>),
-}
\ No newline at end of file
+  If(Box<ASTNode>, Box<ASTNode>, Option<Box<ASTNode>>),
+  Let(Vec<(String, ASTNode)>, Vec<ASTNode>), // each binding sees the ones before it
+  Do(Vec<ASTNode>),
+  Set(String, Box<ASTNode>),          // set!
+  Cond(Vec<(ASTNode, Vec<ASTNode>)>), // `else` is read as a #t test
+  And(Vec<ASTNode>),
+  Or(Vec<ASTNode>),
+  Error, // a form that failed to parse; its diagnostic is reported separately
+}
+
+// A function's parameter list as written, e.g.
+// `(a &optional (b 1) &rest more &key (c 2) d)`. Parameters without a
+// default default to nil.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Params {
+  pub required: Vec<String>,
+  pub optional: Vec<(String, Option<ASTNode>)>,
+  pub rest: Option<String>,
+  pub keys: Vec<(String, Option<ASTNode>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum ParamSection {
+  Required,
+  Optional,
+  Rest,
+  Key,
+}
+
+impl Params {
+  // Fails with a message and the span of the offending parameter.
+  pub fn from_nodes(nodes: &[ASTNode]) -> Result<Params, (String, Span)> {
+    let mut params = Params::default();
+    let mut section = ParamSection::Required;
+    let mut names: Vec<&str> = Vec::new();
+    let rest_error = |span| {
+      Err((
+        "'&rest' must be followed by exactly one name".to_string(),
+        span,
+      ))
+    };
+
+    for node in nodes {
+      if let NodeType::Symbol(marker) = &node.node_type {
+        if marker.starts_with('&') {
+          let next = match marker.as_str() {
+            "&optional" => ParamSection::Optional,
+            "&rest" => ParamSection::Rest,
+            "&key" => ParamSection::Key,
+            _ => return Err((format!("Unknown parameter marker '{}'", marker), node.span)),
+          };
+          if next <= section {
+            return Err((format!("'{}' is out of place", marker), node.span));
+          }
+          if section == ParamSection::Rest && params.rest.is_none() {
+            return rest_error(node.span);
+          }
+          section = next;
+          continue;
+        }
+      }
+
+      let (name, default) = match &node.node_type {
+        NodeType::Symbol(name) => (name, None),
+        NodeType::List(items) => match items.as_slice() {
+          [ASTNode {
+            node_type: NodeType::Symbol(name),
+            ..
+          }, default] => (name, Some(default.clone())),
+          _ => return Err(("Expected a (name default) pair".to_string(), node.span)),
+        },
+        _ => return Err(("Expected a parameter name".to_string(), node.span)),
+      };
+      if names.contains(&name.as_str()) {
+        return Err((format!("Duplicate parameter '{}'", name), node.span));
+      }
+      names.push(name);
+
+      match section {
+        ParamSection::Required if default.is_some() => {
+          return Err((
+            "Only &optional and &key parameters take a default".to_string(),
+            node.span,
+          ))
+        }
+        ParamSection::Required => params.required.push(name.clone()),
+        ParamSection::Optional => params.optional.push((name.clone(), default)),
+        ParamSection::Rest if default.is_some() || params.rest.is_some() => {
+          return rest_error(node.span)
+        }
+        ParamSection::Rest => params.rest = Some(name.clone()),
+        ParamSection::Key => params.keys.push((name.clone(), default)),
+      }
+    }
+
+    match nodes.last() {
+      Some(last) if section == ParamSection::Rest && params.rest.is_none() => rest_error(last.span),
+      _ => Ok(params),
+    }
+  }
+
+  pub fn accepts(&self, count: usize) -> bool {
+    count >= self.required.len()
+      && (self.is_variadic() || count <= self.required.len() + self.optional.len())
+  }
+
+  // The argument counts accepted, as in "expects 1 to 3 arguments".
+  pub fn arity(&self) -> String {
+    let required = self.required.len();
+    if self.is_variadic() {
+      format!("at least {}", required)
+    } else if self.optional.is_empty() {
+      required.to_string()
+    } else {
+      format!("{} to {}", required, required + self.optional.len())
+    }
+  }
+
+  pub fn is_variadic(&self) -> bool {
+    self.rest.is_some() || !self.keys.is_empty()
+  }
+}
+
+// A node together with the source it was parsed from. Equality compares
+// structure only, so trees built by hand compare equal to parsed ones.
+#[derive(Debug, Clone)]
+pub struct ASTNode {
+  pub node_type: NodeType,
+  pub span: Span,
+}
+
+impl ASTNode {
+  pub fn new(node_type: NodeType, span: Span) -> Self {
+    ASTNode { node_type, span }
+  }
+
+  // A special form written back as the list it was parsed from, for quoting
+  // and printing. Other nodes give None.
+  pub fn special_form_items(&self) -> Option<Vec<ASTNode>> {
+    let symbol = |name: &str| ASTNode::new(NodeType::Symbol(name.to_string()), self.span);
+    let list = |items: Vec<ASTNode>| ASTNode::new(NodeType::List(items), self.span);
+    let tagged = |name: &str, items: &[ASTNode]| {
+      let mut tagged = vec![symbol(name)];
+      tagged.extend(items.iter().cloned());
+      tagged
+    };
+    let items = match &self.node_type {
+      NodeType::If(condition, then, otherwise) => {
+        let mut items = vec![symbol("if"), (**condition).clone(), (**then).clone()];
+        items.extend(otherwise.iter().map(|otherwise| (**otherwise).clone()));
+        items
+      }
+      NodeType::Let(bindings, body) => {
+        let bindings = bindings
+          .iter()
+          .map(|(name, value)| list(vec![symbol(name), value.clone()]))
+          .collect();
+        let mut items = vec![symbol("let"), list(bindings)];
+        items.extend(body.iter().cloned());
+        items
+      }
+      NodeType::Do(body) => tagged("do", body),
+      NodeType::Set(name, value) => vec![symbol("set!"), symbol(name), (**value).clone()],
+      NodeType::Cond(clauses) => {
+        let clauses = clauses
+          .iter()
+          .map(|(test, body)| {
+            let mut clause = vec![test.clone()];
+            clause.extend(body.iter().cloned());
+            list(clause)
+          })
+          .collect::<Vec<_>>();
+        tagged("cond", &clauses)
+      }
+      NodeType::And(items) => tagged("and", items),
+      NodeType::Or(items) => tagged("or", items),
+      _ => return None,
+    };
+    Some(items)
+  }
+
+  // Where a `def` sits below this top-level form, if anywhere. Definitions
+  // belong at the top level or directly in a top-level `do`.
+  pub fn misplaced_definition(&self) -> Option<Span> {
+    match &self.node_type {
+      NodeType::Variable(_, value, _) => value.definition_span(),
+      NodeType::Do(body) => body.iter().find_map(ASTNode::misplaced_definition),
+      _ => self.definition_span(),
+    }
+  }
+
+  // The node with each symbol in `renames` replaced wherever it's written,
+  // binders and quoted data included. Templates are renamed separately.
+  pub fn rename_symbols(&self, renames: &HashMap<String, String>) -> ASTNode {
+    let name = |name: &String| renames.get(name).unwrap_or(name).clone();
+    let one = |node: &ASTNode| Box::new(node.rename_symbols(renames));
+    let all = |nodes: &[ASTNode]| -> Vec<ASTNode> {
+      nodes
+        .iter()
+        .map(|node| node.rename_symbols(renames))
+        .collect()
+    };
+    let node_type = match &self.node_type {
+      NodeType::Program(forms) => NodeType::Program(all(forms)),
+      NodeType::Symbol(symbol) => NodeType::Symbol(name(symbol)),
+      NodeType::List(items) => NodeType::List(all(items)),
+      NodeType::Quote(inner) => NodeType::Quote(one(inner)),
+      NodeType::Variable(variable, value, doc) => {
+        NodeType::Variable(name(variable), one(value), doc.clone())
+      }
+      NodeType::FuncDef(params, body) => NodeType::FuncDef(all(params), all(body)),
+      NodeType::MacroComma(inner) => NodeType::MacroComma(one(inner)),
+      NodeType::MacroListExpand(inner) => NodeType::MacroListExpand(one(inner)),
+      NodeType::If(condition, then, otherwise) => {
+        NodeType::If(one(condition), one(then), otherwise.as_deref().map(one))
+      }
+      NodeType::Let(bindings, body) => NodeType::Let(
+        bindings
+          .iter()
+          .map(|(binding, value)| (name(binding), value.rename_symbols(renames)))
+          .collect(),
+        all(body),
+      ),
+      NodeType::Do(body) => NodeType::Do(all(body)),
+      NodeType::Set(variable, value) => NodeType::Set(name(variable), one(value)),
+      NodeType::Cond(clauses) => NodeType::Cond(
+        clauses
+          .iter()
+          .map(|(test, body)| (test.rename_symbols(renames), all(body)))
+          .collect(),
+      ),
+      NodeType::And(items) => NodeType::And(all(items)),
+      NodeType::Or(items) => NodeType::Or(all(items)),
+      other => other.clone(),
+    };
+    ASTNode::new(node_type, self.span)
+  }
+
+  // The first `def` in or below this node.
+  fn definition_span(&self) -> Option<Span> {
+    match &self.node_type {
+      NodeType::Variable(..) => Some(self.span),
+      NodeType::List(items) => items.iter().find_map(ASTNode::definition_span),
+      // Parameter defaults are `(name default)` lists among the params.
+      NodeType::FuncDef(params, body) => {
+        params.iter().chain(body).find_map(ASTNode::definition_span)
+      }
+      _ => self
+        .special_form_items()?
+        .iter()
+        .find_map(ASTNode::definition_span),
+    }
+  }
+}
+
+impl PartialEq for ASTNode {
+  fn eq(&self, other: &Self) -> bool {
+    self.node_type == other.node_type
+  }
+}
+
+// Nodes that don't come from source text get an empty span.
+impl From<NodeType> for ASTNode {
+  fn from(node_type: NodeType) -> Self {
+    ASTNode::new(node_type, Span::default())
+  }
+}
@@ -0,0 +1,422 @@
+use super::{ast::ASTNode, parser::Parser, parser_error::ParseError};
+use crate::scanner::{
+  scanner::Lexer,
+  span::{FileId, Position, Span},
+  token::{Token, TokenType},
+};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriviaKind {
+  Whitespace,
+  LineComment,  // ; ...
+  BlockComment, // #| ... |#
+  DatumComment, // #; and the form it comments out
+  Error,        // text the scanner rejected
+}
+
+// A concrete syntax tree: every byte of the source belongs to exactly one
+// leaf, so printing the tree gives back the source unchanged. Lists keep
+// their parens as tokens, and quotes and reader macros are grouped with the
+// form they apply to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CstNode {
+  Token {
+    token: Token,
+    text: String,
+  },
+  Trivia {
+    kind: TriviaKind,
+    text: String,
+    span: Span,
+  },
+  // '(' through ')'; the ')' is missing if the source ends first.
+  List {
+    children: Vec<CstNode>,
+    span: Span,
+  },
+  // A quote or reader macro, any trivia after it, then its form.
+  Prefixed {
+    children: Vec<CstNode>,
+    span: Span,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cst {
+  pub children: Vec<CstNode>,
+}
+
+impl CstNode {
+  pub fn span(&self) -> Span {
+    match self {
+      CstNode::Token { token, .. } => token.span,
+      CstNode::Trivia { span, .. }
+      | CstNode::List { span, .. }
+      | CstNode::Prefixed { span, .. } => *span,
+    }
+  }
+
+  pub fn is_trivia(&self) -> bool {
+    matches!(self, CstNode::Trivia { .. })
+  }
+
+  fn collect_tokens(&self, tokens: &mut Vec<Token>) {
+    match self {
+      CstNode::Token { token, .. } => tokens.push(token.clone()),
+      CstNode::Trivia { .. } => {}
+      CstNode::List { children, .. } | CstNode::Prefixed { children, .. } => {
+        for child in children {
+          child.collect_tokens(tokens);
+        }
+      }
+    }
+  }
+}
+
+impl Cst {
+  // Builds the tree from the source and the tokens scanned from it. Text
+  // between tokens becomes trivia.
+  pub fn new(source: &str, tokens: &[Token]) -> Self {
+    let file = tokens.first().map(|t| t.span.file).unwrap_or_default();
+    let mut builder = Builder {
+      source,
+      file,
+      position: Position::new(0, 1, 1),
+      frames: vec![Frame::new(FrameKind::Root, Position::new(0, 1, 1))],
+    };
+    for token in tokens {
+      builder.trivia(token.span.start.offset);
+      builder.token(token);
+    }
+    builder.trivia(source.len());
+    builder.finish()
+  }
+
+  // The tokens the tree was built from, for the parser.
+  pub fn tokens(&self) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for child in &self.children {
+      child.collect_tokens(&mut tokens);
+    }
+    tokens
+  }
+
+  pub fn to_ast(&self) -> Result<ASTNode, Vec<ParseError>> {
+    Parser::new(self.tokens()).parse()
+  }
+}
+
+impl fmt::Display for CstNode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CstNode::Token { text, .. } | CstNode::Trivia { text, .. } => write!(f, "{}", text),
+      CstNode::List { children, .. } | CstNode::Prefixed { children, .. } => {
+        children.iter().try_for_each(|child| write!(f, "{}", child))
+      }
+    }
+  }
+}
+
+impl fmt::Display for Cst {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self
+      .children
+      .iter()
+      .try_for_each(|child| write!(f, "{}", child))
+  }
+}
+
+#[derive(PartialEq)]
+enum FrameKind {
+  Root,
+  List,
+  Prefixed,
+}
+
+// A node still being filled in.
+struct Frame {
+  kind: FrameKind,
+  start: Position,
+  children: Vec<CstNode>,
+}
+
+impl Frame {
+  fn new(kind: FrameKind, start: Position) -> Self {
+    Frame {
+      kind,
+      start,
+      children: Vec::new(),
+    }
+  }
+}
+
+struct Builder<'a> {
+  source: &'a str,
+  file: FileId,
+  position: Position,
+  frames: Vec<Frame>,
+}
+
+impl Builder<'_> {
+  fn push(&mut self, node: CstNode) {
+    self.frames.last_mut().unwrap().children.push(node);
+  }
+
+  // Splits the text up to `end` into trivia.
+  fn trivia(&mut self, end: usize) {
+    while self.position.offset < end {
+      let rest = &self.source[self.position.offset..end];
+      let (kind, len) = trivia_piece(rest, self.file);
+      let text = &rest[..len];
+      let start = self.position;
+      self.position = advance(start, text);
+      self.push(CstNode::Trivia {
+        kind,
+        text: text.to_string(),
+        span: Span::new(self.file, start, self.position),
+      });
+    }
+  }
+
+  fn token(&mut self, token: &Token) {
+    let text = self.source[token.span.start.offset..token.span.end.offset].to_string();
+    self.position = token.span.end;
+    let node = CstNode::Token {
+      token: token.clone(),
+      text,
+    };
+    match token.token_type {
+      TokenType::LeftParen => {
+        let mut frame = Frame::new(FrameKind::List, token.span.start);
+        frame.children.push(node);
+        self.frames.push(frame);
+      }
+      TokenType::Quote | TokenType::ReaderMacro(_) => {
+        let mut frame = Frame::new(FrameKind::Prefixed, token.span.start);
+        frame.children.push(node);
+        self.frames.push(frame);
+      }
+      TokenType::RightParen if self.innermost_list().is_some() => {
+        // Forms missing after a quote inside the list end with it.
+        while self.frames.last().unwrap().kind != FrameKind::List {
+          self.close();
+        }
+        self.push(node);
+        self.close();
+        self.close_prefixes();
+      }
+      _ => {
+        self.push(node);
+        self.close_prefixes();
+      }
+    }
+  }
+
+  fn innermost_list(&self) -> Option<&Frame> {
+    self
+      .frames
+      .iter()
+      .rev()
+      .find(|frame| frame.kind == FrameKind::List)
+  }
+
+  // A finished form completes every quote waiting on it.
+  fn close_prefixes(&mut self) {
+    while self.frames.last().unwrap().kind == FrameKind::Prefixed {
+      self.close();
+    }
+  }
+
+  fn close(&mut self) {
+    let frame = self.frames.pop().unwrap();
+    let span = Span::new(self.file, frame.start, self.position);
+    let node = match frame.kind {
+      FrameKind::List => CstNode::List {
+        children: frame.children,
+        span,
+      },
+      _ => CstNode::Prefixed {
+        children: frame.children,
+        span,
+      },
+    };
+    self.push(node);
+  }
+
+  fn finish(mut self) -> Cst {
+    while self.frames.len() > 1 {
+      self.close();
+    }
+    Cst {
+      children: self.frames.pop().unwrap().children,
+    }
+  }
+}
+
+// The kind and byte length of the trivia at the start of `text`.
+fn trivia_piece(text: &str, file: FileId) -> (TriviaKind, usize) {
+  let first = text.chars().next().unwrap();
+  if first.is_whitespace() {
+    let len = text
+      .find(|c: char| !c.is_whitespace())
+      .unwrap_or(text.len());
+    (TriviaKind::Whitespace, len)
+  } else if first == ';' {
+    (
+      TriviaKind::LineComment,
+      text.find('\n').unwrap_or(text.len()),
+    )
+  } else if text.starts_with("#|") {
+    (TriviaKind::BlockComment, block_comment_len(text))
+  } else if let Some(rest) = text.strip_prefix("#;") {
+    (TriviaKind::DatumComment, 2 + datum_len(rest, file))
+  } else {
+    let len = text.find(char::is_whitespace).unwrap_or(text.len());
+    (TriviaKind::Error, len)
+  }
+}
+
+// Length of a `#| ... |#` comment, nesting included, or all of `text` if it
+// isn't closed.
+fn block_comment_len(text: &str) -> usize {
+  let mut depth = 0;
+  let mut i = 0;
+  while i < text.len() {
+    if text[i..].starts_with("#|") {
+      depth += 1;
+      i += 2;
+    } else if text[i..].starts_with("|#") {
+      depth -= 1;
+      i += 2;
+      if depth == 0 {
+        return i;
+      }
+    } else {
+      i += text[i..].chars().next().unwrap().len_utf8();
+    }
+  }
+  text.len()
+}
+
+// Length of the form commented out after a `#;`. Scanning what follows
+// drops any nested `#;` forms for us, so the commented form is the first
+// one among the remaining tokens.
+fn datum_len(text: &str, file: FileId) -> usize {
+  let tokens: Vec<Token> = Lexer::new(text.as_bytes(), file).flatten().collect();
+  match form_end(&tokens, 0) {
+    Some(end) => tokens[end - 1].span.end.offset,
+    None => text.len(),
+  }
+}
+
+// Index just past the form starting at `start`, if it's complete.
+fn form_end(tokens: &[Token], start: usize) -> Option<usize> {
+  match tokens.get(start)?.token_type {
+    TokenType::RightParen => None,
+    TokenType::Quote | TokenType::ReaderMacro(_) => form_end(tokens, start + 1),
+    TokenType::LeftParen => {
+      let mut index = start + 1;
+      loop {
+        match tokens.get(index)?.token_type {
+          TokenType::RightParen => return Some(index + 1),
+          _ => index = form_end(tokens, index)?,
+        }
+      }
+    }
+    _ => Some(start + 1),
+  }
+}
+
+fn advance(mut position: Position, text: &str) -> Position {
+  for c in text.chars() {
+    position.offset += c.len_utf8();
+    if c == '\n' {
+      position.line += 1;
+      position.column = 1;
+    } else {
+      position.column += 1;
+    }
+  }
+  position
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::scanner::scanner::read_str_scan;
+
+  fn cst(source: &str) -> Cst {
+    let tokens: Vec<Token> = Lexer::new(source.as_bytes(), FileId::default())
+      .flatten()
+      .collect();
+    Cst::new(source, &tokens)
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let sources = [
+      "; header\n(def x 1)  ; trailing\n",
+      "(print \"a\\tb\\\n   c\" #r#\"raw \"q\"\"# #\\space)\r\n",
+      "#| outer #| inner |# |#\n'(a `(b ,c ,@d)) #; #; e (f) g",
+      "(λ (café) #;(skip (me)) '日本)",
+      "(a #;) ) 12abc (unclosed \"str",
+      "",
+    ];
+    for source in sources {
+      assert_eq!(cst(source).to_string(), source);
+    }
+  }
+
+  #[test]
+  fn test_structure() {
+    let tree = cst("(a ' ; note\n b) ;end");
+    let [CstNode::List { children, span }, CstNode::Trivia { kind, text, .. }, CstNode::Trivia {
+      kind: TriviaKind::LineComment,
+      ..
+    }] = &tree.children[..]
+    else {
+      panic!("unexpected tree {:?}", tree);
+    };
+    assert_eq!((*kind, text.as_str()), (TriviaKind::Whitespace, " "));
+    assert_eq!((span.start.offset, span.end.offset), (0, 15));
+    assert_eq!(children.len(), 5);
+    let CstNode::Prefixed { children, .. } = &children[3] else {
+      panic!("expected a quoted form, got {:?}", children[3]);
+    };
+    let kinds: Vec<_> = children
+      .iter()
+      .map(|child| match child {
+        CstNode::Trivia { kind, .. } => Some(*kind),
+        _ => None,
+      })
+      .collect();
+    assert_eq!(
+      kinds,
+      [
+        None,
+        Some(TriviaKind::Whitespace),
+        Some(TriviaKind::LineComment),
+        Some(TriviaKind::Whitespace),
+        None
+      ]
+    );
+    assert_eq!(children[2].span().start, Position::new(5, 1, 6));
+    assert_eq!(children[4].span().start, Position::new(13, 2, 2));
+
+    let tree = cst("#; #; (a) 'b c");
+    let kinds: Vec<_> = tree.children.iter().map(CstNode::is_trivia).collect();
+    assert_eq!(kinds, [true, true, false]);
+    assert_eq!(tree.children[0].to_string(), "#; #; (a) 'b");
+  }
+
+  #[test]
+  fn test_lower_to_ast() {
+    let source = "(def f (fn (x) ; doubles\n  (* x 2))) #;(f 1) (f 21)";
+    let tokens = read_str_scan(source.to_string()).unwrap();
+    let expected = Parser::new(tokens.clone()).parse().unwrap();
+    let tree = Cst::new(source, &tokens);
+    assert_eq!(tree.tokens(), tokens);
+    assert_eq!(tree.to_ast().unwrap(), expected);
+  }
+}
@@ -1,3 +1,4 @@
+#[allow(dead_code)]
 pub fn test() {
   println!("Hello World!");
 }
@@ -1,4 +1,8 @@
 pub mod ast;
+// For tools; the tisp binary doesn't build concrete syntax trees yet.
+#[allow(dead_code)]
+pub mod cst;
 pub mod ir;
+#[allow(clippy::module_inception)]
 pub mod parser;
-mod parser_error;
+pub mod parser_error;
@@ -1,18 +1,39 @@
 use uuid::Uuid;
 
 use super::{
-  ast::ASTNode,
+  ast::{ASTNode, NodeType, Params},
   parser_error::{ParseError, ParseResult},
 };
-use crate::scanner::token::{Token, TokenType};
+use crate::scanner::{
+  span::Span,
+  token::{Token, TokenType},
+};
 use std::collections::HashMap;
 
+// A `macro` definition. Hygienic macros, marked `:hygienic` after the name,
+// rename the bindings their templates introduce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroDefinition {
+  pub params: Vec<ASTNode>,
+  pub body: Vec<ASTNode>,
+  pub hygienic: bool,
+}
+
+pub type MacroTable = HashMap<String, MacroDefinition>;
+pub type TemplateTable = HashMap<Uuid, Box<ASTNode>>;
+
+// Lists headed by these become their own nodes rather than calls.
+pub const SPECIAL_FORMS: [&str; 8] = ["quote", "if", "let", "do", "set!", "cond", "and", "or"];
+
 #[derive(Debug, Clone)]
 pub struct Parser {
   tokens: Vec<Token>,
   current: usize,
-  macros: HashMap<String, (Vec<ASTNode>, Vec<ASTNode>)>,
-  template: HashMap<Uuid, Box<ASTNode>>,
+  macros: MacroTable,
+  template: TemplateTable,
+  quasiquote_depth: usize,
+  // Inside quoted data, where `(if ...)` is just a list.
+  quoted: bool,
   errors: Vec<ParseError>,
 }
 
@@ -23,10 +44,29 @@ impl Parser {
       current: 0,
       macros: HashMap::new(),
       template: HashMap::new(),
+      quasiquote_depth: 0,
+      quoted: false,
       errors: Vec::new(),
     }
   }
 
+  // Starts from macros defined by an earlier parser, e.g. a previous REPL entry.
+  pub fn with_macros(tokens: Vec<Token>, macros: MacroTable, template: TemplateTable) -> Self {
+    Parser {
+      macros,
+      template,
+      ..Parser::new(tokens)
+    }
+  }
+
+  pub fn macros(&self) -> &MacroTable {
+    &self.macros
+  }
+
+  pub fn templates(&self) -> &TemplateTable {
+    &self.template
+  }
+
   fn is_at_end(&self) -> bool {
     self.current >= self.tokens.len()
   }
@@ -64,67 +104,121 @@ impl Parser {
   fn is_current_and_next_match(&self, token_pair: &(TokenType, TokenType)) -> bool {
     match (self.peek(), self.peek_next()) {
       (Some(current_token), Some(next_token)) => {
-        &current_token.token_type == &token_pair.0 && &next_token.token_type == &token_pair.1
+        current_token.token_type == token_pair.0 && next_token.token_type == token_pair.1
       }
       _ => false,
     }
   }
 
   pub fn parse(&mut self) -> Result<ASTNode, Vec<ParseError>> {
+    let (program, errors) = self.parse_recovering();
+    if errors.is_empty() {
+      Ok(program)
+    } else {
+      Err(errors)
+    }
+  }
+
+  // Parses every top-level form even if some fail. A form with an error
+  // becomes an Error node and parsing picks up again after its closing
+  // paren, so each mistake is reported once.
+  pub fn parse_recovering(&mut self) -> (ASTNode, Vec<ParseError>) {
+    let start = self.current_span();
     let mut nodes = Vec::new();
 
     while !self.is_at_end() {
-      if self.is_current_and_next_match(&(TokenType::LeftParen, TokenType::Macro)) {
+      let form_start = self.current;
+      let form_span = self.current_span();
+      let result = if self.is_current_match(&TokenType::RightParen) {
+        self.advance(); // Consume ')'
+        Err(ParseError::new("Unmatched ')'", form_span))
+      } else if self.is_current_and_next_match(&(TokenType::LeftParen, TokenType::Macro)) {
         self.advance(); // Consume '('
         self.advance(); // Consume 'macro'
-        match self.parse_symbol() {
-          Ok(name) => match self.parse_macro_definition(name) {
-            Ok(node) => nodes.push(node),
-            Err(err) => self.errors.push(err),
-          },
-          Err(err) => self.errors.push(err),
-        }
+        self
+          .parse_symbol()
+          .and_then(|name| self.parse_macro_definition(name, form_span))
       } else {
-        match self.parse_expression() {
-          Ok(node) => nodes.push(node),
-          Err(err) => self.errors.push(err),
+        self
+          .parse_expression()
+          .and_then(|node| match node.misplaced_definition() {
+            Some(span) => Err(ParseError::new(
+              "def is only allowed at the top level",
+              span,
+            )),
+            None => Ok(node),
+          })
+      };
+
+      match result {
+        Ok(node) => nodes.push(node),
+        Err(err) => {
+          self.errors.push(err);
+          self.synchronize(form_start);
+          nodes.push(ASTNode::new(NodeType::Error, self.span_from(form_span)));
         }
       }
     }
 
-    if self.errors.is_empty() {
-      Ok(ASTNode::Program(nodes))
-    } else {
-      Err(self.errors.clone())
+    let program = ASTNode::new(NodeType::Program(nodes), self.span_from(start));
+    (program, std::mem::take(&mut self.errors))
+  }
+
+  // Skips the rest of the top-level form that began at token `start`,
+  // through the ')' that brings the paren depth back to zero.
+  fn synchronize(&mut self, start: usize) {
+    if self.current == start {
+      self.advance();
+    }
+    let mut depth: i32 = self.tokens[start..self.current]
+      .iter()
+      .map(paren_depth_change)
+      .sum();
+    while depth > 0 && !self.is_at_end() {
+      depth += self.advance().map_or(0, paren_depth_change);
     }
   }
 
   fn parse_expression(&mut self) -> ParseResult<ASTNode> {
     if self.is_current_match(&TokenType::LeftParen) {
+      let start = self.current_span();
       self.advance(); // Consume '('
-      self.parse_list()
+      self.parse_list(start)
     } else {
       self.parse_atom()
     }
   }
 
-  fn parse_list(&mut self) -> ParseResult<ASTNode> {
-    let mut elements = Vec::new();
+  fn parse_list(&mut self, start: Span) -> ParseResult<ASTNode> {
+    let mut elements: Vec<ASTNode> = Vec::new();
 
     while !self.is_current_match(&TokenType::RightParen) && !self.is_at_end() {
-      if self.is_current_match(&TokenType::Var) {
-        self.advance(); // Consume 'def'
-        let name = self.parse_symbol()?;
-        let definition = self.parse_definition(name)?;
-        elements.push(definition);
+      let element_start = self.current_span();
+      // `(quote x)` is `'x` written out.
+      let head = elements.first().map(|head| &head.node_type);
+      if !self.quoted && matches!(head, Some(NodeType::Symbol(head)) if head == "quote") {
+        let quoted_expr = self.parse_quoted(true)?;
+        elements.push(quoted_expr);
+      } else if let Some(name) = self.quoted_reserved_word() {
+        self.advance();
+        elements.push(ASTNode::new(
+          NodeType::Symbol(name.to_string()),
+          element_start,
+        ));
+      } else if let Some(keyword) = self.definition_keyword() {
+        if !elements.is_empty() {
+          return Err(self.error(&format!("{} is only allowed at the top level", keyword)));
+        }
+        self.advance(); // Consume 'def' or 'defn'
+        return self.parse_definition(keyword, start);
+      } else if elements.is_empty() && self.is_current_match(&TokenType::Func) {
+        self.advance(); // Consume 'fn'
+        return self.parse_function_definition(start);
       } else if self.is_current_match(&TokenType::Keyword("quote".to_string())) {
         self.advance(); // Consume 'quote'
-        let quoted_expr = self.parse_expression()?;
-        elements.push(ASTNode::Quote(Box::new(quoted_expr)));
-      } else if self.is_current_match(&TokenType::ReaderMacro("`".to_string())) {
-        self.advance(); // Consume 'quote'
-        let quoted_expr = self.parse_macro_template()?;
-        elements.push(ASTNode::Quote(Box::new(quoted_expr)));
+        let quoted_expr = self.parse_quoted(true)?;
+        let span = self.span_from(element_start);
+        elements.push(ASTNode::new(NodeType::Quote(Box::new(quoted_expr)), span));
       } else {
         let ast = self.parse_expression()?;
         elements.push(ast);
@@ -132,41 +226,83 @@ impl Parser {
     }
 
     if !self.is_current_match(&TokenType::RightParen) {
-      return Err(self.error("Expected ')' at the end of list"));
+      return Err(
+        self
+          .error("Expected ')' at the end of list")
+          .with_note("list opened here", start),
+      );
     }
 
     self.advance(); // Consume ')'
-    if elements.len() == 1 && matches!(elements[0], ASTNode::Variable(..)) {
-      Ok(elements.pop().unwrap())
-    } else {
-      Ok(ASTNode::List(elements))
+    let span = self.span_from(start);
+    if let Some(NodeType::Symbol(name)) = elements.first().map(|head| &head.node_type) {
+      if !self.quoted && SPECIAL_FORMS.contains(&name.as_str()) {
+        return special_form(elements, span);
+      }
     }
+    Ok(ASTNode::new(NodeType::List(elements), span))
   }
 
-  fn parse_definition(&mut self, name: String) -> ParseResult<ASTNode> {
-    match self.peek() {
-      Some(Token {
-        token_type: TokenType::LeftParen,
-        ..
-      }) => {
-        self.advance(); // Consume '('
-        if self.is_current_match(&TokenType::Func) {
-          self.advance(); // Consume 'fn'
-          self
-            .parse_function_definition()
-            .map(|ast| ASTNode::Variable(name, Box::new(ast)))
-        } else {
-          Err(self.error("Expected 'fn' after '('"))
+  // In quoted data `def` and `fn` are plain symbols, e.g. for a macro to
+  // build code from.
+  fn quoted_reserved_word(&self) -> Option<&'static str> {
+    if !self.quoted {
+      return None;
+    }
+    match self.peek()?.token_type {
+      TokenType::Var => Some("def"),
+      TokenType::Defn => Some("defn"),
+      TokenType::Func => Some("fn"),
+      _ => None,
+    }
+  }
+
+  fn definition_keyword(&self) -> Option<&'static str> {
+    match self.peek()?.token_type {
+      TokenType::Var => Some("def"),
+      TokenType::Defn => Some("defn"),
+      _ => None,
+    }
+  }
+
+  // `(def name ["doc"] value)` or `(defn name (params) ["doc"] body...)`,
+  // from just after the keyword through the closing ')'.
+  fn parse_definition(&mut self, keyword: &str, start: Span) -> ParseResult<ASTNode> {
+    let name = self.parse_symbol()?;
+    let (value, doc) = if keyword == "defn" {
+      let mut function = self.parse_function_definition(start)?;
+      let doc = match &mut function.node_type {
+        NodeType::FuncDef(_, body) => docstring(body),
+        _ => None,
+      };
+      (function, doc)
+    } else {
+      let value = self.parse_expression()?;
+      let value = match value.node_type {
+        NodeType::StringLiteral(doc) if !self.is_current_match(&TokenType::RightParen) => {
+          (self.parse_expression()?, Some(doc))
         }
+        _ => (value, None),
+      };
+      if !self.is_current_match(&TokenType::RightParen) {
+        return Err(
+          self
+            .error(&format!("Expected ')' after the value of '{}'", name))
+            .with_note("definition opened here", start),
+        );
       }
-      _ => self
-        .parse_atom()
-        .map(|ast| ASTNode::Variable(name, Box::new(ast))),
-    }
+      self.advance(); // Consume ')'
+      value
+    };
+    Ok(ASTNode::new(
+      NodeType::Variable(name, Box::new(value), doc),
+      self.span_from(start),
+    ))
   }
 
-  fn parse_function_definition(&mut self) -> ParseResult<ASTNode> {
-    let params = self.parse_arg_list()?; // 解析函数的参数列表
+  fn parse_function_definition(&mut self, start: Span) -> ParseResult<ASTNode> {
+    let params = self.parse_arg_list(true)?; // 解析函数的参数列表
+    Params::from_nodes(&params).map_err(|(message, span)| ParseError::new(&message, span))?;
     let mut body = Vec::new();
 
     while !self.is_current_match(&TokenType::RightParen) && !self.is_at_end() {
@@ -174,15 +310,26 @@ impl Parser {
     }
 
     if !self.is_current_match(&TokenType::RightParen) {
-      return Err(self.error("Expected ')' to close function body"));
+      return Err(
+        self
+          .error("Expected ')' to close function body")
+          .with_note("function opened here", start),
+      );
     }
 
     self.advance(); // Consume ')'
-    Ok(ASTNode::FuncDef(params, body))
+    Ok(ASTNode::new(
+      NodeType::FuncDef(params, body),
+      self.span_from(start),
+    ))
   }
 
-  fn parse_macro_definition(&mut self, name: String) -> ParseResult<ASTNode> {
-    let params = self.parse_arg_list()?;
+  fn parse_macro_definition(&mut self, name: String, start: Span) -> ParseResult<ASTNode> {
+    let hygienic = self.is_current_match(&TokenType::Keyword("hygienic".to_string()));
+    if hygienic {
+      self.advance(); // Consume ':hygienic'
+    }
+    let params = self.parse_arg_list(false)?;
     let mut body = Vec::new();
 
     while !self.is_current_match(&TokenType::RightParen) && !self.is_at_end() {
@@ -190,55 +337,117 @@ impl Parser {
     }
 
     if !self.is_current_match(&TokenType::RightParen) {
-      return Err(self.error("Expected ')' to close macro body"));
+      return Err(
+        self
+          .error("Expected ')' to close macro body")
+          .with_note("macro opened here", start),
+      );
     }
 
     self.advance(); // Consume ')'
 
-    self.macros.entry(name.clone()).or_insert((params, body));
+    self.macros.insert(
+      name.clone(),
+      MacroDefinition {
+        params,
+        body,
+        hygienic,
+      },
+    );
 
-    Ok(ASTNode::MacroDef(name))
+    Ok(ASTNode::new(
+      NodeType::MacroDef(name),
+      self.span_from(start),
+    ))
   }
 
-  fn parse_macro_template(&mut self) -> ParseResult<ASTNode> {
-    let mut ast = self.parse_expression()?;
+  // The form after '`'. Its body is kept in the template table for the
+  // macro expander; unquotes inside it may refer to one level less.
+  fn parse_macro_template(&mut self, start: Span) -> ParseResult<ASTNode> {
+    self.quasiquote_depth += 1;
+    let result = self.parse_quoted(true);
+    self.quasiquote_depth -= 1;
+    let ast = result?;
     let uuid = Uuid::new_v4();
-    
+
     self.template.entry(uuid).or_insert(Box::new(ast));
 
-    Ok(ASTNode::MacroTemplate(uuid))
+    Ok(ASTNode::new(
+      NodeType::MacroTemplate(uuid),
+      self.span_from(start),
+    ))
   }
 
-  fn parse_reader_macro(&mut self, reader: String) -> ParseResult<ASTNode> {
+  fn parse_reader_macro(&mut self, reader: String, start: Span) -> ParseResult<ASTNode> {
     match reader.as_str() {
-      "," => Ok(self.parse_macro_comma()?),
-      "@" => Ok(self.parse_macro_expand()?),
-      _ => unimplemented!(),
+      "`" => self.parse_macro_template(start),
+      "," => self.parse_macro_comma(start),
+      ",@" => self.parse_macro_expand(start),
+      _ => Err(ParseError::new("Unknown reader macro", start)),
     }
   }
 
-  fn parse_macro_comma(&mut self) -> ParseResult<ASTNode> {
-    unimplemented!()
+  fn parse_macro_comma(&mut self, start: Span) -> ParseResult<ASTNode> {
+    let expr = self.parse_unquoted("Unquote ',' outside of quasiquote", start)?;
+    Ok(ASTNode::new(
+      NodeType::MacroComma(Box::new(expr)),
+      self.span_from(start),
+    ))
   }
 
-  fn parse_macro_expand(&mut self) -> ParseResult<ASTNode> {
-    unimplemented!()
+  fn parse_macro_expand(&mut self, start: Span) -> ParseResult<ASTNode> {
+    let expr = self.parse_unquoted("Unquote-splicing ',@' outside of quasiquote", start)?;
+    Ok(ASTNode::new(
+      NodeType::MacroListExpand(Box::new(expr)),
+      self.span_from(start),
+    ))
   }
 
-  fn parse_arg_list(&mut self) -> ParseResult<Vec<ASTNode>> {
+  // Each unquote steps out one quasiquote level, so `,,x` needs two.
+  fn parse_unquoted(&mut self, message: &str, start: Span) -> ParseResult<ASTNode> {
+    if self.quasiquote_depth == 0 {
+      return Err(ParseError::new(message, start));
+    }
+    self.quasiquote_depth -= 1;
+    let result = self.parse_quoted(false);
+    self.quasiquote_depth += 1;
+    result
+  }
+
+  // Parses an expression as data (`quoted`) or as code, e.g. under an unquote.
+  fn parse_quoted(&mut self, quoted: bool) -> ParseResult<ASTNode> {
+    let outer = std::mem::replace(&mut self.quoted, quoted);
+    let result = self.parse_expression();
+    self.quoted = outer;
+    result
+  }
+
+  // Functions, unlike macros, may give &optional and &key parameters a
+  // default as a (name default) pair.
+  fn parse_arg_list(&mut self, defaults: bool) -> ParseResult<Vec<ASTNode>> {
     if !self.is_current_match(&TokenType::LeftParen) {
       return Err(self.error("Expected '(' to start argument list"));
     }
+    let start = self.current_span();
     self.advance(); // Consume '('
     let mut params = Vec::new();
 
     while !self.is_current_match(&TokenType::RightParen) && !self.is_at_end() {
+      if defaults && self.is_current_match(&TokenType::LeftParen) {
+        params.push(self.parse_expression()?);
+        continue;
+      }
+      let param_start = self.current_span();
       let param = self.parse_symbol()?;
-      params.push(ASTNode::Symbol(param));
+      params.push(ASTNode::new(NodeType::Symbol(param), param_start));
     }
 
     if !self.is_current_match(&TokenType::RightParen) {
-      return Err(self.error("Expected ')' to close argument list"));
+      return Err(
+        self
+          .error("Expected ')' to close argument list")
+          .with_note("argument list opened here", start),
+      );
     }
 
     self.advance(); // Consume ')'
@@ -251,50 +460,215 @@ impl Parser {
       None => return Err(self.error("Unexpected end of input")),
     };
 
-    match token.token_type {
-      TokenType::Int32(value) => Ok(ASTNode::Int32(value)),
-      TokenType::Float32(value) => Ok(ASTNode::Float32(value)),
-      TokenType::Bool(value) => Ok(ASTNode::Bool(value)),
-      TokenType::Symbol(value) => Ok(ASTNode::Symbol(value)),
-      TokenType::Keyword(value) => Ok(ASTNode::Keyword(value)),
-      TokenType::String(value) => Ok(ASTNode::StringLiteral(value)),
-      TokenType::Character(value) => Ok(ASTNode::Character(value)),
+    let node_type = match token.token_type {
+      TokenType::Int32(value) => NodeType::Int32(value),
+      TokenType::Int64(value) => NodeType::Int64(value),
+      TokenType::Float32(value) => NodeType::Float32(value),
+      TokenType::Float64(value) => NodeType::Float64(value),
+      TokenType::Bool(value) => NodeType::Bool(value),
+      TokenType::Nil => NodeType::Nil,
+      TokenType::Symbol(value) => NodeType::Symbol(value),
+      TokenType::Keyword(value) => NodeType::Keyword(value),
+      TokenType::String(value) => NodeType::StringLiteral(value),
+      TokenType::Character(value) => NodeType::Character(value),
       TokenType::Quote => {
-        let expr = self.parse_expression()?;
-        Ok(ASTNode::Quote(Box::new(expr)))
+        let expr = self.parse_quoted(true)?;
+        NodeType::Quote(Box::new(expr))
       }
-      TokenType::ReaderMacro(value) => {
-        Ok(self.parse_reader_macro(value)?)
-      },
-      _ => Err(self.error("Unexpected token")),
-    }
+      TokenType::ReaderMacro(value) => return self.parse_reader_macro(value, token.span),
+      TokenType::RightParen => {
+        return Err(ParseError::new(
+          "Expected an expression, found ')'",
+          token.span,
+        ))
+      }
+      TokenType::Func => {
+        return Err(ParseError::new(
+          "fn is only allowed at the start of a form",
+          token.span,
+        ))
+      }
+      _ => return Err(ParseError::new("Unexpected token", token.span)),
+    };
+    Ok(ASTNode::new(node_type, self.span_from(token.span)))
   }
 
   fn parse_symbol(&mut self) -> ParseResult<String> {
+    let span = self.current_span();
     match self.advance() {
       Some(Token {
         token_type: TokenType::Symbol(name),
         ..
       }) => Ok(name.clone()),
-      _ => Err(self.error("Expected symbol")),
+      _ => Err(ParseError::new("Expected symbol", span)),
+    }
+  }
+
+  // The span of the next token, or an empty span just past the last one.
+  fn current_span(&self) -> Span {
+    match (self.peek(), self.tokens.last()) {
+      (Some(token), _) => token.span,
+      (None, Some(last)) => Span::new(last.span.file, last.span.end, last.span.end),
+      (None, None) => Span::default(),
+    }
+  }
+
+  // From `start` through the most recently consumed token.
+  fn span_from(&self, start: Span) -> Span {
+    match self.previous() {
+      Some(token) if token.span.end.offset >= start.start.offset => start.to(&token.span),
+      _ => start,
     }
   }
 
   fn error(&self, message: &str) -> ParseError {
-    if let Some(token) = self.peek() {
-      ParseError::new(message, token.line, token.column)
-    } else {
-      ParseError::new(message, 0, 0)
+    ParseError::new(message, self.current_span())
+  }
+}
+
+// Takes a leading string off a function body as its docstring, unless the
+// string is the whole body and so its value.
+fn docstring(body: &mut Vec<ASTNode>) -> Option<String> {
+  match body.first().map(|form| &form.node_type) {
+    Some(NodeType::StringLiteral(doc)) if body.len() > 1 => {
+      let doc = doc.clone();
+      body.remove(0);
+      Some(doc)
     }
+    _ => None,
   }
 }
 
+fn paren_depth_change(token: &Token) -> i32 {
+  match token.token_type {
+    TokenType::LeftParen => 1,
+    TokenType::RightParen => -1,
+    _ => 0,
+  }
+}
+
+// Builds a special form from its parsed list, checking its shape so errors
+// point at the form rather than surfacing when it runs.
+fn special_form(mut elements: Vec<ASTNode>, span: Span) -> ParseResult<ASTNode> {
+  let NodeType::Symbol(name) = elements.remove(0).node_type else {
+    unreachable!("special forms are headed by a symbol");
+  };
+  let args = elements;
+  let arity_error = |expected: &str| {
+    ParseError::new(
+      &format!("{} expects {}, got {}", name, expected, args.len()),
+      span,
+    )
+  };
+
+  let node_type = match name.as_str() {
+    "quote" => {
+      if args.len() != 1 {
+        return Err(arity_error("1 argument"));
+      }
+      NodeType::Quote(Box::new(args.into_iter().next().unwrap()))
+    }
+    "if" => {
+      if args.len() != 2 && args.len() != 3 {
+        return Err(arity_error("2 or 3 arguments"));
+      }
+      let mut args = args.into_iter().map(Box::new);
+      NodeType::If(args.next().unwrap(), args.next().unwrap(), args.next())
+    }
+    "let" => {
+      if args.is_empty() {
+        return Err(arity_error("a binding list and a body"));
+      }
+      let mut args = args.into_iter();
+      let bindings = args.next().unwrap();
+      let NodeType::List(bindings) = bindings.node_type else {
+        return Err(ParseError::new(
+          "let expects a list of (name value) bindings",
+          bindings.span,
+        ));
+      };
+      let bindings = bindings
+        .into_iter()
+        .map(|binding| match binding.node_type {
+          NodeType::List(pair) => match <[ASTNode; 2]>::try_from(pair) {
+            Ok(
+              [ASTNode {
+                node_type: NodeType::Symbol(name),
+                ..
+              }, value],
+            ) => Ok((name, value)),
+            _ => Err(ParseError::new(
+              "let binding must be a (name value) pair",
+              binding.span,
+            )),
+          },
+          _ => Err(ParseError::new(
+            "let binding must be a (name value) pair",
+            binding.span,
+          )),
+        })
+        .collect::<ParseResult<Vec<_>>>()?;
+      NodeType::Let(bindings, args.collect())
+    }
+    "do" => NodeType::Do(args),
+    "set!" => {
+      if args.len() != 2 {
+        return Err(arity_error("2 arguments"));
+      }
+      let mut args = args.into_iter();
+      let target = args.next().unwrap();
+      let NodeType::Symbol(target_name) = target.node_type else {
+        return Err(ParseError::new(
+          "set! expects a symbol to assign to",
+          target.span,
+        ));
+      };
+      NodeType::Set(target_name, Box::new(args.next().unwrap()))
+    }
+    "cond" => {
+      let count = args.len();
+      let mut clauses = Vec::new();
+      for (i, clause) in args.into_iter().enumerate() {
+        let clause_span = clause.span;
+        let mut items = match clause.node_type {
+          NodeType::List(items) if !items.is_empty() => items,
+          _ => {
+            return Err(ParseError::new(
+              "cond clause must be a list starting with a test",
+              clause_span,
+            ))
+          }
+        };
+        let mut test = items.remove(0);
+        if test.node_type == NodeType::Symbol("else".to_string()) {
+          if i + 1 != count {
+            return Err(ParseError::new(
+              "else must be the last cond clause",
+              clause_span,
+            ));
+          }
+          test.node_type = NodeType::Bool(true);
+        }
+        clauses.push((test, items));
+      }
+      NodeType::Cond(clauses)
+    }
+    "and" => NodeType::And(args),
+    "or" => NodeType::Or(args),
+    _ => unreachable!("not a special form: {}", name),
+  };
+  Ok(ASTNode::new(node_type, span))
+}
+
 #[cfg(test)]
 mod tests {
   use std::vec;
 
   use super::*;
-  use crate::scanner::scanner::read_str_scan;
+  use crate::scanner::{
+    scanner::{read_str_scan, read_str_scan_with_file},
+    span::{FileId, Position},
+  };
 
   fn parse_lisp_code(code: &str) -> Result<ASTNode, Vec<ParseError>> {
     let tokens = read_str_scan(code.to_string()).unwrap();
@@ -314,25 +688,32 @@ mod tests {
 
     assert!(result.is_ok());
 
-    let expected_ast = ASTNode::Program(vec![ASTNode::Variable(
+    let expected_ast: ASTNode = NodeType::Program(vec![NodeType::Variable(
       "add".to_string(),
-      Box::new(ASTNode::FuncDef(
-        vec![
-          ASTNode::Symbol("x".to_string()),
-          ASTNode::Symbol("y".to_string()),
-        ],
-        vec![ASTNode::List(vec![
-          ASTNode::Symbol("+".to_string()),
-          ASTNode::Symbol("x".to_string()),
-          ASTNode::Symbol("y".to_string()),
-        ])],
-      )),
-    )]);
+      Box::new(
+        NodeType::FuncDef(
+          vec![
+            NodeType::Symbol("x".to_string()).into(),
+            NodeType::Symbol("y".to_string()).into(),
+          ],
+          vec![NodeType::List(vec![
+            NodeType::Symbol("+".to_string()).into(),
+            NodeType::Symbol("x".to_string()).into(),
+            NodeType::Symbol("y".to_string()).into(),
+          ])
+          .into()],
+        )
+        .into(),
+      ),
+      None,
+    )
+    .into()])
+    .into();
 
     assert_eq!(result.unwrap(), expected_ast);
   }
 
-  #[test]
+  // #[test]
   // fn test_macro_definition() {
   //   let code = r#"
   //           (macro log (msg)
@@ -369,11 +750,13 @@ mod tests {
 
     assert!(result.is_ok());
 
-    let expected_ast = ASTNode::Program(vec![ASTNode::List(vec![
-      ASTNode::Symbol("add".to_string()),
-      ASTNode::Int32(10),
-      ASTNode::Int32(20),
-    ])]);
+    let expected_ast: ASTNode = NodeType::Program(vec![NodeType::List(vec![
+      NodeType::Symbol("add".to_string()).into(),
+      NodeType::Int32(10).into(),
+      NodeType::Int32(20).into(),
+    ])
+    .into()])
+    .into();
 
     assert_eq!(result.unwrap(), expected_ast);
   }
@@ -388,10 +771,12 @@ mod tests {
 
     assert!(result.is_ok());
 
-    let expected_ast = ASTNode::Program(vec![ASTNode::List(vec![
-      ASTNode::Symbol("log".to_string()),
-      ASTNode::StringLiteral("Hello, World!".to_string()),
-    ])]);
+    let expected_ast: ASTNode = NodeType::Program(vec![NodeType::List(vec![
+      NodeType::Symbol("log".to_string()).into(),
+      NodeType::StringLiteral("Hello, World!".to_string()).into(),
+    ])
+    .into()])
+    .into();
 
     assert_eq!(result.unwrap(), expected_ast);
   }
@@ -408,25 +793,33 @@ mod tests {
     println!("{:?}", result);
     assert!(result.is_ok());
 
-    let expected_ast = ASTNode::Program(vec![ASTNode::Variable(
+    let expected_ast: ASTNode = NodeType::Program(vec![NodeType::Variable(
       "calculate".to_string(),
-      Box::new(ASTNode::FuncDef(
-        vec![
-          ASTNode::Symbol("a".to_string()),
-          ASTNode::Symbol("b".to_string()),
-          ASTNode::Symbol("c".to_string()),
-        ],
-        vec![ASTNode::List(vec![
-          ASTNode::Symbol("+".to_string()),
-          ASTNode::Symbol("a".to_string()),
-          ASTNode::List(vec![
-            ASTNode::Symbol("*".to_string()),
-            ASTNode::Symbol("b".to_string()),
-            ASTNode::Symbol("c".to_string()),
-          ]),
-        ])],
-      )),
-    )]);
+      Box::new(
+        NodeType::FuncDef(
+          vec![
+            NodeType::Symbol("a".to_string()).into(),
+            NodeType::Symbol("b".to_string()).into(),
+            NodeType::Symbol("c".to_string()).into(),
+          ],
+          vec![NodeType::List(vec![
+            NodeType::Symbol("+".to_string()).into(),
+            NodeType::Symbol("a".to_string()).into(),
+            NodeType::List(vec![
+              NodeType::Symbol("*".to_string()).into(),
+              NodeType::Symbol("b".to_string()).into(),
+              NodeType::Symbol("c".to_string()).into(),
+            ])
+            .into(),
+          ])
+          .into()],
+        )
+        .into(),
+      ),
+      None,
+    )
+    .into()])
+    .into();
 
     assert_eq!(result.unwrap(), expected_ast);
   }
@@ -441,13 +834,28 @@ mod tests {
 
     assert!(result.is_ok());
 
-    let expected_ast = ASTNode::Program(vec![ASTNode::Quote(Box::new(ASTNode::List(vec![
-      ASTNode::Int32(1),
-      ASTNode::Int32(2),
-      ASTNode::Int32(3),
-    ])))]);
+    let expected_ast: ASTNode = NodeType::Program(vec![NodeType::Quote(Box::new(
+      NodeType::List(vec![
+        NodeType::Int32(1).into(),
+        NodeType::Int32(2).into(),
+        NodeType::Int32(3).into(),
+      ])
+      .into(),
+    ))
+    .into()])
+    .into();
 
     assert_eq!(result.unwrap(), expected_ast);
+    // Written out, as data even where it looks like code.
+    assert_eq!(parse_lisp_code("(quote (1 2 3))").unwrap(), expected_ast);
+    assert_eq!(
+      parse_lisp_code("(quote (if a))").unwrap(),
+      parse_lisp_code("'(if a)").unwrap()
+    );
+    assert_eq!(
+      parse_lisp_code("(quote a b)").unwrap_err()[0].message,
+      "quote expects 1 argument, got 2"
+    );
   }
 
   #[test]
@@ -469,4 +877,290 @@ mod tests {
       .message
       .contains("Expected ')' to close function body"));
   }
+
+  #[test]
+  fn test_spans() {
+    let code = "(def sq (fn (x)\n  (* x x)))\n'y";
+    let tokens = read_str_scan_with_file(code.to_string(), FileId(3)).unwrap();
+    let program = Parser::new(tokens).parse().unwrap();
+    let offsets = |node: &ASTNode| (node.span.start.offset, node.span.end.offset);
+
+    assert_eq!(offsets(&program), (0, 30));
+    assert_eq!(program.span.file, FileId(3));
+    let NodeType::Program(forms) = &program.node_type else {
+      panic!("expected a program");
+    };
+
+    let definition = &forms[0];
+    assert_eq!(offsets(definition), (0, 27));
+    assert_eq!(definition.span.end, Position::new(27, 2, 12));
+    let NodeType::Variable(_, function, _) = &definition.node_type else {
+      panic!("expected a definition");
+    };
+    assert_eq!(offsets(function), (8, 26));
+    let NodeType::FuncDef(params, body) = &function.node_type else {
+      panic!("expected a function");
+    };
+    assert_eq!(offsets(&params[0]), (13, 14));
+    assert_eq!(body[0].span.start, Position::new(18, 2, 3));
+    assert_eq!(body[0].span.end, Position::new(25, 2, 10));
+
+    let quoted = &forms[1];
+    assert_eq!(quoted.span.start, Position::new(28, 3, 1));
+    assert_eq!(quoted.span.end, Position::new(30, 3, 3));
+  }
+
+  #[test]
+  fn test_error_recovery() {
+    let code = ") (+ 1 2) (def (fn)) (list 3 {) ) (def x) (print 4) (f";
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    let (program, errors) = Parser::new(tokens).parse_recovering();
+
+    let found: Vec<_> = errors
+      .iter()
+      .map(|error| (error.message.as_str(), error.span.start.offset))
+      .collect();
+    assert_eq!(
+      found,
+      [
+        ("Unmatched ')'", 0),
+        ("Expected symbol", 15),
+        ("Unexpected token", 29),
+        ("Unmatched ')'", 32),
+        ("Expected an expression, found ')'", 40),
+        ("Expected ')' at the end of list", 54),
+      ]
+    );
+    assert_eq!(errors[5].notes[0].span.start.offset, 52);
+
+    let NodeType::Program(forms) = &program.node_type else {
+      panic!("expected a program");
+    };
+    let errors_at: Vec<_> = forms
+      .iter()
+      .map(|form| form.node_type == NodeType::Error)
+      .collect();
+    assert_eq!(
+      errors_at,
+      [true, false, true, true, true, true, false, true]
+    );
+    assert_eq!(
+      (forms[2].span.start.offset, forms[2].span.end.offset),
+      (10, 20)
+    );
+  }
+
+  #[test]
+  fn test_anonymous_functions_and_params() {
+    let program =
+      parse_lisp_code("((fn (x) x) 1) (fn (a &optional (b 1) &rest r &key k) a)").unwrap();
+    let NodeType::Program(forms) = program.node_type else {
+      panic!("expected a program");
+    };
+    let NodeType::List(call) = &forms[0].node_type else {
+      panic!("expected a call, got {:?}", forms[0].node_type);
+    };
+    assert!(matches!(call[0].node_type, NodeType::FuncDef(..)));
+    let NodeType::FuncDef(params, _) = &forms[1].node_type else {
+      panic!("expected a function, got {:?}", forms[1].node_type);
+    };
+    let params = Params::from_nodes(params).unwrap();
+    assert_eq!(params.required, ["a"]);
+    assert_eq!(params.optional[0].0, "b");
+    assert_eq!(params.optional[0].1, Some(NodeType::Int32(1).into()));
+    assert_eq!(params.rest.as_deref(), Some("r"));
+    assert_eq!(params.keys, [("k".to_string(), None)]);
+    assert_eq!(params.arity(), "at least 1");
+
+    let error = |code: &str| parse_lisp_code(code).unwrap_err()[0].message.clone();
+    assert_eq!(
+      error("(list fn)"),
+      "fn is only allowed at the start of a form"
+    );
+    assert_eq!(
+      error("(fn (a &rest) a)"),
+      "'&rest' must be followed by exactly one name"
+    );
+    assert_eq!(
+      error("(fn (&rest a b) a)"),
+      "'&rest' must be followed by exactly one name"
+    );
+    assert_eq!(
+      error("(fn (&key a &optional b) a)"),
+      "'&optional' is out of place"
+    );
+    assert_eq!(
+      error("(fn ((a 1)) a)"),
+      "Only &optional and &key parameters take a default"
+    );
+    assert_eq!(
+      error("(fn (&maybe a) a)"),
+      "Unknown parameter marker '&maybe'"
+    );
+    assert_eq!(error("(fn (a &optional a) a)"), "Duplicate parameter 'a'");
+  }
+
+  #[test]
+  fn test_definitions() {
+    let code = r#"
+            (def x (+ 1 2))
+            (def greeting "Says hello." "hello")
+            (def empty "")
+            (defn inc (n) "Adds one." (+ n 1))
+            (do (defn id (x) x))
+        "#;
+    let program = parse_lisp_code(code).unwrap();
+    let NodeType::Program(forms) = program.node_type else {
+      panic!("expected a program");
+    };
+    let definition = |form: &ASTNode| match &form.node_type {
+      NodeType::Variable(name, value, doc) => (name.clone(), value.node_type.clone(), doc.clone()),
+      other => panic!("expected a definition, got {:?}", other),
+    };
+    assert!(matches!(definition(&forms[0]).1, NodeType::List(_)));
+    assert_eq!(
+      definition(&forms[1]),
+      (
+        "greeting".to_string(),
+        NodeType::StringLiteral("hello".to_string()),
+        Some("Says hello.".to_string())
+      )
+    );
+    assert_eq!(definition(&forms[2]).2, None);
+    let (name, function, doc) = definition(&forms[3]);
+    assert_eq!((name.as_str(), doc.as_deref()), ("inc", Some("Adds one.")));
+    assert!(
+      matches!(function, NodeType::FuncDef(params, body) if params.len() == 1 && body.len() == 1)
+    );
+
+    let error = |code: &str| parse_lisp_code(code).unwrap_err()[0].message.clone();
+    assert_eq!(
+      error("(f (def x 1))"),
+      "def is only allowed at the top level"
+    );
+    assert_eq!(
+      error("(def f (fn () (defn g () 1)))"),
+      "def is only allowed at the top level"
+    );
+    assert_eq!(
+      error("(defn f (&optional (a (def y 1))) a)"),
+      "def is only allowed at the top level"
+    );
+    assert_eq!(
+      error("(list 1 defn)"),
+      "defn is only allowed at the top level"
+    );
+    assert_eq!(error("(def x 1 2)"), "Expected ')' after the value of 'x'");
+
+    // A quoted `def` is only data.
+    assert!(parse_lisp_code("'(def x 1)").is_ok());
+  }
+
+  #[test]
+  fn test_special_forms() {
+    let program =
+      parse_lisp_code("(if a 1) (let ((x 1) (y x)) y) (cond (a 1) (else 2)) (or)").unwrap();
+    let NodeType::Program(forms) = program.node_type else {
+      panic!("expected a program");
+    };
+    assert!(matches!(forms[0].node_type, NodeType::If(_, _, None)));
+    let NodeType::Let(bindings, body) = &forms[1].node_type else {
+      panic!("expected let, got {:?}", forms[1].node_type);
+    };
+    assert_eq!(bindings[1].0, "y");
+    assert_eq!(body.len(), 1);
+    let NodeType::Cond(clauses) = &forms[2].node_type else {
+      panic!("expected cond, got {:?}", forms[2].node_type);
+    };
+    assert_eq!(clauses[1].0.node_type, NodeType::Bool(true));
+    assert_eq!(forms[3].node_type, NodeType::Or(vec![]));
+
+    // Quoted data is left alone, but unquoted code is not.
+    let program = parse_lisp_code("'(if) `(let ,(and))").unwrap();
+    let NodeType::Program(forms) = program.node_type else {
+      panic!("expected a program");
+    };
+    assert!(
+      matches!(&forms[0].node_type, NodeType::Quote(list) if matches!(list.node_type, NodeType::List(_)))
+    );
+
+    let error = |code: &str| parse_lisp_code(code).unwrap_err()[0].message.clone();
+    assert_eq!(error("(if 1)"), "if expects 2 or 3 arguments, got 1");
+    assert_eq!(
+      error("(let (x 1) x)"),
+      "let binding must be a (name value) pair"
+    );
+    assert_eq!(error("(set! 1 2)"), "set! expects a symbol to assign to");
+    assert_eq!(error("(set! x)"), "set! expects 2 arguments, got 1");
+    assert_eq!(
+      error("(cond (else 1) (a 2))"),
+      "else must be the last cond clause"
+    );
+  }
+
+  #[test]
+  fn test_quasiquote() {
+    let code = "`(a ,b ,@(c) `(d ,,e))";
+    let tokens = read_str_scan(code.to_string()).unwrap();
+    let mut parser = Parser::new(tokens);
+    let program = parser.parse().unwrap();
+    let template = |node: &ASTNode| match &node.node_type {
+      NodeType::MacroTemplate(uuid) => parser.templates()[uuid].clone(),
+      other => panic!("expected a template, got {:?}", other),
+    };
+
+    let NodeType::Program(forms) = &program.node_type else {
+      panic!("expected a program");
+    };
+    assert_eq!(
+      (forms[0].span.start.offset, forms[0].span.end.offset),
+      (0, 22)
+    );
+    let NodeType::List(outer) = template(&forms[0]).node_type else {
+      panic!("expected a list template");
+    };
+    assert_eq!(outer[0], NodeType::Symbol("a".to_string()).into());
+    assert_eq!(
+      outer[1],
+      NodeType::MacroComma(Box::new(NodeType::Symbol("b".to_string()).into())).into()
+    );
+    assert_eq!(
+      outer[2],
+      NodeType::MacroListExpand(Box::new(
+        NodeType::List(vec![NodeType::Symbol("c".to_string()).into()]).into()
+      ))
+      .into()
+    );
+    assert_eq!(outer[2].span.start.offset, 7);
+
+    // The inner template keeps its own unquotes; `,,e` reaches the outer one.
+    assert_eq!(
+      *template(&outer[3]),
+      NodeType::List(vec![
+        NodeType::Symbol("d".to_string()).into(),
+        NodeType::MacroComma(Box::new(
+          NodeType::MacroComma(Box::new(NodeType::Symbol("e".to_string()).into())).into()
+        ))
+        .into(),
+      ])
+      .into()
+    );
+  }
+
+  #[test]
+  fn test_unquote_outside_quasiquote() {
+    let errors = parse_lisp_code(",a (list ,@b) `(c ,,d) `(e ,f)").unwrap_err();
+    let found: Vec<_> = errors
+      .iter()
+      .map(|error| (error.message.as_str(), error.span.start.offset))
+      .collect();
+    assert_eq!(
+      found,
+      [
+        ("Unquote ',' outside of quasiquote", 0),
+        ("Unquote-splicing ',@' outside of quasiquote", 9),
+        ("Unquote ',' outside of quasiquote", 19),
+      ]
+    );
+  }
 }
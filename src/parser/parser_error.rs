@@ -1,18 +1,42 @@
-#[derive(Debug, Clone)]
+use crate::scanner::span::Span;
+use std::fmt;
+
+// Secondary location shown alongside an error, e.g. where an unclosed list began.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+  pub message: String,
+  pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ParseError {
   pub message: String,
-  pub line: u32,
-  pub column: u32,
+  pub span: Span,
+  pub notes: Vec<Note>,
 }
 
 impl ParseError {
-  pub fn new(message: &str, line: u32, column: u32) -> Self {
+  pub fn new(message: &str, span: Span) -> Self {
     ParseError {
       message: message.to_string(),
-      line,
-      column,
+      span,
+      notes: Vec::new(),
     }
   }
+
+  pub fn with_note(mut self, message: &str, span: Span) -> Self {
+    self.notes.push(Note {
+      message: message.to_string(),
+      span,
+    });
+    self
+  }
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
@@ -1 +1,230 @@
+use crate::diagnostic::diagnostic::Diagnostic;
+use crate::expander::expander::Expander;
+use crate::interpreter::interpreter::Interpreter;
+use crate::parser::parser::{MacroTable, Parser, TemplateTable};
+use crate::scanner::{
+  scanner::{read_str_scan, Lexer},
+  span::FileId,
+  token::TokenType,
+};
+use std::io::{self, BufRead, StdinLock, Stdout, Write};
 
+const PROMPT: &str = "tisp> ";
+const CONTINUATION_PROMPT: &str = "...   ";
+
+// Reads forms until their parentheses balance, then scans, parses and
+// evaluates them. `def` bindings live in the interpreter's globals and
+// macro definitions are handed from one entry's parser to the next.
+pub struct Repl<R: BufRead, W: Write> {
+  input: R,
+  interpreter: Interpreter<W>,
+  macros: MacroTable,
+  template: TemplateTable,
+}
+
+impl Repl<StdinLock<'static>, Stdout> {
+  pub fn new() -> Self {
+    Repl::with_io(io::stdin().lock(), io::stdout())
+  }
+}
+
+impl Default for Repl<StdinLock<'static>, Stdout> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<R: BufRead, W: Write> Repl<R, W> {
+  pub fn with_io(input: R, output: W) -> Self {
+    Repl {
+      input,
+      interpreter: Interpreter::with_output(output),
+      macros: MacroTable::new(),
+      template: TemplateTable::new(),
+    }
+  }
+
+  #[allow(dead_code)]
+  pub fn output(&self) -> &W {
+    self.interpreter.output()
+  }
+
+  pub fn run(&mut self) -> io::Result<()> {
+    let mut buffer = String::new();
+    loop {
+      let prompt = if buffer.is_empty() {
+        PROMPT
+      } else {
+        CONTINUATION_PROMPT
+      };
+      write!(self.out(), "{}", prompt)?;
+      self.out().flush()?;
+
+      let mut line = String::new();
+      if self.input.read_line(&mut line)? == 0 {
+        writeln!(self.out())?;
+        if !buffer.trim().is_empty() {
+          writeln!(self.out(), "error: unexpected end of input")?;
+        }
+        return Ok(());
+      }
+
+      if buffer.is_empty() && matches!(line.trim(), ":quit" | ":q") {
+        return Ok(());
+      }
+      if let Some(name) = line
+        .trim()
+        .strip_prefix(":doc ")
+        .filter(|_| buffer.is_empty())
+      {
+        self.print_doc(name.trim())?;
+        continue;
+      }
+
+      buffer.push_str(&line);
+      if buffer.trim().is_empty() {
+        buffer.clear();
+        continue;
+      }
+      if !is_complete(&buffer) {
+        continue;
+      }
+
+      let source = std::mem::take(&mut buffer);
+      self.eval(source)?;
+    }
+  }
+
+  fn eval(&mut self, source: String) -> io::Result<()> {
+    let tokens = match read_str_scan(source) {
+      Ok(tokens) => tokens,
+      Err(errors) => {
+        for error in errors {
+          writeln!(self.out(), "error: {}", Diagnostic::from(error))?;
+        }
+        return Ok(());
+      }
+    };
+
+    let mut parser = Parser::with_macros(tokens, self.macros.clone(), self.template.clone());
+    let ast = match parser.parse() {
+      Ok(ast) => ast,
+      Err(errors) => {
+        for error in errors {
+          writeln!(self.out(), "error: {}", Diagnostic::from(error))?;
+        }
+        return Ok(());
+      }
+    };
+    let mut expander = Expander::new(parser.macros().clone(), parser.templates().clone());
+    let expanded = expander.expand_program(&ast);
+    // Imported macros stay available to later entries too.
+    self.macros = expander.macros().clone();
+    self.template = expander.templates().clone();
+    let ast = match expanded {
+      Ok(ast) => ast,
+      Err(errors) => {
+        for error in errors {
+          writeln!(self.out(), "error: {}", Diagnostic::from(error))?;
+        }
+        return Ok(());
+      }
+    };
+
+    *self.interpreter.templates_mut() = self.template.clone();
+    match self.interpreter.eval_program(&ast) {
+      Ok(value) => writeln!(self.out(), "{}", value),
+      Err(error) => writeln!(self.out(), "error: {}", error),
+    }
+  }
+
+  fn print_doc(&mut self, name: &str) -> io::Result<()> {
+    match self.interpreter.doc(name).map(str::to_string) {
+      Some(doc) => writeln!(self.out(), "{}", doc),
+      None => writeln!(self.out(), "no documentation for '{}'", name),
+    }
+  }
+
+  fn out(&mut self) -> &mut W {
+    self.interpreter.output_mut()
+  }
+}
+
+// True once every '(' is closed and no token, block comment or `#;` form is
+// left open; extra ')' count as complete so the parser can report them.
+pub fn is_complete(source: &str) -> bool {
+  let mut lexer = Lexer::incremental(FileId::default());
+  lexer.push_str(source);
+  let mut depth = 0i32;
+  for token in lexer.by_ref().flatten() {
+    match token.token_type {
+      TokenType::LeftParen => depth += 1,
+      TokenType::RightParen => depth -= 1,
+      _ => {}
+    }
+  }
+  !lexer.is_waiting() && depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run_session(input: &str) -> String {
+    let mut repl = Repl::with_io(input.as_bytes(), Vec::new());
+    repl.run().unwrap();
+    String::from_utf8(repl.output().clone()).unwrap()
+  }
+
+  #[test]
+  fn test_is_complete() {
+    assert!(is_complete("(+ 1 2)"));
+    assert!(!is_complete("(def f (fn (x)"));
+    assert!(!is_complete(r#"(print "unclosed ) paren"#));
+    assert!(is_complete(r#"(print ")(")"#));
+    assert!(is_complete(r"(list #\( 1)"));
+    assert!(is_complete(")"));
+    assert!(is_complete("(+ 1 #| ( |# 2) ; (\n"));
+    assert!(!is_complete("(+ 1 2) #| #| |#"));
+    assert!(is_complete(r##"(print #r#"(\"x"#)"##));
+  }
+
+  #[test]
+  fn test_bindings_persist_across_entries() {
+    let output = run_session("(def x 40)\n(def add2 (fn (n)\n  (+ n 2)))\n(add2 x)\n");
+    assert_eq!(output, "tisp> 40\ntisp> ...   <fn>\ntisp> 42\ntisp> \n");
+  }
+
+  #[test]
+  fn test_errors_do_not_end_the_session() {
+    let output = run_session("(+ 1 missing)\n(def)\n(+ 1 2)\n:quit\n(+ 3 4)\n");
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines[0], "tisp> error: Undefined symbol 'missing'");
+    assert!(lines[1].starts_with("tisp> error: Expected symbol"));
+    assert_eq!(lines[2], "tisp> 3");
+    assert_eq!(lines[3..], ["tisp> "]);
+
+    let output = run_session("(+ 1\n");
+    assert!(output.ends_with("error: unexpected end of input\n"));
+  }
+
+  #[test]
+  fn test_doc_command() {
+    let output = run_session("(defn inc (n) \"Adds one to n.\" (+ n 1))\n:doc inc\n:doc dec\n");
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines[1], "tisp> Adds one to n.");
+    assert_eq!(lines[2], "tisp> no documentation for 'dec'");
+  }
+
+  #[test]
+  fn test_macros_persist_across_entries() {
+    let mut repl = Repl::with_io("(macro twice (x) `(list ,x ,x))\n".as_bytes(), Vec::new());
+    repl.run().unwrap();
+    assert!(repl.macros.contains_key("twice"));
+
+    repl.input = "(def y 1)\n(twice (+ y 1))\n".as_bytes();
+    repl.run().unwrap();
+    assert!(repl.macros.contains_key("twice"));
+    assert!(String::from_utf8_lossy(repl.output()).contains("(2 2)\n"));
+  }
+}
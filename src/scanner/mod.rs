@@ -1,2 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod scanner;
+pub mod scanner_error;
+pub mod span;
 pub mod token;
@@ -1,240 +1,870 @@
-use super::token::{Token, TokenType};
-use std::fs;
+use super::{
+  scanner_error::ScanError,
+  span::{ColumnMode, FileId, Position, Span},
+  token::{Token, TokenType},
+};
+use std::{
+  collections::VecDeque,
+  fs::File,
+  io::{self, BufRead, BufReader},
+  num::IntErrorKind,
+};
 
-pub fn read_file_scan(file_name: String) -> Result<Vec<Token>, Vec<String>> {
-  fs::read_to_string(file_name)
-    .map_err(|e| vec![e.to_string()])
-    .and_then(read_str_scan)
+// The outer result reports a file that couldn't be opened, the inner one the
+// problems found in its text.
+#[allow(dead_code)]
+pub fn read_file_scan(file_name: String) -> io::Result<Result<Vec<Token>, Vec<ScanError>>> {
+  let file = File::open(file_name)?;
+  Ok(collect(Lexer::new(BufReader::new(file), FileId::default())))
 }
 
-pub fn read_str_scan(text: String) -> Result<Vec<Token>, Vec<String>> {
+pub fn read_str_scan(text: String) -> Result<Vec<Token>, Vec<ScanError>> {
+  read_str_scan_with_file(text, FileId::default())
+}
+
+pub fn read_str_scan_with_file(text: String, file: FileId) -> Result<Vec<Token>, Vec<ScanError>> {
+  collect(Lexer::new(text.as_bytes(), file))
+}
+
+fn collect<R: BufRead>(lexer: Lexer<R>) -> Result<Vec<Token>, Vec<ScanError>> {
   let mut tokens = Vec::new();
   let mut errors = Vec::new();
-  let mut chars = text.chars().peekable();
-  let mut line = 1;
-  let mut column = 0;
+  for item in lexer {
+    match item {
+      Ok(token) => tokens.push(token),
+      Err(error) => errors.push(error),
+    }
+  }
+  if errors.is_empty() {
+    Ok(tokens)
+  } else {
+    Err(errors)
+  }
+}
 
-  while let Some(c) = chars.next() {
-    column += 1;
-    let token_type = match c {
-      '(' => TokenType::LeftParen,
-      ')' => TokenType::RightParen,
-      '{' => TokenType::LeftBrace,
-      '}' => TokenType::RightBrace,
-      '/' => TokenType::Symbol("/".to_string()),
-      '*' => TokenType::Symbol("*".to_string()),
-      '!' => {
-        if chars.peek() == Some(&'=') {
-          chars.next();
-          TokenType::BangEqual
-        } else {
-          TokenType::Bang
-        }
+// Scans tokens lazily from a reader, holding on to no more text than the
+// token being read and the rest of its line (or, after `#;`, the form being
+// skipped).
+//
+// A lexer made with `incremental` is fed with `push_str` instead. When the
+// text runs out partway through a token or a commented-out form, `next`
+// returns None and `is_waiting` is true; the lexer rewinds to where that
+// token started and carries on from there once more text is pushed. `finish`
+// marks the end of the input, after which running out is an error as usual.
+pub struct Lexer<R> {
+  chars: Cursor<R>,
+  file: FileId,
+  lexemes: VecDeque<Lexeme>,
+  pending: VecDeque<Result<Token, ScanError>>,
+  waiting: bool,
+}
+
+enum Lexeme {
+  Token(Token),
+  Error(ScanError),
+  DatumComment(Span),
+}
+
+// How skipping the form after a `#;` ended.
+enum Skip {
+  Done,
+  Missing,
+  Unclosed,
+  Starved,
+}
+
+impl<R: BufRead> Lexer<R> {
+  pub fn new(reader: R, file: FileId) -> Self {
+    Lexer {
+      chars: Cursor::new(reader),
+      file,
+      lexemes: VecDeque::new(),
+      pending: VecDeque::new(),
+      waiting: false,
+    }
+  }
+
+  pub fn push_str(&mut self, text: &str) {
+    self.chars.push_str(text);
+  }
+
+  #[allow(dead_code)]
+  pub fn with_columns(mut self, columns: ColumnMode) -> Self {
+    self.chars.columns = columns;
+    self
+  }
+
+  #[allow(dead_code)]
+  pub fn finish(&mut self) {
+    self.chars.closed = true;
+  }
+
+  // True when the last call to `next` stopped in the middle of a token or a
+  // commented-out form for lack of input.
+  pub fn is_waiting(&self) -> bool {
+    self.waiting
+  }
+
+  fn next_lexeme(&mut self) -> Option<Lexeme> {
+    if self.lexemes.is_empty() && !self.scan_lexemes() {
+      return None;
+    }
+    self.lexemes.pop_front()
+  }
+
+  // Scans until something is queued. Returns false at the end of the input,
+  // or when it ran out mid-token, in which case the token is rewound.
+  fn scan_lexemes(&mut self) -> bool {
+    loop {
+      self.chars.starved = false;
+      let start = self.chars.position;
+      let Some(c) = self.chars.next() else {
+        return false;
+      };
+      let mut errors = Vec::new();
+      let lexeme = lex(&mut self.chars, self.file, start, c, &mut errors);
+      if self.chars.starved {
+        self.chars.rewind(start);
+        self.waiting = true;
+        return false;
       }
-      '=' => {
-        if chars.peek() == Some(&'=') {
-          chars.next();
-          TokenType::Symbol("==".to_string())
-        } else {
-          TokenType::Symbol("=".to_string())
-        }
+      self.lexemes.extend(errors.into_iter().map(Lexeme::Error));
+      self.lexemes.extend(lexeme);
+      if !self.lexemes.is_empty() {
+        return true;
       }
-      '>' => {
-        if chars.peek() == Some(&'=') {
-          chars.next();
-          TokenType::Symbol(">=".to_string())
-        } else {
-          TokenType::Symbol(">".to_string())
-        }
+    }
+  }
+
+  // Drops the form following a `#;`. Several `#;` in a row each drop one
+  // more form; a `#;` inside a dropped form goes with it. Returns false if
+  // the input ran out first.
+  fn skip_datum(&mut self, span: Span) -> bool {
+    let mut skipped = Vec::new();
+    let skip = self.skip_form(&mut skipped);
+    match skip {
+      Skip::Starved => {
+        self.lexemes.clear();
+        self.chars.rewind(span.start);
+        self.waiting = true;
+        return false;
       }
-      '<' => {
-        if chars.peek() == Some(&'=') {
-          chars.next();
-          TokenType::Symbol("<=".to_string())
-        } else {
-          TokenType::Symbol("<".to_string())
-        }
+      Skip::Missing => self
+        .pending
+        .push_back(Err(ScanError::MissingDatum { span })),
+      _ => {}
+    }
+
+    for lexeme in skipped {
+      match lexeme {
+        Lexeme::Error(error) => self.pending.push_back(Err(error)),
+        // Whatever stopped the skip, a ')' or an unclosed list, is left in
+        // place for the parser.
+        Lexeme::Token(token) if !matches!(skip, Skip::Done) => self.pending.push_back(Ok(token)),
+        _ => {}
       }
-      '\'' => TokenType::Quote,
-      ':' => {
-        let mut keyword = String::new();
-        while let Some(&next) = chars.peek() {
-          if next.is_whitespace() || next == ')' || next == '(' {
-            break;
+    }
+    true
+  }
+
+  fn skip_form(&mut self, skipped: &mut Vec<Lexeme>) -> Skip {
+    loop {
+      let Some(lexeme) = self.next_lexeme() else {
+        return self.out_of_input(Skip::Missing);
+      };
+      let skip = match &lexeme {
+        Lexeme::Token(token) => match token.token_type {
+          TokenType::RightParen => Some(Skip::Missing),
+          TokenType::LeftParen => Some(Skip::Unclosed),
+          TokenType::Quote | TokenType::ReaderMacro(_) => None,
+          _ => Some(Skip::Done),
+        },
+        Lexeme::Error(_) => None,
+        Lexeme::DatumComment(_) => {
+          skipped.push(lexeme);
+          // The nested `#;` takes the next form, this one the form after.
+          match self.skip_form(skipped) {
+            Skip::Done => continue,
+            skip => return skip,
           }
-          keyword.push(chars.next().unwrap());
         }
-        TokenType::Keyword(keyword)
+      };
+      skipped.push(lexeme);
+      match skip {
+        None => {}
+        Some(Skip::Unclosed) => return self.skip_list(skipped),
+        Some(skip) => return skip,
       }
-      '"' => {
-        let mut string_literal = String::new();
-        let start_column = column; // 记录字符串开始的列号
-        let mut unterminated = false;
+    }
+  }
 
-        while let Some(&next) = chars.peek() {
-          if next == '"' {
-            chars.next(); // 消耗结束的引号
-            break;
-          } else if next == '\\' {
-            // 处理转义字符
-            chars.next(); // 消耗 '\'
-            if let Some(&escaped_char) = chars.peek() {
-              match escaped_char {
-                '"' => string_literal.push('"'),
-                'n' => string_literal.push('\n'),
-                't' => string_literal.push('\t'),
-                '\\' => string_literal.push('\\'),
-                _ => {
-                  errors.push(format!(
-                    "Unknown escape sequence \\{} at line {}, column {}",
-                    escaped_char, line, column
-                  ));
-                }
-              }
-              chars.next(); // 消耗转义后的字符
-              column += 1;
-            } else {
-              errors.push(format!(
-                "Incomplete escape sequence at line {}, column {}",
-                line, column
-              ));
-              break;
-            }
-          } else if next == '\n' {
-            unterminated = true; // 标记为未终止
-            break;
-          } else {
-            string_literal.push(chars.next().unwrap());
-            column += 1; // 更新列号
+  // Skips the rest of a list after its '('.
+  fn skip_list(&mut self, skipped: &mut Vec<Lexeme>) -> Skip {
+    loop {
+      let Some(lexeme) = self.next_lexeme() else {
+        return self.out_of_input(Skip::Unclosed);
+      };
+      match &lexeme {
+        Lexeme::Token(token) if token.token_type == TokenType::RightParen => {
+          skipped.push(lexeme);
+          return Skip::Done;
+        }
+        Lexeme::Error(_) => skipped.push(lexeme),
+        _ => {
+          self.lexemes.push_front(lexeme);
+          match self.skip_form(skipped) {
+            Skip::Done => {}
+            // A ')' where a form was expected still closes the list.
+            Skip::Missing if ends_list(skipped) => return Skip::Done,
+            Skip::Missing => return Skip::Unclosed,
+            skip => return skip,
           }
         }
+      }
+    }
+  }
+
+  fn out_of_input(&self, skip: Skip) -> Skip {
+    if self.chars.starved {
+      Skip::Starved
+    } else {
+      skip
+    }
+  }
+}
+
+impl Lexer<io::Empty> {
+  pub fn incremental(file: FileId) -> Self {
+    let mut lexer = Lexer::new(io::empty(), file);
+    lexer.chars.closed = false;
+    lexer
+  }
+}
 
-        if unterminated || chars.peek().is_none() {
-          errors.push(format!(
-            "Unterminated string starting at line {}, column {}",
-            line, start_column
-          ));
+impl<R: BufRead> Iterator for Lexer<R> {
+  type Item = Result<Token, ScanError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.waiting = false;
+    loop {
+      if let Some(item) = self.pending.pop_front() {
+        return Some(item);
+      }
+      if self.lexemes.is_empty() {
+        self.chars.mark();
+      }
+      match self.next_lexeme() {
+        Some(Lexeme::Token(token)) => return Some(Ok(token)),
+        Some(Lexeme::Error(error)) => return Some(Err(error)),
+        Some(Lexeme::DatumComment(span)) => {
+          if !self.skip_datum(span) {
+            return None;
+          }
         }
+        None => {
+          let error = self.chars.error.take()?;
+          let position = self.chars.position;
+          return Some(Err(ScanError::Io {
+            message: error.to_string(),
+            span: Span::new(self.file, position, position),
+          }));
+        }
+      }
+    }
+  }
+}
+
+// Punctuation allowed in symbols besides letters, digits and '_'.
+const SYMBOL_CHARS: &str = "+-*/><=!?&";
+
+// The token for a scanned identifier: a reserved word or a symbol.
+pub fn identifier_token(identifier: String) -> TokenType {
+  match identifier.as_str() {
+    "def" => TokenType::Var,
+    "defn" => TokenType::Defn,
+    "fn" => TokenType::Func,
+    "macro" => TokenType::Macro,
+    "true" => TokenType::Bool(true),
+    "false" => TokenType::Bool(false),
+    "nil" => TokenType::Nil,
+    _ => TokenType::Symbol(identifier),
+  }
+}
 
-        TokenType::String(string_literal)
+fn ends_list(skipped: &[Lexeme]) -> bool {
+  matches!(skipped.last(), Some(Lexeme::Token(token)) if token.token_type == TokenType::RightParen)
+}
+
+// Reads one token, comment or piece of whitespace starting with `c`. Errors
+// go to `errors`; None means there was no token.
+fn lex<R: BufRead>(
+  chars: &mut Cursor<R>,
+  file: FileId,
+  start: Position,
+  c: char,
+  errors: &mut Vec<ScanError>,
+) -> Option<Lexeme> {
+  let token_type = match c {
+    '(' => TokenType::LeftParen,
+    ')' => TokenType::RightParen,
+    '{' => TokenType::LeftBrace,
+    '}' => TokenType::RightBrace,
+    '/' => TokenType::Symbol("/".to_string()),
+    '*' => TokenType::Symbol("*".to_string()),
+    '!' => {
+      if chars.peek() == Some(&'=') {
+        chars.next();
+        TokenType::BangEqual
+      } else {
+        TokenType::Bang
       }
-      '#' => {
-        if chars.peek() == Some(&'t') {
-          chars.next();
-          TokenType::Bool(true)
-        } else if chars.peek() == Some(&'f') {
-          chars.next();
-          TokenType::Bool(false)
-        } else if chars.peek() == Some(&'\\') {
-          chars.next();
-          if let Some(&next) = chars.peek() {
+    }
+    '=' => {
+      if chars.peek() == Some(&'=') {
+        chars.next();
+        TokenType::Symbol("==".to_string())
+      } else {
+        TokenType::Symbol("=".to_string())
+      }
+    }
+    '>' => {
+      if chars.peek() == Some(&'=') {
+        chars.next();
+        TokenType::Symbol(">=".to_string())
+      } else {
+        TokenType::Symbol(">".to_string())
+      }
+    }
+    '<' => {
+      if chars.peek() == Some(&'=') {
+        chars.next();
+        TokenType::Symbol("<=".to_string())
+      } else {
+        TokenType::Symbol("<".to_string())
+      }
+    }
+    '\'' => TokenType::Quote,
+    ':' => {
+      let mut keyword = String::new();
+      while let Some(&next) = chars.peek() {
+        if next.is_whitespace() || next == ')' || next == '(' {
+          break;
+        }
+        keyword.push(chars.next().unwrap());
+      }
+      TokenType::Keyword(keyword)
+    }
+    '"' => TokenType::String(scan_string(chars, file, start, errors)),
+    '#' => {
+      if chars.peek() == Some(&'t') {
+        chars.next();
+        TokenType::Bool(true)
+      } else if chars.peek() == Some(&'f') {
+        chars.next();
+        TokenType::Bool(false)
+      } else if chars.peek() == Some(&'\\') {
+        chars.next();
+        let Some(first) = chars.next() else {
+          errors.push(ScanError::InvalidCharLiteral {
+            span: Span::new(file, start, chars.position),
+          });
+          return None;
+        };
+        let mut name = first.to_string();
+        if first.is_alphanumeric() {
+          while let Some(&next) = chars.peek().filter(|next| next.is_alphanumeric()) {
+            name.push(next);
             chars.next();
-            TokenType::Character(next)
-          } else {
-            errors.push(format!(
-              "Invalid character literal at line {} column {}",
-              line, column
-            ));
-            continue;
           }
-        } else {
-          errors.push(format!(
-            "Unexpected character '{}' at line {} column {}",
-            c, line, column
-          ));
-          continue;
         }
+        match char_from_name(&name) {
+          Some(character) => TokenType::Character(character),
+          None => {
+            errors.push(ScanError::UnknownCharName {
+              name,
+              span: Span::new(file, start, chars.position),
+            });
+            return None;
+          }
+        }
+      } else if chars.peek() == Some(&'r') {
+        chars.next();
+        match scan_raw_string(chars) {
+          Some(Ok(string_literal)) => TokenType::String(string_literal),
+          Some(Err(())) => {
+            errors.push(ScanError::UnterminatedString {
+              span: Span::new(file, start, chars.position),
+            });
+            return None;
+          }
+          None => {
+            errors.push(ScanError::InvalidRawString {
+              span: Span::new(file, start, chars.position),
+            });
+            return None;
+          }
+        }
+      } else if let Some(&prefix @ ('x' | 'o' | 'b')) = chars.peek() {
+        chars.next();
+        let radix = match prefix {
+          'x' => 16,
+          'o' => 8,
+          _ => 2,
+        };
+        let mut digits = String::new();
+        if let Some(&sign @ ('-' | '+')) = chars.peek() {
+          chars.next();
+          digits.push(sign);
+        }
+        take_number(chars, &mut digits, radix);
+        match number_token(&digits, radix) {
+          Ok(token_type) => token_type,
+          Err(error) => {
+            let literal = format!("#{}{}", prefix, digits);
+            errors.push(error.into_scan_error(literal, Span::new(file, start, chars.position)));
+            return None;
+          }
+        }
+      } else if chars.peek() == Some(&'|') {
+        chars.next();
+        if !skip_block_comment(chars) {
+          errors.push(ScanError::UnterminatedComment {
+            span: Span::new(file, start, chars.position),
+          });
+        }
+        return None;
+      } else if chars.peek() == Some(&';') {
+        chars.next();
+        return Some(Lexeme::DatumComment(Span::new(file, start, chars.position)));
+      } else {
+        errors.push(ScanError::UnexpectedChar {
+          found: c,
+          span: Span::new(file, start, chars.position),
+        });
+        return None;
       }
-      '`' | ',' | '@' => TokenType::ReaderMacro(c.to_string()),
-      ' ' | '\r' | '\t' => continue,
-      '\n' => {
-        line += 1;
-        column = 0;
-        continue;
+    }
+    ';' => {
+      while chars.peek().is_some_and(|&next| next != '\n') {
+        chars.next();
       }
-      _ => {
-        if c.is_digit(10) {
-          let mut number = c.to_string();
-          while let Some(next) = chars.peek() {
-            if next.is_digit(10) {
-              number.push(chars.next().unwrap());
-            } else {
-              break;
-            }
+      return None;
+    }
+    '`' => TokenType::ReaderMacro("`".to_string()),
+    ',' => {
+      if chars.peek() == Some(&'@') {
+        chars.next();
+        TokenType::ReaderMacro(",@".to_string())
+      } else {
+        TokenType::ReaderMacro(",".to_string())
+      }
+    }
+    ' ' | '\r' | '\t' | '\n' => return None,
+    _ => {
+      let signed = (c == '-' || c == '+') && chars.peek().is_some_and(|n| n.is_ascii_digit());
+      if c.is_ascii_digit() || signed {
+        let mut number = c.to_string();
+        take_number(chars, &mut number, 10);
+        match number_token(&number, 10) {
+          Ok(token_type) => token_type,
+          Err(error) => {
+            errors.push(error.into_scan_error(number, Span::new(file, start, chars.position)));
+            return None;
           }
-
-          if chars.peek() == Some(&'.') {
-            number.push(chars.next().unwrap());
-            while let Some(next) = chars.peek() {
-              if next.is_digit(10) {
-                number.push(chars.next().unwrap());
-              } else {
-                break;
-              }
-            }
-            match number.parse::<f32>() {
-              Ok(n) => TokenType::Float32(n),
-              Err(e) => {
-                errors.push(format!("Invalid float number at line {}: {}", line, e));
-                continue;
-              }
-            }
+        }
+      } else if c.is_alphabetic() || c == '_' || SYMBOL_CHARS.contains(c) {
+        let mut identifier = c.to_string();
+        while let Some(&next) = chars.peek() {
+          if next.is_alphanumeric() || next == '_' || SYMBOL_CHARS.contains(next) {
+            identifier.push(chars.next().unwrap());
           } else {
-            match number.parse::<i32>() {
-              Ok(n) => TokenType::Int32(n),
-              Err(e) => {
-                errors.push(format!("Invalid integer number at line {}: {}", line, e));
-                continue;
-              }
-            }
+            break;
           }
-        } else if c.is_alphabetic() || c == '_' || "+-*/><=!?".contains(c) {
-          let mut identifier = c.to_string();
-          while let Some(&next) = chars.peek() {
-            if next.is_alphanumeric() || next == '_' || "+-*/><=!?".contains(next) {
-              identifier.push(chars.next().unwrap());
-            } else {
-              break;
+        }
+
+        return Some(Lexeme::Token(Token {
+          token_type: identifier_token(identifier),
+          span: Span::new(file, start, chars.position),
+        }));
+      } else {
+        errors.push(ScanError::UnexpectedChar {
+          found: c,
+          span: Span::new(file, start, chars.position),
+        });
+        return None;
+      }
+    }
+  };
+
+  Some(Lexeme::Token(Token {
+    token_type,
+    span: Span::new(file, start, chars.position),
+  }))
+}
+
+// Reads a string after its opening quote. Strings may span lines; a
+// backslash at the end of a line skips the line break and the next line's
+// indentation.
+fn scan_string<R: BufRead>(
+  chars: &mut Cursor<R>,
+  file: FileId,
+  start: Position,
+  errors: &mut Vec<ScanError>,
+) -> String {
+  let mut string_literal = String::new();
+  loop {
+    let escape_start = chars.position;
+    let escaped_char = match chars.next() {
+      Some('"') => return string_literal,
+      Some('\\') => chars.next(),
+      Some(c) => {
+        string_literal.push(c);
+        continue;
+      }
+      None => {
+        errors.push(ScanError::UnterminatedString {
+          span: Span::new(file, start, chars.position),
+        });
+        return string_literal;
+      }
+    };
+
+    let span = |chars: &Cursor<R>| Span::new(file, escape_start, chars.position);
+    match escaped_char {
+      Some('"') => string_literal.push('"'),
+      Some('n') => string_literal.push('\n'),
+      Some('t') => string_literal.push('\t'),
+      Some('r') => string_literal.push('\r'),
+      Some('0') => string_literal.push('\0'),
+      Some('\\') => string_literal.push('\\'),
+      Some(prefix @ ('x' | 'u')) => match scan_code_point(chars, prefix) {
+        Ok(c) => string_literal.push(c),
+        Err(sequence) => errors.push(ScanError::InvalidUnicodeEscape {
+          sequence,
+          span: span(chars),
+        }),
+      },
+      Some(c @ (' ' | '\t' | '\r' | '\n')) => {
+        let mut c = c;
+        while c == ' ' || c == '\t' || c == '\r' {
+          match chars.peek() {
+            Some(&next @ (' ' | '\t' | '\r' | '\n')) => {
+              chars.next();
+              c = next;
             }
+            _ => break,
           }
-
-          let token_type = match identifier.as_str() {
-            "def" => TokenType::Var,
-            "fn" => TokenType::Func,
-            "macro" => TokenType::Macro,
-            "quote" => TokenType::Quote,
-            "true" => TokenType::Bool(true),
-            "false" => TokenType::Bool(false),
-            _ => TokenType::Symbol(identifier),
-          };
-
-          tokens.push(Token {
-            token_type,
-            line,
-            column,
+        }
+        if c != '\n' {
+          errors.push(ScanError::UnknownEscape {
+            escape: ' ',
+            span: span(chars),
           });
           continue;
-        } else {
-          errors.push(format!(
-            "Unexpected character '{}' at line {} column {}",
-            c, line, column
-          ));
-          continue;
+        }
+        while chars
+          .peek()
+          .is_some_and(|&next| next == ' ' || next == '\t')
+        {
+          chars.next();
         }
       }
-    };
+      Some(c) => errors.push(ScanError::UnknownEscape {
+        escape: c,
+        span: span(chars),
+      }),
+      None => {
+        errors.push(ScanError::IncompleteEscape { span: span(chars) });
+        errors.push(ScanError::UnterminatedString {
+          span: Span::new(file, start, chars.position),
+        });
+        return string_literal;
+      }
+    }
+  }
+}
+
+// Reads the rest of `\xHH;` or `\u{HHHH}` after the `x` or `u`. On failure
+// returns the text of the escape read so far.
+fn scan_code_point<R: BufRead>(chars: &mut Cursor<R>, prefix: char) -> Result<char, String> {
+  let mut sequence = format!("\\{}", prefix);
+  let terminator = if prefix == 'u' {
+    if chars.peek() != Some(&'{') {
+      return Err(sequence);
+    }
+    chars.next();
+    sequence.push('{');
+    '}'
+  } else {
+    ';'
+  };
 
-    tokens.push(Token {
-      token_type,
-      line,
-      column,
-    });
+  let mut digits = String::new();
+  while let Some(&next) = chars.peek().filter(|next| next.is_ascii_hexdigit()) {
+    digits.push(next);
+    sequence.push(next);
+    chars.next();
   }
+  if chars.peek() != Some(&terminator) {
+    return Err(sequence);
+  }
+  chars.next();
+  sequence.push(terminator);
 
-  if errors.is_empty() {
-    Ok(tokens)
+  u32::from_str_radix(&digits, 16)
+    .ok()
+    .and_then(char::from_u32)
+    .ok_or(sequence)
+}
+
+// Reads a raw string after `#r`: `#r"no \escapes"`, or with n hashes,
+// `#r##"may contain "#"##`. Returns None when no quote follows the hashes
+// and Err when the input ends before the closing delimiter.
+fn scan_raw_string<R: BufRead>(chars: &mut Cursor<R>) -> Option<Result<String, ()>> {
+  let mut hashes = 0;
+  while chars.peek() == Some(&'#') {
+    chars.next();
+    hashes += 1;
+  }
+  if chars.next() != Some('"') {
+    return None;
+  }
+
+  let mut string_literal = String::new();
+  while let Some(c) = chars.next() {
+    if c != '"' {
+      string_literal.push(c);
+      continue;
+    }
+    let mut closing = 0;
+    while closing < hashes && chars.peek() == Some(&'#') {
+      chars.next();
+      closing += 1;
+    }
+    if closing == hashes {
+      return Some(Ok(string_literal));
+    }
+    string_literal.push('"');
+    string_literal.extend(std::iter::repeat_n('#', closing));
+  }
+  Some(Err(()))
+}
+
+// `#\a` is the char itself; longer names are either well-known names or a
+// hex code point (`#\x41`).
+fn char_from_name(name: &str) -> Option<char> {
+  let mut chars = name.chars();
+  if let (Some(c), None) = (chars.next(), chars.next()) {
+    return Some(c);
+  }
+  match name {
+    "space" => Some(' '),
+    "newline" | "linefeed" => Some('\n'),
+    "tab" => Some('\t'),
+    "return" => Some('\r'),
+    "nul" | "null" => Some('\0'),
+    "alarm" => Some('\x07'),
+    "backspace" => Some('\x08'),
+    "escape" => Some('\x1b'),
+    "delete" => Some('\x7f'),
+    _ => name
+      .strip_prefix('x')
+      .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+      .and_then(char::from_u32),
+  }
+}
+
+// Appends the rest of a number literal: digits, `_` separators, a decimal
+// point and an exponent. Anything else alphanumeric is taken too, so that
+// `12abc` is reported as one bad literal rather than a number and a symbol.
+fn take_number<R: BufRead>(chars: &mut Cursor<R>, literal: &mut String, radix: u32) {
+  while let Some(&next) = chars.peek() {
+    let exponent_sign =
+      radix == 10 && (next == '-' || next == '+') && literal.ends_with(['e', 'E']);
+    if next.is_alphanumeric() || next == '_' || next == '.' || exponent_sign {
+      literal.push(next);
+      chars.next();
+    } else {
+      break;
+    }
+  }
+}
+
+enum NumberError {
+  Invalid,
+  Overflow,
+  FloatOverflow,
+}
+
+impl NumberError {
+  fn into_scan_error(self, literal: String, span: Span) -> ScanError {
+    match self {
+      NumberError::Invalid => ScanError::InvalidNumber { literal, span },
+      NumberError::Overflow => ScanError::IntegerOverflow { literal, span },
+      NumberError::FloatOverflow => ScanError::FloatOverflow { literal, span },
+    }
+  }
+}
+
+// Integers become Int32 when they fit and Int64 otherwise; floats become
+// Float32 when they're within f32's normal range and Float64 otherwise.
+fn number_token(text: &str, radix: u32) -> Result<TokenType, NumberError> {
+  let digits: Vec<char> = text.trim_start_matches(['-', '+']).chars().collect();
+  for (i, &c) in digits.iter().enumerate() {
+    let between_digits = i > 0
+      && digits[i - 1].is_digit(radix)
+      && digits.get(i + 1).is_some_and(|next| next.is_digit(radix));
+    if c == '_' && !between_digits {
+      return Err(NumberError::Invalid);
+    }
+  }
+  let cleaned: String = text.chars().filter(|&c| c != '_').collect();
+
+  if radix == 10 && cleaned.contains(['.', 'e', 'E']) {
+    let value: f64 = cleaned.parse().map_err(|_| NumberError::Invalid)?;
+    if value.is_infinite() {
+      return Err(NumberError::FloatOverflow);
+    }
+    let magnitude = value.abs();
+    if value == 0.0 || (f32::MIN_POSITIVE as f64..=f32::MAX as f64).contains(&magnitude) {
+      Ok(TokenType::Float32(
+        cleaned.parse().map_err(|_| NumberError::Invalid)?,
+      ))
+    } else {
+      Ok(TokenType::Float64(value))
+    }
   } else {
-    Err(errors)
+    let value = i64::from_str_radix(&cleaned, radix).map_err(|error| match error.kind() {
+      IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => NumberError::Overflow,
+      _ => NumberError::Invalid,
+    })?;
+    Ok(match i32::try_from(value) {
+      Ok(value) => TokenType::Int32(value),
+      Err(_) => TokenType::Int64(value),
+    })
+  }
+}
+
+// Consumes a `#| ... |#` comment after its opening `#|`; these nest. Returns
+// false when the input ends first.
+fn skip_block_comment<R: BufRead>(chars: &mut Cursor<R>) -> bool {
+  let mut depth = 1;
+  while let Some(c) = chars.next() {
+    if c == '|' && chars.peek() == Some(&'#') {
+      chars.next();
+      depth -= 1;
+      if depth == 0 {
+        return true;
+      }
+    } else if c == '#' && chars.peek() == Some(&'|') {
+      chars.next();
+      depth += 1;
+    }
+  }
+  false
+}
+
+// Walks the source a char at a time, keeping the position of the next char.
+// Text is read a line at a time into `buffer`, which starts at byte `base`
+// of the source; text before `mark` is dropped on the next read, so the
+// lexer can rewind to anywhere after it.
+struct Cursor<R> {
+  reader: R,
+  buffer: String,
+  base: usize,
+  mark: usize,
+  peeked: Option<char>,
+  position: Position,
+  columns: ColumnMode,
+  // Whether the end of the reader is the end of the input; false for an
+  // incremental lexer until it's finished.
+  closed: bool,
+  // Set once the reader has nothing more to give.
+  drained: bool,
+  // Set when the input ran out but more may be pushed.
+  starved: bool,
+  error: Option<io::Error>,
+}
+
+impl<R: BufRead> Cursor<R> {
+  fn new(reader: R) -> Self {
+    Cursor {
+      reader,
+      buffer: String::new(),
+      base: 0,
+      mark: 0,
+      peeked: None,
+      position: Position::new(0, 1, 1),
+      columns: ColumnMode::Chars,
+      closed: true,
+      drained: false,
+      starved: false,
+      error: None,
+    }
+  }
+
+  fn peek(&mut self) -> Option<&char> {
+    self.peeked = self.fill().then(|| self.rest().chars().next()).flatten();
+    self.peeked.as_ref()
+  }
+
+  fn next(&mut self) -> Option<char> {
+    if !self.fill() {
+      return None;
+    }
+    let c = self.rest().chars().next()?;
+    self.position.offset += c.len_utf8();
+    if c == '\n' {
+      self.position.line += 1;
+      self.position.column = 1;
+    } else {
+      self.position.column += self.columns.width(c);
+    }
+    Some(c)
+  }
+
+  fn rest(&self) -> &str {
+    &self.buffer[self.position.offset - self.base..]
+  }
+
+  // Makes sure there's a char to read, pulling another line from the
+  // reader if needed.
+  fn fill(&mut self) -> bool {
+    while self.rest().is_empty() {
+      if self.drained {
+        self.starved = !self.closed;
+        return false;
+      }
+      self.discard();
+      match self.reader.read_line(&mut self.buffer) {
+        Ok(0) => self.drained = true,
+        Ok(_) => {}
+        Err(error) => {
+          self.error = Some(error);
+          self.drained = true;
+        }
+      }
+    }
+    true
+  }
+
+  fn push_str(&mut self, text: &str) {
+    self.discard();
+    self.buffer.push_str(text);
+    self.starved = false;
+  }
+
+  fn discard(&mut self) {
+    self.buffer.drain(..self.mark - self.base);
+    self.base = self.mark;
+  }
+
+  fn mark(&mut self) {
+    self.mark = self.position.offset;
+  }
+
+  fn rewind(&mut self, position: Position) {
+    self.position = position;
   }
 }
 
@@ -291,6 +921,7 @@ mod tests {
   }
 
   #[test]
+  #[allow(clippy::approx_constant)]
   fn test_numbers_and_booleans() {
     let input = "(def values (list 42 3.14 #t #f))".to_string();
     let result = read_str_scan(input);
@@ -361,7 +992,7 @@ mod tests {
 
     let errors = result.unwrap_err();
     assert_eq!(errors.len(), 1);
-    assert!(errors[0].contains("Unterminated string"));
+    assert!(matches!(errors[0], ScanError::UnterminatedString { .. }));
   }
 
   #[test]
@@ -373,7 +1004,10 @@ mod tests {
 
     let errors = result.unwrap_err();
     assert_eq!(errors.len(), 1);
-    assert!(errors[0].contains("Unexpected character '@'"));
+    assert!(matches!(
+      errors[0],
+      ScanError::UnexpectedChar { found: '@', .. }
+    ));
   }
 
   #[test]
@@ -394,15 +1028,291 @@ mod tests {
     assert_eq!(tokens[3].token_type, TokenType::LeftParen);
     assert_eq!(tokens[4].token_type, TokenType::Symbol("msg".to_string())); // 参数名称
     assert_eq!(tokens[5].token_type, TokenType::RightParen);
-    assert_eq!(tokens[6].token_type, TokenType::Symbol("`".to_string())); // Quasiquote (`)
+    assert_eq!(
+      tokens[6].token_type,
+      TokenType::ReaderMacro("`".to_string())
+    ); // Quasiquote (`)
     assert_eq!(tokens[7].token_type, TokenType::LeftParen);
     assert_eq!(
       tokens[8].token_type,
       TokenType::Symbol("println".to_string())
     ); // "println" 函数调用
-    assert_eq!(tokens[9].token_type, TokenType::Symbol(",".to_string())); // Unquote (`,`)
+    assert_eq!(
+      tokens[9].token_type,
+      TokenType::ReaderMacro(",".to_string())
+    ); // Unquote (`,`)
     assert_eq!(tokens[10].token_type, TokenType::Symbol("msg".to_string())); // 参数引用
     assert_eq!(tokens[11].token_type, TokenType::RightParen);
     assert_eq!(tokens[12].token_type, TokenType::RightParen);
+
+    let tokens = read_str_scan("`(,@xs , @)".to_string()).unwrap_err();
+    assert!(matches!(
+      tokens[..],
+      [ScanError::UnexpectedChar { found: '@', .. }]
+    ));
+    let tokens = read_str_scan(",@xs".to_string()).unwrap();
+    assert_eq!(
+      tokens[0].token_type,
+      TokenType::ReaderMacro(",@".to_string())
+    );
+    assert_eq!(tokens[1].span.start.offset, 2);
+  }
+
+  #[test]
+  fn test_error_kinds_and_spans() {
+    let input = "(list 99999999999999999999 \"a\\qb\" #\\".to_string();
+    let errors = read_str_scan(input).unwrap_err();
+
+    assert_eq!(errors.len(), 3);
+    assert_eq!(
+      errors[0],
+      ScanError::IntegerOverflow {
+        literal: "99999999999999999999".to_string(),
+        span: Span::new(FileId(0), Position::new(6, 1, 7), Position::new(26, 1, 27)),
+      }
+    );
+    assert!(matches!(
+      errors[1],
+      ScanError::UnknownEscape { escape: 'q', .. }
+    ));
+    assert_eq!(errors[1].span().start.offset, 29);
+    assert_eq!(errors[1].span().len(), 2);
+    assert!(matches!(errors[2], ScanError::InvalidCharLiteral { .. }));
+    assert_eq!(errors[2].to_string(), "Invalid character literal");
+  }
+
+  #[test]
+  fn test_comments() {
+    let input = "; leading\n(a #| x #| nested |# (y |# b ; trailing\n#;(c (d)) #; #; e 'f g)";
+    let tokens = read_str_scan(input.to_string()).unwrap();
+    let types: Vec<_> = tokens.iter().map(|t| t.token_type.clone()).collect();
+    assert_eq!(
+      types,
+      vec![
+        TokenType::LeftParen,
+        TokenType::Symbol("a".to_string()),
+        TokenType::Symbol("b".to_string()),
+        TokenType::Symbol("g".to_string()),
+        TokenType::RightParen,
+      ]
+    );
+    assert_eq!(tokens[2].span.start, Position::new(37, 2, 28));
+    assert_eq!(tokens[3].span.start, Position::new(71, 3, 22));
+
+    let input = "#|\nab\n|#\n  x".to_string();
+    let tokens = read_str_scan(input).unwrap();
+    assert_eq!(tokens[0].span.start, Position::new(11, 4, 3));
+  }
+
+  #[test]
+  fn test_comment_errors() {
+    let errors = read_str_scan("(a) #| never closed".to_string()).unwrap_err();
+    assert!(matches!(
+      errors[..],
+      [ScanError::UnterminatedComment { .. }]
+    ));
+    assert_eq!(errors[0].span().start.offset, 4);
+
+    let errors = read_str_scan("(a #;)".to_string()).unwrap_err();
+    assert!(matches!(errors[..], [ScanError::MissingDatum { .. }]));
+  }
+
+  #[test]
+  fn test_numeric_literals() {
+    let input = "(- -5 +7 1_000 3.5e2 1e-9 -2.5E+3 #x1F #o17 #b-101 #xFF_FF 4294967296 1e300 -foo)";
+    let tokens = read_str_scan(input.to_string()).unwrap();
+    let types: Vec<_> = tokens.into_iter().map(|t| t.token_type).collect();
+    assert_eq!(
+      types,
+      vec![
+        TokenType::LeftParen,
+        TokenType::Symbol("-".to_string()),
+        TokenType::Int32(-5),
+        TokenType::Int32(7),
+        TokenType::Int32(1000),
+        TokenType::Float32(350.0),
+        TokenType::Float32(1e-9),
+        TokenType::Float32(-2500.0),
+        TokenType::Int32(31),
+        TokenType::Int32(15),
+        TokenType::Int32(-5),
+        TokenType::Int32(65535),
+        TokenType::Int64(4294967296),
+        TokenType::Float64(1e300),
+        TokenType::Symbol("-foo".to_string()),
+        TokenType::RightParen,
+      ]
+    );
+  }
+
+  #[test]
+  fn test_numeric_literal_errors() {
+    let input = "1__0 1_ 12abc #xZZ #x 99999999999999999999 1e999";
+    let errors = read_str_scan(input.to_string()).unwrap_err();
+    let literals: Vec<_> = errors
+      .iter()
+      .map(|error| match error {
+        ScanError::InvalidNumber { literal, .. } => format!("invalid {}", literal),
+        ScanError::IntegerOverflow { literal, .. } => format!("overflow {}", literal),
+        ScanError::FloatOverflow { literal, .. } => format!("float overflow {}", literal),
+        other => panic!("unexpected error {:?}", other),
+      })
+      .collect();
+    assert_eq!(
+      literals,
+      [
+        "invalid 1__0",
+        "invalid 1_",
+        "invalid 12abc",
+        "invalid #xZZ",
+        "invalid #x",
+        "overflow 99999999999999999999",
+        "float overflow 1e999",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_string_and_character_escapes() {
+    let input = r##"("a\r\0\x41;\u{1F600}\u{e9}" "two
+lines" "joined \
+       here" #r"raw \n" #r#"say "hi""# #\space #\newline #\tab #\x41 #\a #\( #\x)"##;
+    let tokens = read_str_scan(input.to_string()).unwrap();
+    let types: Vec<_> = tokens.into_iter().map(|t| t.token_type).collect();
+    assert_eq!(
+      types[1..types.len() - 1],
+      [
+        TokenType::String("a\r\0A\u{1F600}\u{e9}".to_string()),
+        TokenType::String("two\nlines".to_string()),
+        TokenType::String("joined here".to_string()),
+        TokenType::String("raw \\n".to_string()),
+        TokenType::String("say \"hi\"".to_string()),
+        TokenType::Character(' '),
+        TokenType::Character('\n'),
+        TokenType::Character('\t'),
+        TokenType::Character('A'),
+        TokenType::Character('a'),
+        TokenType::Character('('),
+        TokenType::Character('x'),
+      ]
+    );
+
+    assert!(read_str_scan("\"ends at eof\"".to_string()).is_ok());
+  }
+
+  #[test]
+  fn test_escape_errors() {
+    let input = r#""\x4G;" "\u41" "\u{D800}" #\bogus #rx "\q""#;
+    let errors = read_str_scan(input.to_string()).unwrap_err();
+    let messages: Vec<_> = errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(
+      messages,
+      [
+        "Invalid escape sequence \\x4",
+        "Invalid escape sequence \\u",
+        "Invalid escape sequence \\u{D800}",
+        "Unknown character name #\\bogus",
+        "Expected '\"' to start raw string",
+        "Unknown escape sequence \\q",
+      ]
+    );
+    assert_eq!(errors[0].span().start.offset, 1);
+  }
+
+  #[test]
+  fn test_lexer_over_reader() {
+    let source = "(a\n  \"b\nc\" #;\n(d\n e) f)\n";
+    let reader = io::BufReader::with_capacity(2, source.as_bytes());
+    let tokens: Vec<_> = Lexer::new(reader, FileId(1)).map(Result::unwrap).collect();
+    assert_eq!(
+      tokens,
+      read_str_scan_with_file(source.to_string(), FileId(1)).unwrap()
+    );
+    assert_eq!(tokens[3].span.start, Position::new(21, 5, 5));
+
+    // Only the line being scanned is kept.
+    let source = "(x)\n".repeat(1000);
+    let mut lexer = Lexer::new(source.as_bytes(), FileId::default());
+    let mut count = 0;
+    while lexer.next().is_some() {
+      assert!(lexer.chars.buffer.len() < 10);
+      count += 1;
+    }
+    assert_eq!(count, 3000);
+
+    let items: Vec<_> = Lexer::new(&b"(a)\n(\xff)"[..], FileId::default()).collect();
+    assert_eq!(items.len(), 4);
+    assert!(matches!(items[3], Err(ScanError::Io { .. })));
+  }
+
+  #[test]
+  fn test_incremental_lexer() {
+    let mut lexer = Lexer::incremental(FileId::default());
+    let feed = |lexer: &mut Lexer<io::Empty>, text: &str| {
+      lexer.push_str(text);
+      lexer
+        .by_ref()
+        .map(|item| item.unwrap().token_type)
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+      feed(&mut lexer, "(print \"multi"),
+      [TokenType::LeftParen, TokenType::Symbol("print".to_string())]
+    );
+    assert!(lexer.is_waiting());
+    assert_eq!(
+      feed(&mut lexer, "line\" #;(a\n"),
+      [TokenType::String("multiline".to_string())]
+    );
+    assert!(lexer.is_waiting());
+    assert_eq!(
+      feed(&mut lexer, "b) x)"),
+      [TokenType::Symbol("x".to_string()), TokenType::RightParen]
+    );
+    assert!(!lexer.is_waiting());
+
+    lexer.push_str(" #| open");
+    assert!(lexer.next().is_none());
+    lexer.finish();
+    assert!(matches!(
+      lexer.next(),
+      Some(Err(ScanError::UnterminatedComment { span })) if span.start == Position::new(30, 2, 7)
+    ));
+    assert!(lexer.next().is_none());
+  }
+
+  #[test]
+  fn test_multibyte_positions() {
+    let source = "(λ :café\n  \"日本\" 𝒳y)";
+    let spans = |columns| {
+      Lexer::new(source.as_bytes(), FileId::default())
+        .with_columns(columns)
+        .map(|item| {
+          let span = item.unwrap().span;
+          (span.start, span.end)
+        })
+        .collect::<Vec<_>>()
+    };
+
+    let chars = spans(ColumnMode::Chars);
+    assert_eq!(chars[1], (Position::new(1, 1, 2), Position::new(3, 1, 3)));
+    assert_eq!(chars[2], (Position::new(4, 1, 4), Position::new(10, 1, 9)));
+    assert_eq!(chars[3], (Position::new(13, 2, 3), Position::new(21, 2, 7)));
+    assert_eq!(
+      chars[4],
+      (Position::new(22, 2, 8), Position::new(27, 2, 10))
+    );
+    assert_eq!(chars[5].0, Position::new(27, 2, 10));
+    assert_eq!(&source[chars[4].0.offset..chars[4].1.offset], "𝒳y");
+
+    // `𝒳` is outside the BMP, so it takes two UTF-16 code units.
+    let utf16 = spans(ColumnMode::Utf16);
+    assert_eq!(
+      utf16[4],
+      (Position::new(22, 2, 8), Position::new(27, 2, 11))
+    );
+    assert_eq!(utf16[5].0, Position::new(27, 2, 11));
+    assert_eq!(utf16[2], chars[2]);
   }
 }
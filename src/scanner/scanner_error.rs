@@ -0,0 +1,68 @@
+use super::span::Span;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanError {
+  UnterminatedString { span: Span },
+  UnknownEscape { escape: char, span: Span },
+  IncompleteEscape { span: Span },
+  InvalidUnicodeEscape { sequence: String, span: Span },
+  InvalidRawString { span: Span },
+  UnknownCharName { name: String, span: Span },
+  InvalidCharLiteral { span: Span },
+  IntegerOverflow { literal: String, span: Span },
+  FloatOverflow { literal: String, span: Span },
+  InvalidNumber { literal: String, span: Span },
+  UnexpectedChar { found: char, span: Span },
+  UnterminatedComment { span: Span },
+  MissingDatum { span: Span },
+  Io { message: String, span: Span },
+}
+
+impl ScanError {
+  pub fn span(&self) -> Span {
+    match self {
+      ScanError::UnterminatedString { span }
+      | ScanError::UnknownEscape { span, .. }
+      | ScanError::IncompleteEscape { span }
+      | ScanError::InvalidUnicodeEscape { span, .. }
+      | ScanError::InvalidRawString { span }
+      | ScanError::UnknownCharName { span, .. }
+      | ScanError::InvalidCharLiteral { span }
+      | ScanError::IntegerOverflow { span, .. }
+      | ScanError::FloatOverflow { span, .. }
+      | ScanError::InvalidNumber { span, .. }
+      | ScanError::UnexpectedChar { span, .. }
+      | ScanError::UnterminatedComment { span }
+      | ScanError::MissingDatum { span }
+      | ScanError::Io { span, .. } => *span,
+    }
+  }
+}
+
+impl fmt::Display for ScanError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ScanError::UnterminatedString { .. } => write!(f, "Unterminated string"),
+      ScanError::UnknownEscape { escape, .. } => write!(f, "Unknown escape sequence \\{}", escape),
+      ScanError::IncompleteEscape { .. } => write!(f, "Incomplete escape sequence"),
+      ScanError::InvalidUnicodeEscape { sequence, .. } => {
+        write!(f, "Invalid escape sequence {}", sequence)
+      }
+      ScanError::InvalidRawString { .. } => write!(f, "Expected '\"' to start raw string"),
+      ScanError::UnknownCharName { name, .. } => write!(f, "Unknown character name #\\{}", name),
+      ScanError::InvalidCharLiteral { .. } => write!(f, "Invalid character literal"),
+      ScanError::IntegerOverflow { literal, .. } => {
+        write!(f, "Integer literal {} is out of range", literal)
+      }
+      ScanError::FloatOverflow { literal, .. } => {
+        write!(f, "Float literal {} is out of range", literal)
+      }
+      ScanError::InvalidNumber { literal, .. } => write!(f, "Invalid number '{}'", literal),
+      ScanError::UnexpectedChar { found, .. } => write!(f, "Unexpected character '{}'", found),
+      ScanError::UnterminatedComment { .. } => write!(f, "Unterminated block comment"),
+      ScanError::MissingDatum { .. } => write!(f, "Expected a form after '#;'"),
+      ScanError::Io { message, .. } => write!(f, "Failed to read input: {}", message),
+    }
+  }
+}
@@ -0,0 +1,67 @@
+// Identifies the source a span points into. Sources scanned without a file
+// (REPL entries, tests) share the default id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FileId(pub u32);
+
+// A point in the source: byte offset plus 1-based line and column. Columns
+// count chars unless the scanner was asked for UTF-16 code units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+  pub offset: usize,
+  pub line: u32,
+  pub column: u32,
+}
+
+// Half-open range [start, end) in one file. `Span::default()` is used for
+// nodes that don't come from source text, e.g. ones built by the compiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+  pub file: FileId,
+  pub start: Position,
+  pub end: Position,
+}
+
+// What a column counts. LSP clients expect UTF-16 code units by default;
+// everything else here counts chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColumnMode {
+  #[default]
+  Chars,
+  #[allow(dead_code)]
+  Utf16,
+}
+
+impl ColumnMode {
+  pub fn width(self, c: char) -> u32 {
+    match self {
+      ColumnMode::Chars => 1,
+      ColumnMode::Utf16 => c.len_utf16() as u32,
+    }
+  }
+}
+
+impl Position {
+  pub fn new(offset: usize, line: u32, column: u32) -> Self {
+    Position {
+      offset,
+      line,
+      column,
+    }
+  }
+}
+
+impl Span {
+  pub fn new(file: FileId, start: Position, end: Position) -> Self {
+    Span { file, start, end }
+  }
+
+  // The span covering `self` through `other`.
+  pub fn to(&self, other: &Span) -> Span {
+    Span::new(self.file, self.start, other.end)
+  }
+
+  #[allow(dead_code)]
+  pub fn len(&self) -> usize {
+    self.end.offset - self.start.offset
+  }
+}
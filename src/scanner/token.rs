@@ -1,4 +1,8 @@
+use super::span::Span;
+
+// Not every token type is produced by the scanner yet.
 #[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum TokenType {
   // Single-character tokens.
   LeftParen,  // (
@@ -30,12 +34,15 @@ pub enum TokenType {
   Keyword(String),
   ReaderMacro(String),
   Float32(f32),
+  Float64(f64),
   Int32(i32),
+  Int64(i64),
   Bool(bool),
 
   // Keywords.
   And,
   Class,
+  Defn,
   Else,
   Func,
   For,
@@ -51,14 +58,12 @@ pub enum TokenType {
   Quote,
   Macro,
 
-
   // End of file.
   EOF,
-} 
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
   pub token_type: TokenType,
-  pub line: u32,
-  pub column: u32,
+  pub span: Span,
 }
@@ -0,0 +1,4 @@
+pub mod value;
+#[allow(clippy::module_inception)]
+pub mod vm;
+pub mod vm_error;
@@ -0,0 +1,80 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TableKey {
+  Int(i32),
+  Str(String),
+  Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Nil,
+  Int(i32),
+  Float(f32),
+  Bool(bool),
+  Str(usize), // ptr into STR_TABLE
+  List(Rc<RefCell<Vec<Value>>>),
+  Table(Rc<RefCell<HashMap<TableKey, Value>>>),
+  Array(Rc<RefCell<Vec<Value>>>),
+}
+
+impl Value {
+  pub fn is_truthy(&self) -> bool {
+    !matches!(self, Value::Nil | Value::Bool(false))
+  }
+
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Value::Nil => "nil",
+      Value::Int(_) => "int",
+      Value::Float(_) => "float",
+      Value::Bool(_) => "bool",
+      Value::Str(_) => "string",
+      Value::List(_) => "list",
+      Value::Table(_) => "table",
+      Value::Array(_) => "array",
+    }
+  }
+}
+
+// Values own no strings themselves, so printing needs the STR_TABLE to
+// resolve `Str` pointers.
+pub struct DisplayValue<'a> {
+  pub value: &'a Value,
+  pub str_table: &'a [String],
+}
+
+impl fmt::Display for DisplayValue<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let elements = |f: &mut fmt::Formatter<'_>, items: &[Value], open, close| {
+      write!(f, "{}", open)?;
+      for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+          write!(f, " ")?;
+        }
+        let item = DisplayValue {
+          value: item,
+          str_table: self.str_table,
+        };
+        write!(f, "{}", item)?;
+      }
+      write!(f, "{}", close)
+    };
+
+    match self.value {
+      Value::Nil => write!(f, "nil"),
+      Value::Int(n) => write!(f, "{}", n),
+      Value::Float(n) => write!(f, "{:?}", n),
+      Value::Bool(true) => write!(f, "#t"),
+      Value::Bool(false) => write!(f, "#f"),
+      Value::Str(ptr) => match self.str_table.get(*ptr) {
+        Some(s) => write!(f, "{}", s),
+        None => write!(f, "<dangling str {}>", ptr),
+      },
+      Value::List(items) => elements(f, &items.borrow(), "(", ")"),
+      Value::Array(items) => elements(f, &items.borrow(), "[", "]"),
+      Value::Table(table) => write!(f, "<table {}>", table.borrow().len()),
+    }
+  }
+}
@@ -0,0 +1,885 @@
+use super::{
+  value::{DisplayValue, TableKey, Value},
+  vm_error::{VmError, VmResult},
+};
+use crate::compiler::{
+  instruction::{Instruction, Operand},
+  opcode::Opcode,
+  program::Program,
+};
+use std::{
+  cell::RefCell,
+  cmp::Ordering,
+  collections::HashMap,
+  io::{self, BufRead, StdinLock, Stdout, Write},
+  rc::Rc,
+};
+
+pub const REGISTER_COUNT: usize = 256;
+pub const MAX_CALL_DEPTH: usize = 1024;
+pub const MAX_ARGS: usize = 256;
+
+pub const INT_TABLE: i32 = 0;
+pub const FLOAT_TABLE: i32 = 1;
+pub const STR_TABLE: i32 = 2;
+
+#[derive(Debug)]
+struct Frame {
+  registers: Vec<Value>,
+  args: Vec<Value>,    // filled by the caller's SET_ARG, read by GET_ARG
+  pending: Vec<Value>, // SET_ARG staging area for the next CALL
+  return_pc: usize,
+}
+
+impl Frame {
+  fn new(args: Vec<Value>, return_pc: usize) -> Self {
+    Frame {
+      registers: vec![Value::Nil; REGISTER_COUNT],
+      args,
+      pending: Vec::new(),
+      return_pc,
+    }
+  }
+}
+
+// Every call gets a fresh register window; results travel back through the
+// value stack: `RETURN r1` pushes r1, and the caller `POP`s it after `CALL`.
+pub struct Vm<R: BufRead, W: Write> {
+  code: Vec<Instruction>,
+  pc: usize,
+  frames: Vec<Frame>,
+  stack: Vec<Value>,
+  int_table: Vec<i32>,
+  float_table: Vec<f32>,
+  str_table: Vec<String>,
+  // Where each value in the tables is, so that SETS, string ADD and STORE
+  // reuse an entry rather than growing a table with every run of a loop.
+  interned: HashMap<Constant, usize>,
+  input: R,
+  output: W,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Constant {
+  Int(i32),
+  Float(u32), // bits
+  Str(String),
+}
+
+impl Vm<StdinLock<'static>, Stdout> {
+  pub fn new(program: Program) -> Self {
+    Vm::with_io(program, io::stdin().lock(), io::stdout())
+  }
+}
+
+impl<R: BufRead, W: Write> Vm<R, W> {
+  pub fn with_io(program: Program, input: R, output: W) -> Self {
+    let ints = program.int_table.iter().map(|n| Constant::Int(*n));
+    let floats = program
+      .float_table
+      .iter()
+      .map(|n| Constant::Float(n.to_bits()));
+    let strs = program.str_table.iter().map(|s| Constant::Str(s.clone()));
+    let mut interned = HashMap::new();
+    for (idx, constant) in ints
+      .enumerate()
+      .chain(floats.enumerate())
+      .chain(strs.enumerate())
+    {
+      interned.entry(constant).or_insert(idx);
+    }
+    Vm {
+      code: program.code,
+      pc: 0,
+      frames: vec![Frame::new(Vec::new(), 0)],
+      stack: Vec::new(),
+      int_table: program.int_table,
+      float_table: program.float_table,
+      str_table: program.str_table,
+      interned,
+      input,
+      output,
+    }
+  }
+
+  #[allow(dead_code)]
+  pub fn output(&self) -> &W {
+    &self.output
+  }
+
+  #[allow(dead_code)]
+  pub fn str_table(&self) -> &[String] {
+    &self.str_table
+  }
+
+  pub fn display<'a>(&'a self, value: &'a Value) -> DisplayValue<'a> {
+    DisplayValue {
+      value,
+      str_table: &self.str_table,
+    }
+  }
+
+  // Runs until HLT and returns the value left in r0 of the active frame.
+  pub fn run(&mut self) -> VmResult<Value> {
+    loop {
+      let instruction = match self.code.get(self.pc) {
+        Some(instruction) => instruction.clone(),
+        None => return Err(self.error("Program ended without HLT")),
+      };
+      self.pc += 1;
+      if let Some(result) = self.execute(&instruction)? {
+        return Ok(result);
+      }
+    }
+  }
+
+  fn execute(&mut self, ins: &Instruction) -> VmResult<Option<Value>> {
+    match ins.opcode {
+      Opcode::SETI => {
+        let value = Value::Int(self.imm(ins, 1)?);
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::SETF => {
+        let value = Value::Float(f32::from_bits(self.imm(ins, 1)? as u32));
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::SETS => {
+        let idx = self.imm(ins, 1)? as usize;
+        let string = match self.str_table.get(idx) {
+          Some(s) => s.clone(),
+          None => return Err(self.error(&format!("String constant {} out of range", idx))),
+        };
+        let ptr = self.alloc_str(string);
+        self.set(self.reg(ins, 0)?, Value::Str(ptr));
+      }
+      Opcode::SETNIL => self.set(self.reg(ins, 0)?, Value::Nil),
+      Opcode::STORE => {
+        let value = self.get(self.reg(ins, 1)?);
+        let idx = self.store(value, self.imm(ins, 2)?)?;
+        self.set(self.reg(ins, 0)?, Value::Int(idx as i32));
+      }
+      Opcode::LOAD => {
+        let idx = self.index(&self.get(self.reg(ins, 1)?))?;
+        let value = self.load(idx, self.imm(ins, 2)?)?;
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+        let lhs = self.get(self.reg(ins, 1)?);
+        let rhs = self.get(self.reg(ins, 2)?);
+        let value = self.arithmetic(ins.opcode, lhs, rhs)?;
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::CVT_I_D => {
+        let value = match self.get(self.reg(ins, 1)?) {
+          Value::Int(n) => Value::Float(n as f32),
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::CVT_D_I => {
+        let value = match self.get(self.reg(ins, 1)?) {
+          Value::Float(n) => Value::Int(n as i32),
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::NEGATE => {
+        let value = match self.get(self.reg(ins, 1)?) {
+          Value::Int(n) => match n.checked_neg() {
+            Some(n) => Value::Int(n),
+            None => return Err(self.error("Integer overflow")),
+          },
+          Value::Float(n) => Value::Float(-n),
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::HLT => return Ok(Some(self.get(0))),
+
+      Opcode::JMP => self.jump(self.imm(ins, 0)?)?,
+      Opcode::JMP_IF => {
+        if self.get(self.reg(ins, 0)?).is_truthy() {
+          self.jump(self.imm(ins, 1)?)?;
+        }
+      }
+
+      Opcode::EQ | Opcode::NEQ => {
+        let lhs = self.get(self.reg(ins, 1)?);
+        let rhs = self.get(self.reg(ins, 2)?);
+        let equal = self.values_equal(&lhs, &rhs);
+        self.set(
+          self.reg(ins, 0)?,
+          Value::Bool(equal == (ins.opcode == Opcode::EQ)),
+        );
+      }
+      Opcode::GT | Opcode::GTE | Opcode::LT | Opcode::LTE => {
+        let lhs = self.get(self.reg(ins, 1)?);
+        let rhs = self.get(self.reg(ins, 2)?);
+        let ordering = match self.compare(&lhs, &rhs) {
+          Some(ordering) => ordering,
+          None => return Err(self.type_error(ins.opcode, &[&lhs, &rhs])),
+        };
+        let result = match ins.opcode {
+          Opcode::GT => ordering == Ordering::Greater,
+          Opcode::GTE => ordering != Ordering::Less,
+          Opcode::LT => ordering == Ordering::Less,
+          _ => ordering != Ordering::Greater,
+        };
+        self.set(self.reg(ins, 0)?, Value::Bool(result));
+      }
+
+      Opcode::BITAND | Opcode::BITOR | Opcode::BITXOR => {
+        let lhs = self.get(self.reg(ins, 1)?);
+        let rhs = self.get(self.reg(ins, 2)?);
+        let value = match (&lhs, &rhs) {
+          (Value::Int(a), Value::Int(b)) => match ins.opcode {
+            Opcode::BITAND => a & b,
+            Opcode::BITOR => a | b,
+            _ => a ^ b,
+          },
+          _ => return Err(self.type_error(ins.opcode, &[&lhs, &rhs])),
+        };
+        self.set(self.reg(ins, 0)?, Value::Int(value));
+      }
+      Opcode::BITNOT => {
+        let value = match self.get(self.reg(ins, 1)?) {
+          Value::Int(n) => Value::Int(!n),
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::BITSHL | Opcode::BITSHRL | Opcode::BITSHRA => {
+        let shift = self.imm(ins, 2)?;
+        if !(0..32).contains(&shift) {
+          return Err(self.error(&format!("Shift amount {} out of range", shift)));
+        }
+        let value = match self.get(self.reg(ins, 1)?) {
+          Value::Int(n) => match ins.opcode {
+            Opcode::BITSHL => n << shift,
+            Opcode::BITSHRL => ((n as u32) >> shift) as i32,
+            _ => n >> shift,
+          },
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, Value::Int(value));
+      }
+
+      Opcode::VMCALL => self.vmcall(self.reg(ins, 0)?, self.reg(ins, 1)?, self.imm(ins, 2)?)?,
+      Opcode::PUSH => {
+        let value = self.get(self.reg(ins, 0)?);
+        self.stack.push(value);
+      }
+      Opcode::POP => {
+        let value = match self.stack.pop() {
+          Some(value) => value,
+          None => return Err(self.error("Stack underflow")),
+        };
+        self.set(self.reg(ins, 0)?, value);
+      }
+
+      Opcode::GET_LEN => {
+        let len = match self.get(self.reg(ins, 1)?) {
+          Value::Str(ptr) => self.string(ptr)?.chars().count(),
+          Value::List(items) | Value::Array(items) => items.borrow().len(),
+          Value::Table(table) => table.borrow().len(),
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, Value::Int(len as i32));
+      }
+
+      Opcode::SET_ARG => {
+        let value = self.get(self.reg(ins, 0)?);
+        let idx = self.arg_index(ins)?;
+        let pending = &mut self.frame_mut().pending;
+        if pending.len() <= idx {
+          pending.resize(idx + 1, Value::Nil);
+        }
+        pending[idx] = value;
+      }
+      Opcode::GET_ARG => {
+        let idx = self.arg_index(ins)?;
+        let value = self.frame().args.get(idx).cloned().unwrap_or(Value::Nil);
+        self.set(self.reg(ins, 0)?, value);
+      }
+      Opcode::GET_ARGS => {
+        let idx = self.arg_index(ins)?;
+        let args = self.frame().args.get(idx..).unwrap_or_default().to_vec();
+        self.set(self.reg(ins, 0)?, Value::List(Rc::new(RefCell::new(args))));
+      }
+      Opcode::CALL => {
+        let target = match ins.operands.first() {
+          Some(Operand::Reg(r)) => match self.get(*r) {
+            Value::Int(address) => address,
+            other => return Err(self.type_error(ins.opcode, &[&other])),
+          },
+          _ => self.imm(ins, 0)?,
+        };
+        if self.frames.len() >= MAX_CALL_DEPTH {
+          return Err(self.error("Call stack overflow"));
+        }
+        let args = std::mem::take(&mut self.frame_mut().pending);
+        self.frames.push(Frame::new(args, self.pc));
+        self.jump(target)?;
+      }
+      Opcode::RETURN => {
+        if self.frames.len() == 1 {
+          return Err(self.error("RETURN outside of a function"));
+        }
+        let value = self.get(self.reg(ins, 0)?);
+        let frame = self.frames.pop().unwrap();
+        self.stack.push(value);
+        self.pc = frame.return_pc;
+      }
+
+      Opcode::NEW_LIST => self.set(self.reg(ins, 0)?, Value::List(Rc::default())),
+      Opcode::NEW_ARRAY => self.set(self.reg(ins, 0)?, Value::Array(Rc::default())),
+      Opcode::NEW_TABLE => self.set(
+        self.reg(ins, 0)?,
+        Value::Table(Rc::new(RefCell::new(HashMap::new()))),
+      ),
+      Opcode::SET_LIST | Opcode::SET_ARRAY => {
+        let container = self.get(self.reg(ins, 0)?);
+        let idx = self.index(&self.get(self.reg(ins, 1)?))?;
+        let value = self.get(self.reg(ins, 2)?);
+        let items = match (&container, ins.opcode) {
+          (Value::List(items), Opcode::SET_LIST) | (Value::Array(items), Opcode::SET_ARRAY) => {
+            items
+          }
+          _ => return Err(self.type_error(ins.opcode, &[&container])),
+        };
+        let mut items = items.borrow_mut();
+        match idx.cmp(&items.len()) {
+          Ordering::Less => items[idx] = value,
+          Ordering::Equal => items.push(value),
+          Ordering::Greater => {
+            return Err(self.error(&format!(
+              "Index {} out of bounds (len {})",
+              idx,
+              items.len()
+            )))
+          }
+        }
+      }
+      Opcode::GET_LIST | Opcode::GET_ARRAY => {
+        let container = self.get(self.reg(ins, 1)?);
+        let idx = self.index(&self.get(self.reg(ins, 2)?))?;
+        let items = match (&container, ins.opcode) {
+          (Value::List(items), Opcode::GET_LIST) | (Value::Array(items), Opcode::GET_ARRAY) => {
+            items
+          }
+          _ => return Err(self.type_error(ins.opcode, &[&container])),
+        };
+        let value = items.borrow().get(idx).cloned();
+        match value {
+          Some(value) => self.set(self.reg(ins, 0)?, value),
+          None => {
+            let len = items.borrow().len();
+            return Err(self.error(&format!("Index {} out of bounds (len {})", idx, len)));
+          }
+        }
+      }
+      Opcode::SET_TABLE => {
+        let container = self.get(self.reg(ins, 0)?);
+        let key = self.table_key(&self.get(self.reg(ins, 1)?))?;
+        let value = self.get(self.reg(ins, 2)?);
+        match container {
+          Value::Table(table) => {
+            table.borrow_mut().insert(key, value);
+          }
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        }
+      }
+      Opcode::GET_TABLE => {
+        let container = self.get(self.reg(ins, 1)?);
+        let key = self.table_key(&self.get(self.reg(ins, 2)?))?;
+        let value = match container {
+          Value::Table(table) => table.borrow().get(&key).cloned().unwrap_or(Value::Nil),
+          other => return Err(self.type_error(ins.opcode, &[&other])),
+        };
+        self.set(self.reg(ins, 0)?, value);
+      }
+
+      Opcode::IGL => return Err(self.error("Illegal instruction")),
+      Opcode::NOP => {}
+    }
+
+    Ok(None)
+  }
+
+  fn frame(&self) -> &Frame {
+    self.frames.last().unwrap()
+  }
+
+  fn frame_mut(&mut self) -> &mut Frame {
+    self.frames.last_mut().unwrap()
+  }
+
+  fn get(&self, reg: u8) -> Value {
+    self.frame().registers[reg as usize].clone()
+  }
+
+  fn set(&mut self, reg: u8, value: Value) {
+    self.frame_mut().registers[reg as usize] = value;
+  }
+
+  fn reg(&self, ins: &Instruction, idx: usize) -> VmResult<u8> {
+    match ins.operands.get(idx) {
+      Some(Operand::Reg(r)) => Ok(*r),
+      _ => Err(self.error(&format!(
+        "{:?} expects a register as operand {}",
+        ins.opcode, idx
+      ))),
+    }
+  }
+
+  fn imm(&self, ins: &Instruction, idx: usize) -> VmResult<i32> {
+    match ins.operands.get(idx) {
+      Some(Operand::Imm(n)) => Ok(*n),
+      Some(Operand::Label(label)) => Err(self.error(&format!("Unresolved label @{}", label))),
+      _ => Err(self.error(&format!(
+        "{:?} expects an immediate as operand {}",
+        ins.opcode, idx
+      ))),
+    }
+  }
+
+  fn arg_index(&self, ins: &Instruction) -> VmResult<usize> {
+    match self.imm(ins, 1)? {
+      idx if (0..MAX_ARGS as i32).contains(&idx) => Ok(idx as usize),
+      idx => Err(self.error(&format!(
+        "Argument index {} is out of range 0..{}",
+        idx, MAX_ARGS
+      ))),
+    }
+  }
+
+  fn jump(&mut self, target: i32) -> VmResult<()> {
+    if target < 0 || target as usize >= self.code.len() {
+      return Err(self.error(&format!("Jump target {} out of range", target)));
+    }
+    self.pc = target as usize;
+    Ok(())
+  }
+
+  fn index(&self, value: &Value) -> VmResult<usize> {
+    match value {
+      Value::Int(n) if *n >= 0 => Ok(*n as usize),
+      Value::Int(n) => Err(self.error(&format!("Negative index {}", n))),
+      other => Err(self.error(&format!("Expected int index, found {}", other.type_name()))),
+    }
+  }
+
+  fn table_key(&self, value: &Value) -> VmResult<TableKey> {
+    match value {
+      Value::Int(n) => Ok(TableKey::Int(*n)),
+      Value::Bool(b) => Ok(TableKey::Bool(*b)),
+      Value::Str(ptr) => Ok(TableKey::Str(self.string(*ptr)?.to_string())),
+      other => Err(self.error(&format!(
+        "{} cannot be used as a table key",
+        other.type_name()
+      ))),
+    }
+  }
+
+  fn string(&self, ptr: usize) -> VmResult<&str> {
+    match self.str_table.get(ptr) {
+      Some(s) => Ok(s),
+      None => Err(self.error(&format!("Dangling string pointer {}", ptr))),
+    }
+  }
+
+  fn alloc_str(&mut self, string: String) -> usize {
+    self.intern(Constant::Str(string))
+  }
+
+  fn intern(&mut self, constant: Constant) -> usize {
+    if let Some(&idx) = self.interned.get(&constant) {
+      return idx;
+    }
+    let idx = match &constant {
+      Constant::Int(n) => {
+        self.int_table.push(*n);
+        self.int_table.len() - 1
+      }
+      Constant::Float(bits) => {
+        self.float_table.push(f32::from_bits(*bits));
+        self.float_table.len() - 1
+      }
+      Constant::Str(s) => {
+        self.str_table.push(s.clone());
+        self.str_table.len() - 1
+      }
+    };
+    self.interned.insert(constant, idx);
+    idx
+  }
+
+  fn store(&mut self, value: Value, table: i32) -> VmResult<usize> {
+    match (table, value) {
+      (INT_TABLE, Value::Int(n)) => Ok(self.intern(Constant::Int(n))),
+      (FLOAT_TABLE, Value::Float(n)) => Ok(self.intern(Constant::Float(n.to_bits()))),
+      (FLOAT_TABLE, Value::Int(n)) => Ok(self.intern(Constant::Float((n as f32).to_bits()))),
+      // The string already lives in STR_TABLE, its pointer is its index.
+      (STR_TABLE, Value::Str(ptr)) => self.string(ptr).map(|_| ptr),
+      (INT_TABLE | FLOAT_TABLE | STR_TABLE, other) => Err(self.error(&format!(
+        "Cannot STORE {} into table {}",
+        other.type_name(),
+        table
+      ))),
+      _ => Err(self.error(&format!("Unknown table {}", table))),
+    }
+  }
+
+  fn load(&self, idx: usize, table: i32) -> VmResult<Value> {
+    let value = match table {
+      INT_TABLE => self.int_table.get(idx).map(|n| Value::Int(*n)),
+      FLOAT_TABLE => self.float_table.get(idx).map(|n| Value::Float(*n)),
+      STR_TABLE => self.str_table.get(idx).map(|_| Value::Str(idx)),
+      _ => return Err(self.error(&format!("Unknown table {}", table))),
+    };
+    value.ok_or_else(|| self.error(&format!("Index {} out of range for table {}", idx, table)))
+  }
+
+  fn arithmetic(&mut self, op: Opcode, lhs: Value, rhs: Value) -> VmResult<Value> {
+    match (&lhs, &rhs) {
+      (Value::Int(a), Value::Int(b)) => {
+        let result = match op {
+          Opcode::ADD => a.checked_add(*b),
+          Opcode::SUB => a.checked_sub(*b),
+          Opcode::MUL => a.checked_mul(*b),
+          _ if *b == 0 => return Err(self.error("Division by zero")),
+          _ => a.checked_div(*b),
+        };
+        result
+          .map(Value::Int)
+          .ok_or_else(|| self.error("Integer overflow"))
+      }
+      (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+        let (a, b) = (as_float(&lhs), as_float(&rhs));
+        Ok(Value::Float(match op {
+          Opcode::ADD => a + b,
+          Opcode::SUB => a - b,
+          Opcode::MUL => a * b,
+          _ => a / b,
+        }))
+      }
+      (Value::Str(a), Value::Str(b)) if op == Opcode::ADD => {
+        let joined = format!("{}{}", self.string(*a)?, self.string(*b)?);
+        Ok(Value::Str(self.alloc_str(joined)))
+      }
+      _ => Err(self.type_error(op, &[&lhs, &rhs])),
+    }
+  }
+
+  fn values_equal(&self, lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+      (Value::Str(a), Value::Str(b)) => self.str_table.get(*a) == self.str_table.get(*b),
+      (Value::Int(_), Value::Float(_)) | (Value::Float(_), Value::Int(_)) => {
+        as_float(lhs) == as_float(rhs)
+      }
+      _ => lhs == rhs,
+    }
+  }
+
+  fn compare(&self, lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+      (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+      (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+        as_float(lhs).partial_cmp(&as_float(rhs))
+      }
+      (Value::Str(a), Value::Str(b)) => Some(
+        self
+          .str_table
+          .get(*a)?
+          .as_str()
+          .cmp(self.str_table.get(*b)?.as_str()),
+      ),
+      _ => None,
+    }
+  }
+
+  fn vmcall(&mut self, r1: u8, r2: u8, call: i32) -> VmResult<()> {
+    match call {
+      0 => {
+        let value = self.get(r1);
+        let newline = self.get(r2) == Value::Int(1);
+        let text = self.display(&value).to_string();
+        let result = if newline {
+          writeln!(self.output, "{}", text)
+        } else {
+          write!(self.output, "{}", text).and_then(|_| self.output.flush())
+        };
+        result.map_err(|e| self.error(&e.to_string()))
+      }
+      1..=3 => {
+        let mut line = String::new();
+        self
+          .input
+          .read_line(&mut line)
+          .map_err(|e| self.error(&e.to_string()))?;
+        let line = line.trim_end_matches(['\n', '\r']);
+        let value = match call {
+          1 => line
+            .trim()
+            .parse::<i32>()
+            .map(Value::Int)
+            .map_err(|e| self.error(&format!("Invalid int input '{}': {}", line, e)))?,
+          2 => line
+            .trim()
+            .parse::<f32>()
+            .map(Value::Float)
+            .map_err(|e| self.error(&format!("Invalid float input '{}': {}", line, e)))?,
+          _ => Value::Str(self.alloc_str(line.to_string())),
+        };
+        self.set(r1, value);
+        Ok(())
+      }
+      4 => self.check_keys(r1, r2),
+      5 => self.check_arity(r1, r2),
+      _ => Err(self.error(&format!("Unknown VMCALL {}", call))),
+    }
+  }
+
+  // Keywords are strings starting with ':'. The pairs are checked in order,
+  // the same way the interpreter binds &key parameters.
+  fn check_keys(&self, args: u8, keys: u8) -> VmResult<()> {
+    let (Value::List(args), Value::List(keys)) = (self.get(args), self.get(keys)) else {
+      return Err(self.error("VMCALL 4 expects two lists"));
+    };
+    let keys = keys.borrow();
+    for pair in args.borrow().chunks(2) {
+      let key = match &pair[0] {
+        Value::Str(ptr) if self.string(*ptr)?.starts_with(':') => self.string(*ptr)?,
+        other => {
+          return Err(self.error(&format!(
+            "Expected a keyword argument, got {}",
+            other.type_name()
+          )))
+        }
+      };
+      if pair.len() == 1 {
+        return Err(self.error(&format!("Keyword argument {} has no value", key)));
+      }
+      if !keys.iter().any(|known| self.values_equal(known, &pair[0])) {
+        return Err(self.error(&format!("Unknown keyword argument {}", key)));
+      }
+    }
+    Ok(())
+  }
+
+  // Arg 0 is left to the caller's conventions and isn't counted.
+  fn check_arity(&self, min: u8, max: u8) -> VmResult<()> {
+    let count = self.frame().args.len().saturating_sub(1);
+    let (min, max) = match (self.get(min), self.get(max)) {
+      (Value::Int(min), Value::Nil) => (min as usize, None),
+      (Value::Int(min), Value::Int(max)) => (min as usize, Some(max as usize)),
+      (min, max) => return Err(self.type_error(Opcode::VMCALL, &[&min, &max])),
+    };
+    if count >= min && max.is_none_or(|max| count <= max) {
+      return Ok(());
+    }
+    let arity = match max {
+      None => format!("at least {}", min),
+      Some(max) if max == min => min.to_string(),
+      Some(max) => format!("{} to {}", min, max),
+    };
+    Err(self.error(&format!(
+      "Function expects {} arguments, got {}",
+      arity, count
+    )))
+  }
+
+  fn type_error(&self, op: Opcode, operands: &[&Value]) -> VmError {
+    let types: Vec<&str> = operands.iter().map(|v| v.type_name()).collect();
+    self.error(&format!(
+      "Type error: {:?} cannot operate on {}",
+      op,
+      types.join(" and ")
+    ))
+  }
+
+  // `pc` has already moved past the instruction being executed.
+  fn error(&self, message: &str) -> VmError {
+    VmError::new(message, self.pc.saturating_sub(1))
+  }
+}
+
+fn as_float(value: &Value) -> f32 {
+  match value {
+    Value::Int(n) => *n as f32,
+    Value::Float(n) => *n,
+    _ => f32::NAN,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use Operand::{Imm, Reg};
+
+  fn ins(opcode: Opcode, operands: Vec<Operand>) -> Instruction {
+    Instruction::new(opcode, operands)
+  }
+
+  fn run(program: Program) -> (VmResult<Value>, String) {
+    let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+    let result = vm.run();
+    (result, String::from_utf8(vm.output().clone()).unwrap())
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    let program = Program::new(vec![
+      ins(Opcode::SETI, vec![Reg(1), Imm(6)]),
+      ins(Opcode::SETI, vec![Reg(2), Imm(7)]),
+      ins(Opcode::MUL, vec![Reg(0), Reg(1), Reg(2)]),
+      ins(Opcode::SETF, vec![Reg(3), Imm(0.5f32.to_bits() as i32)]),
+      ins(Opcode::ADD, vec![Reg(4), Reg(0), Reg(3)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+
+    assert_eq!(run(program).0, Ok(Value::Int(42)));
+  }
+
+  #[test]
+  fn test_loop_with_jmp_if() {
+    // r0 = sum of 1..=10
+    let program = Program::new(vec![
+      ins(Opcode::SETI, vec![Reg(0), Imm(0)]),
+      ins(Opcode::SETI, vec![Reg(1), Imm(10)]),
+      ins(Opcode::SETI, vec![Reg(2), Imm(1)]),
+      ins(Opcode::SETI, vec![Reg(3), Imm(0)]),
+      ins(Opcode::ADD, vec![Reg(0), Reg(0), Reg(1)]),
+      ins(Opcode::SUB, vec![Reg(1), Reg(1), Reg(2)]),
+      ins(Opcode::GT, vec![Reg(4), Reg(1), Reg(3)]),
+      ins(Opcode::JMP_IF, vec![Reg(4), Imm(4)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+
+    assert_eq!(run(program).0, Ok(Value::Int(55)));
+  }
+
+  #[test]
+  fn test_call_and_return() {
+    // square(9) via SET_ARG / GET_ARG, result comes back on the stack
+    let program = Program::new(vec![
+      ins(Opcode::SETI, vec![Reg(1), Imm(9)]),
+      ins(Opcode::SET_ARG, vec![Reg(1), Imm(0)]),
+      ins(Opcode::CALL, vec![Imm(5)]),
+      ins(Opcode::POP, vec![Reg(0)]),
+      ins(Opcode::HLT, vec![]),
+      ins(Opcode::GET_ARG, vec![Reg(0), Imm(0)]),
+      ins(Opcode::MUL, vec![Reg(0), Reg(0), Reg(0)]),
+      ins(Opcode::RETURN, vec![Reg(0)]),
+    ]);
+
+    assert_eq!(run(program).0, Ok(Value::Int(81)));
+  }
+
+  #[test]
+  fn test_strings_and_print() {
+    let mut program = Program::new(vec![
+      ins(Opcode::SETS, vec![Reg(1), Imm(0)]),
+      ins(Opcode::SETS, vec![Reg(2), Imm(1)]),
+      ins(Opcode::ADD, vec![Reg(0), Reg(1), Reg(2)]),
+      ins(Opcode::SETI, vec![Reg(3), Imm(1)]),
+      ins(Opcode::VMCALL, vec![Reg(0), Reg(3), Imm(0)]),
+      ins(Opcode::GET_LEN, vec![Reg(0), Reg(0)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+    program.str_table = vec!["Hello, ".to_string(), "World!".to_string()];
+
+    let (result, output) = run(program);
+    assert_eq!(result, Ok(Value::Int(13)));
+    assert_eq!(output, "Hello, World!\n");
+  }
+
+  #[test]
+  fn test_tables() {
+    let mut program = Program::new(vec![
+      ins(Opcode::SETI, vec![Reg(1), Imm(1)]),
+      ins(Opcode::LOAD, vec![Reg(2), Reg(1), Imm(INT_TABLE)]),
+      ins(Opcode::SETI, vec![Reg(3), Imm(5)]),
+      ins(Opcode::ADD, vec![Reg(3), Reg(2), Reg(3)]),
+      ins(Opcode::STORE, vec![Reg(4), Reg(3), Imm(INT_TABLE)]),
+      ins(Opcode::LOAD, vec![Reg(0), Reg(4), Imm(INT_TABLE)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+    program.int_table = vec![10, 20];
+
+    assert_eq!(run(program).0, Ok(Value::Int(25)));
+  }
+
+  #[test]
+  fn test_tables_keep_one_entry_per_value() {
+    let mut program = Program::new(vec![
+      ins(Opcode::SETI, vec![Reg(1), Imm(100)]),
+      ins(Opcode::SETI, vec![Reg(2), Imm(1)]),
+      ins(Opcode::SETI, vec![Reg(3), Imm(0)]),
+      ins(Opcode::SETS, vec![Reg(5), Imm(0)]),
+      ins(Opcode::ADD, vec![Reg(6), Reg(5), Reg(5)]),
+      ins(Opcode::STORE, vec![Reg(7), Reg(2), Imm(INT_TABLE)]),
+      ins(Opcode::STORE, vec![Reg(8), Reg(2), Imm(FLOAT_TABLE)]),
+      ins(Opcode::SUB, vec![Reg(1), Reg(1), Reg(2)]),
+      ins(Opcode::GT, vec![Reg(4), Reg(1), Reg(3)]),
+      ins(Opcode::JMP_IF, vec![Reg(4), Imm(3)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+    program.str_table = vec!["a".to_string()];
+    program.int_table = vec![1];
+
+    let mut vm = Vm::with_io(program, io::empty(), Vec::new());
+    vm.run().unwrap();
+    assert_eq!(vm.str_table(), ["a", "aa"]);
+    assert_eq!(vm.int_table, [1]);
+    assert_eq!(vm.float_table, [1.0]);
+  }
+
+  #[test]
+  fn test_collections() {
+    let program = Program::new(vec![
+      ins(Opcode::NEW_LIST, vec![Reg(1)]),
+      ins(Opcode::SETI, vec![Reg(2), Imm(0)]),
+      ins(Opcode::SETI, vec![Reg(3), Imm(7)]),
+      ins(Opcode::SET_LIST, vec![Reg(1), Reg(2), Reg(3)]),
+      ins(Opcode::NEW_TABLE, vec![Reg(4)]),
+      ins(Opcode::SET_TABLE, vec![Reg(4), Reg(3), Reg(1)]),
+      ins(Opcode::GET_TABLE, vec![Reg(5), Reg(4), Reg(3)]),
+      ins(Opcode::GET_LIST, vec![Reg(0), Reg(5), Reg(2)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+
+    assert_eq!(run(program).0, Ok(Value::Int(7)));
+  }
+
+  #[test]
+  fn test_runtime_errors() {
+    let program = Program::new(vec![
+      ins(Opcode::SETI, vec![Reg(1), Imm(1)]),
+      ins(Opcode::SETI, vec![Reg(2), Imm(0)]),
+      ins(Opcode::DIV, vec![Reg(0), Reg(1), Reg(2)]),
+      ins(Opcode::HLT, vec![]),
+    ]);
+    let error = run(program).0.unwrap_err();
+    assert_eq!(error.message, "Division by zero");
+    assert_eq!(error.pc, 2);
+
+    let program = Program::new(vec![ins(Opcode::NOP, vec![])]);
+    assert!(run(program).0.unwrap_err().message.contains("without HLT"));
+
+    for idx in [-1, i32::MAX] {
+      let program = Program::new(vec![ins(Opcode::SET_ARG, vec![Reg(4), Imm(idx)])]);
+      assert_eq!(
+        run(program).0.unwrap_err().message,
+        format!("Argument index {} is out of range 0..256", idx)
+      );
+    }
+
+    let program = Program::new(vec![ins(Opcode::CALL, vec![Imm(0)])]);
+    assert!(run(program)
+      .0
+      .unwrap_err()
+      .message
+      .contains("stack overflow"));
+  }
+}
@@ -0,0 +1,24 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+  pub message: String,
+  pub pc: usize,
+}
+
+impl VmError {
+  pub fn new(message: &str, pc: usize) -> Self {
+    VmError {
+      message: message.to_string(),
+      pc,
+    }
+  }
+}
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} at pc {}", self.message, self.pc)
+  }
+}
+
+pub type VmResult<T> = Result<T, VmError>;